@@ -0,0 +1,243 @@
+//! Derives `BinSizer`, `Encodable` and `Decodable` from `util::byte` for structs and enums.
+//!
+//! Fields are encoded one after another with their own impls. An enum is encoded as a `u8`
+//! tag, the index of the variant, followed by the fields of that variant; when every variant
+//! has a fixed size, shorter variants are padded with zeros so that the enum has a fixed size
+//! too. A type has a fixed `bin_size` only if all its fields have one, so a struct made of
+//! numbers can be stored in a `BTree` page exactly like an `i32`.
+//!
+//! The generated code refers to the traits through `crate::util::byte`, where they are
+//! re-exported together with these derives.
+
+use proc_macro::TokenStream;
+use proc_macro2::{Ident, Span, TokenStream as TokenStream2};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, Data, DeriveInput, Error, Fields, Generics, Result, Type};
+
+#[proc_macro_derive(BinSizer)]
+pub fn derive_bin_sizer(input: TokenStream) -> TokenStream {
+    expand(input, "BinSizer", bin_sizer)
+}
+
+#[proc_macro_derive(Encodable)]
+pub fn derive_encodable(input: TokenStream) -> TokenStream {
+    expand(input, "Encodable", encodable)
+}
+
+#[proc_macro_derive(Decodable)]
+pub fn derive_decodable(input: TokenStream) -> TokenStream {
+    expand(input, "Decodable", decodable)
+}
+
+fn expand(input: TokenStream, name: &str, body: fn(&DeriveInput) -> Result<TokenStream2>) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    let result = check(&input).and_then(|_| body(&input)).map(|body| {
+        let ident = &input.ident;
+        let trait_ = Ident::new(name, Span::call_site());
+        let generics = add_bounds(input.generics.clone(), &trait_);
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+        quote! {
+            impl #impl_generics crate::util::byte::#trait_ for #ident #ty_generics #where_clause {
+                #body
+            }
+        }
+    });
+    result.unwrap_or_else(Error::into_compile_error).into()
+}
+
+// 只支持能确定编码方式的类型
+fn check(input: &DeriveInput) -> Result<()> {
+    match &input.data {
+        Data::Struct(data) if data.fields.is_empty() => Err(Error::new_spanned(
+            &input.ident,
+            "cannot derive for a struct without fields, a bin_size of 0 means variable size",
+        )),
+        Data::Enum(data) if data.variants.is_empty() => {
+            Err(Error::new_spanned(&input.ident, "cannot derive for an enum without variants"))
+        }
+        Data::Enum(data) if data.variants.len() > 256 => {
+            Err(Error::new_spanned(&input.ident, "cannot derive for an enum with more than 256 variants"))
+        }
+        Data::Union(_) => Err(Error::new_spanned(&input.ident, "cannot derive for a union")),
+        _ => Ok(()),
+    }
+}
+
+// 编码和解码都要用到字段的 bin_size
+fn add_bounds(mut generics: Generics, trait_: &Ident) -> Generics {
+    for param in generics.type_params_mut() {
+        param.bounds.push(parse_quote!(crate::util::byte::#trait_));
+        if trait_ != "BinSizer" {
+            param.bounds.push(parse_quote!(crate::util::byte::BinSizer));
+        }
+    }
+    generics
+}
+
+fn field_types(fields: &Fields) -> Vec<&Type> {
+    fields.iter().map(|field| &field.ty).collect()
+}
+
+// 字段在模式中绑定的变量名，元组字段用下标命名
+fn bindings(fields: &Fields) -> Vec<Ident> {
+    fields
+        .iter()
+        .enumerate()
+        .map(|(i, field)| field.ident.clone().unwrap_or_else(|| format_ident!("__field{}", i)))
+        .collect()
+}
+
+// 按字段绑定变量的模式，例如 `Self { a, b }` 或 `Self::Variant(__field0)`
+fn pattern(path: TokenStream2, fields: &Fields) -> TokenStream2 {
+    let bindings = bindings(fields);
+    match fields {
+        Fields::Named(_) => quote!(#path { #(#bindings),* }),
+        Fields::Unnamed(_) => quote!(#path ( #(#bindings),* )),
+        Fields::Unit => path,
+    }
+}
+
+// 所有字段都定长时是字段长度之和，否则为 None
+fn fixed_size(fields: &Fields) -> TokenStream2 {
+    let types = field_types(fields);
+    let count = types.len();
+    quote! {{
+        let sizes: [usize; #count] = [#(<#types as crate::util::byte::BinSizer>::bin_size()),*];
+        if sizes.contains(&0) { None } else { Some(sizes.iter().sum::<usize>()) }
+    }}
+}
+
+fn encoded_size(fields: &Fields) -> TokenStream2 {
+    let bindings = bindings(fields);
+    quote!(0 #(+ crate::util::byte::BinSizer::encoded_size(#bindings))*)
+}
+
+fn encode_fields(fields: &Fields) -> TokenStream2 {
+    let bindings = bindings(fields);
+    quote! {
+        #(__pos += crate::util::byte::Encodable::encode(#bindings, &mut __buf[__pos..])?;)*
+    }
+}
+
+fn decode_fields(fields: &Fields) -> TokenStream2 {
+    let types = field_types(fields);
+    let bindings = bindings(fields);
+    quote! {
+        #(
+            let (#bindings, __size) = <#types as crate::util::byte::Decodable>::decode(&__buf[__pos..])?;
+            __pos += __size;
+        )*
+    }
+}
+
+fn bin_sizer(input: &DeriveInput) -> Result<TokenStream2> {
+    let (bin_size, encoded_size) = match &input.data {
+        Data::Struct(data) => {
+            let pattern = pattern(quote!(Self), &data.fields);
+            let size = encoded_size(&data.fields);
+            let bin_size = fixed_size(&data.fields);
+            (quote!(#bin_size.unwrap_or(0)), quote!({ let #pattern = self; #size }))
+        }
+        Data::Enum(data) => {
+            let sizes = data.variants.iter().map(|variant| fixed_size(&variant.fields));
+            let count = data.variants.len();
+            let arms = data.variants.iter().map(|variant| {
+                let ident = &variant.ident;
+                let pattern = pattern(quote!(Self::#ident), &variant.fields);
+                let size = encoded_size(&variant.fields);
+                quote!(#pattern => 1 + #size,)
+            });
+            // 各变体定长时按最长的变体补齐
+            let bin_size = quote! {{
+                let sizes: [Option<usize>; #count] = [#(#sizes),*];
+                sizes
+                    .iter()
+                    .try_fold(0, |max, size| size.map(|size| max.max(size)))
+                    .map_or(0, |max| 1 + max)
+            }};
+            (bin_size, quote!(match self { #(#arms)* }))
+        }
+        Data::Union(_) => unreachable!(),
+    };
+    Ok(quote! {
+        fn bin_size() -> usize {
+            #bin_size
+        }
+
+        fn encoded_size(&self) -> usize {
+            match <Self as crate::util::byte::BinSizer>::bin_size() {
+                0 => #encoded_size,
+                size => size,
+            }
+        }
+    })
+}
+
+fn encodable(input: &DeriveInput) -> Result<TokenStream2> {
+    let encode = match &input.data {
+        Data::Struct(data) => {
+            let pattern = pattern(quote!(Self), &data.fields);
+            let fields = encode_fields(&data.fields);
+            quote!({ let #pattern = self; #fields })
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().enumerate().map(|(tag, variant)| {
+                let ident = &variant.ident;
+                let tag = tag as u8;
+                let pattern = pattern(quote!(Self::#ident), &variant.fields);
+                let fields = encode_fields(&variant.fields);
+                quote!(#pattern => { __buf[0] = #tag; __pos += 1; #fields })
+            });
+            quote!(match self { #(#arms)* })
+        }
+        Data::Union(_) => unreachable!(),
+    };
+    Ok(quote! {
+        fn encode(&self, __buf: &mut [u8]) -> ::anyhow::Result<usize> {
+            let __size = crate::util::byte::BinSizer::encoded_size(self);
+            crate::util::byte::check_len(__buf, __size)?;
+            let mut __pos = 0;
+            #encode
+            __buf[__pos..__size].fill(0);
+            Ok(__size)
+        }
+    })
+}
+
+fn decodable(input: &DeriveInput) -> Result<TokenStream2> {
+    let name = input.ident.to_string();
+    let decode = match &input.data {
+        Data::Struct(data) => {
+            let fields = decode_fields(&data.fields);
+            let pattern = pattern(quote!(Self), &data.fields);
+            quote!({ #fields #pattern })
+        }
+        Data::Enum(data) => {
+            let arms = data.variants.iter().enumerate().map(|(tag, variant)| {
+                let ident = &variant.ident;
+                let tag = tag as u8;
+                let fields = decode_fields(&variant.fields);
+                let pattern = pattern(quote!(Self::#ident), &variant.fields);
+                quote!(#tag => { #fields #pattern })
+            });
+            quote! {{
+                crate::util::byte::check_len(__buf, 1)?;
+                __pos += 1;
+                match __buf[0] {
+                    #(#arms)*
+                    tag => return Err(::anyhow::anyhow!("invalid tag {} for {}", tag, #name)),
+                }
+            }}
+        }
+        Data::Union(_) => unreachable!(),
+    };
+    Ok(quote! {
+        fn decode(__buf: &[u8]) -> ::anyhow::Result<(Self, usize)> {
+            let __size = <Self as crate::util::byte::BinSizer>::bin_size();
+            crate::util::byte::check_len(__buf, __size)?;
+            let mut __pos = 0;
+            let __value = #decode;
+            Ok((__value, __pos.max(__size)))
+        }
+    })
+}
@@ -0,0 +1,271 @@
+use anyhow::{anyhow, Result};
+use std::fs::File;
+use std::io::BufReader;
+use std::time::Duration;
+use time::Instant;
+
+use crate::util::bank::AccountRecord;
+use crate::util::btree::{BTree, Options, SyncMode};
+use crate::util::import;
+use crate::util::inspect;
+
+pub const DEFAULT_INDEX_PATH: &str = "./testbtree1.btree";
+
+const USAGE: &str = "usage:
+    banksystem1 [--index <path>]                                                交互式菜单
+    banksystem1 [--index <path>] import <csv> [--sorted] [--fill-factor <f>]    导入 account,balance 格式的 csv，余额以元为单位
+    banksystem1 [--index <path>] fsck                                           只读检查索引文件是否完好
+    banksystem1 [--index <path>] inspect meta|page <n>|tree [--json]            查看 META 页、第 n 页或每层的页数和填充率
+    banksystem1 [--index <path>] stats [--json]                                  查看树高、页数、键数、每层填充率和文件大小
+    banksystem1 [--index <path>] compact [--fill-factor <f>]                    按键的顺序紧凑地重写索引文件，运行时不能有其他进程打开它
+    banksystem1 [--index <path>] backup <path>                                  把索引复制到 path 并检查副本是否完好，交互式菜单中可以边营业边备份
+    banksystem1 [--index <path>] bench [--lookups <n>]                          分别用文件读写和内存映射随机查找 n 个账号，比较用时
+
+    --index <path>            索引文件，默认为 ./testbtree1.btree
+    --bloom-fp-rate <rate>    在索引旁边维护误判率为 rate 的布隆过滤器，不存在的账号不用查索引页；建好以后不加这个参数也会使用
+    --mmap                    索引页直接指向文件的内存映射，不再每页一次系统调用和复制
+    --sync <mode>             操作何时落盘：full 每次提交都 fsync（默认）；none 不 fsync，断电可能丢失最近的操作；
+                              group[:<ms>[:<ops>]] 攒够 ops 个操作或最多等 ms 毫秒后一起 fsync，默认 10 毫秒、64 个操作";
+
+// group 不带参数时的组提交间隔和操作数
+const GROUP_COMMIT_MS: u64 = 10;
+const GROUP_COMMIT_OPS: usize = 64;
+
+// bench 的缓冲池很小，查找基本都要读页
+const BENCH_POOL_CAPACITY: usize = 16;
+
+/// 从参数中取出 `--index <path>`，没有给出时使用默认的索引文件
+pub fn take_index_path(args: &mut Vec<String>) -> Result<String> {
+    Ok(take_value(args, "--index", "a path")?.unwrap_or_else(|| DEFAULT_INDEX_PATH.to_string()))
+}
+
+/// 从参数中取出 `--bloom-fp-rate <rate>`、`--mmap` 和 `--sync <mode>`，得到打开索引的选项
+pub fn take_options(args: &mut Vec<String>) -> Result<Options> {
+    let mut options = Options::default();
+    if let Some(i) = args.iter().position(|arg| arg == "--mmap") {
+        args.remove(i);
+        options = options.mmap(true);
+    }
+    if let Some(rate) = take_value(args, "--bloom-fp-rate", "a rate")? {
+        let rate = rate.parse::<f64>().map_err(|_| anyhow!("invalid false-positive rate: {}", rate))?;
+        options = options.bloom_filter(rate);
+    }
+    if let Some(mode) = take_value(args, "--sync", "a mode")? {
+        options = options.sync(parse_sync_mode(&mode)?);
+    }
+    Ok(options)
+}
+
+// 取出 `flag <value>` 中的 value
+fn take_value(args: &mut Vec<String>, flag: &str, what: &str) -> Result<Option<String>> {
+    match args.iter().position(|arg| arg == flag) {
+        Some(i) => {
+            if i + 1 >= args.len() {
+                return Err(anyhow!("{} needs {}\n{}", flag, what, USAGE));
+            }
+            let value = args.remove(i + 1);
+            args.remove(i);
+            Ok(Some(value))
+        }
+        None => Ok(None),
+    }
+}
+
+// none、full 或 group[:<ms>[:<ops>]]
+fn parse_sync_mode(mode: &str) -> Result<SyncMode> {
+    let invalid = || anyhow!("invalid sync mode: {}, expected none, full or group[:<ms>[:<ops>]]", mode);
+    match mode.split(':').collect::<Vec<&str>>().as_slice() {
+        ["none"] => Ok(SyncMode::Off),
+        ["full"] => Ok(SyncMode::Full),
+        ["group", rest @ ..] if rest.len() <= 2 => {
+            let ms = match rest.first() {
+                Some(ms) => ms.parse::<u64>().map_err(|_| invalid())?,
+                None => GROUP_COMMIT_MS,
+            };
+            let ops = match rest.get(1) {
+                Some(ops) => ops.parse::<usize>().map_err(|_| invalid())?,
+                None => GROUP_COMMIT_OPS,
+            };
+            Ok(SyncMode::Group { ops, interval: Duration::from_millis(ms) })
+        }
+        _ => Err(invalid()),
+    }
+}
+
+/// 带参数启动时执行对应的命令而不进入交互菜单
+pub fn run(args: &[String], index_path: &str, options: Options) -> Result<()> {
+    match args[0].as_str() {
+        "import" => run_import(&args[1..], index_path, options),
+        "fsck" => run_fsck(index_path, options),
+        "inspect" => run_inspect(&args[1..], index_path, options),
+        "stats" => run_stats(&args[1..], index_path, options),
+        "compact" => run_compact(&args[1..], index_path, options),
+        "backup" => run_backup(&args[1..], index_path, options),
+        "bench" => run_bench(&args[1..], index_path, options),
+        "help" | "-h" | "--help" => {
+            println!("{}", USAGE);
+            Ok(())
+        }
+        other => Err(anyhow!("unknown command: {}\n{}", other, USAGE)),
+    }
+}
+
+fn run_import(args: &[String], index_path: &str, options: Options) -> Result<()> {
+    let mut csv_path = None;
+    let mut sorted = false;
+    let mut fill_factor = 1.0;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--sorted" => sorted = true,
+            "--fill-factor" => {
+                let value = args.next().ok_or_else(|| anyhow!("--fill-factor needs a value"))?;
+                fill_factor = value.parse::<f64>()?;
+            }
+            path => csv_path = Some(path),
+        }
+    }
+    BTree::<i32, AccountRecord>::check_fill_factor(fill_factor)?;
+    let csv_path = csv_path.ok_or_else(|| anyhow!("missing csv path\n{}", USAGE))?;
+
+    let reader = BufReader::new(File::open(csv_path)?);
+    let btree = BTree::<i32, AccountRecord>::open(index_path, options)?;
+    let start = Instant::now();
+    let report = if sorted {
+        import::bulk_import_csv(reader, &btree, fill_factor)?
+    } else {
+        import::import_csv(reader, &btree)?
+    };
+    for row in &report.rejected {
+        println!("第{}行：{}", row.line, row.reason);
+    }
+    println!("{}，用时{}", report, start.elapsed());
+    Ok(())
+}
+
+// 发现问题时返回错误，以非零状态退出
+fn run_fsck(index_path: &str, options: Options) -> Result<()> {
+    let btree = BTree::<i32, AccountRecord>::open(index_path, options.read_only(true))?;
+    let start = Instant::now();
+    let report = btree.verify()?;
+    println!("{}", report);
+    println!("用时{}", start.elapsed());
+    if !report.is_ok() {
+        return Err(anyhow!("{}: {} problems found", index_path, report.problems.len()));
+    }
+    Ok(())
+}
+
+fn run_inspect(args: &[String], index_path: &str, options: Options) -> Result<()> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).filter(|arg| *arg != "--json").collect();
+    let btree = BTree::<i32, AccountRecord>::open(index_path, options.read_only(true))?;
+    let page = match args.as_slice() {
+        ["meta"] => 0,
+        ["page", index] => index.parse::<u32>().map_err(|_| anyhow!("invalid page index: {}", index))?,
+        ["tree"] => {
+            let levels = btree.levels()?;
+            if json {
+                println!("{}", serde_json::to_string_pretty(&inspect::levels_json(&levels))?);
+            } else {
+                println!("{}", inspect::levels_text(&levels));
+            }
+            return Ok(());
+        }
+        _ => return Err(anyhow!("inspect needs meta, page <n> or tree\n{}", USAGE)),
+    };
+    let text = if json {
+        serde_json::to_string_pretty(&btree.with_page(page, inspect::page_json)?)?
+    } else {
+        btree.with_page(page, |page| format!("page {}: {:?}", page.index, page))?
+    };
+    println!("{}", text);
+    Ok(())
+}
+
+fn run_stats(args: &[String], index_path: &str, options: Options) -> Result<()> {
+    let json = match args {
+        [] => false,
+        [flag] if flag == "--json" => true,
+        _ => return Err(anyhow!("stats only takes --json\n{}", USAGE)),
+    };
+    let btree = BTree::<i32, AccountRecord>::open(index_path, options.read_only(true))?;
+    let stats = btree.stats()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&inspect::stats_json(&stats))?);
+    } else {
+        println!("{}\n\n{}", stats, inspect::levels_text(&stats.levels));
+    }
+    Ok(())
+}
+
+// 紧凑重写总是沿用原文件的布隆过滤器，新文件写完后一次 fsync，这些选项对它都不起作用
+fn run_compact(args: &[String], index_path: &str, options: Options) -> Result<()> {
+    let fill_factor = match args {
+        [] => 1.0,
+        [flag, value] if flag == "--fill-factor" => value.parse::<f64>()?,
+        _ => return Err(anyhow!("compact only takes --fill-factor <f>\n{}", USAGE)),
+    };
+    if options != Options::default() {
+        return Err(anyhow!("compact does not take --bloom-fp-rate, --mmap or --sync\n{}", USAGE));
+    }
+    let start = Instant::now();
+    let report = BTree::<i32, AccountRecord>::compact(index_path, fill_factor)?;
+    println!("{}", report);
+    println!("用时{}", start.elapsed());
+    Ok(())
+}
+
+fn run_backup(args: &[String], index_path: &str, options: Options) -> Result<()> {
+    let backup_path = match args {
+        [path] => path,
+        _ => return Err(anyhow!("backup needs the path of the copy\n{}", USAGE)),
+    };
+    let btree = BTree::<i32, AccountRecord>::open(index_path, options)?;
+    let start = Instant::now();
+    let report = btree.backup_to(backup_path)?;
+    println!("{}", report);
+    println!("用时{}", start.elapsed());
+    Ok(())
+}
+
+// 两种方式按同样的顺序查找同样的账号，内存映射方式载入页时省掉一次系统调用和一次复制
+fn run_bench(args: &[String], index_path: &str, options: Options) -> Result<()> {
+    let lookups = match args {
+        [] => 100_000,
+        [flag, value] if flag == "--lookups" => value.parse::<usize>()?,
+        _ => return Err(anyhow!("bench only takes --lookups <n>\n{}", USAGE)),
+    };
+    let options = options.read_only(true).pool_capacity(BENCH_POOL_CAPACITY);
+    let keys = {
+        let btree = BTree::<i32, AccountRecord>::open(index_path, options.clone())?;
+        btree.iter()?.map(|item| item.map(|(key, _)| key)).collect::<Result<Vec<i32>>>()?
+    };
+    if keys.is_empty() {
+        return Err(anyhow!("{} has no accounts to look up", index_path));
+    }
+    // 固定种子的 xorshift，每次运行查找的账号都一样
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let picks: Vec<i32> = (0..lookups)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            keys[(state % keys.len() as u64) as usize]
+        })
+        .collect();
+    for (name, mmap) in [("文件读写", false), ("内存映射", true)] {
+        let btree = BTree::<i32, AccountRecord>::open(index_path, options.clone().mmap(mmap))?;
+        let start = Instant::now();
+        for key in &picks {
+            if btree.get(key)?.is_none() {
+                return Err(anyhow!("account {} went missing", key));
+            }
+        }
+        let elapsed = start.elapsed();
+        let stats = btree.pool_stats();
+        println!("{}：查找{}次，用时{}，平均每次{}", name, lookups, elapsed, elapsed / lookups as f64);
+        println!("    从文件载入{}页，其中{}页直接指向映射，没有复制", stats.misses, stats.mapped);
+    }
+    Ok(())
+}
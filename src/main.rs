@@ -1,14 +1,29 @@
+mod command;
 mod util;
 
+use std::sync::Arc;
 use time::*;
 
-use util::bank::Bank;
+use util::bank::{format_amount, parse_amount, AccountRecord, AccountStatus, Bank, Currency};
 use util::btree::BTree;
 use util::threadpool::Pool;
 
 fn main() {
-    let mut bank = Bank::new();
-    let mut btree = BTree::<i32, i32>::new("./testbtree1.btree");
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let index_path = command::take_index_path(&mut args).unwrap_or_else(|err| exit_with(err));
+    let options = command::take_options(&mut args).unwrap_or_else(|err| exit_with(err));
+    if !args.is_empty() {
+        if let Err(err) = command::run(&args, &index_path, options) {
+            exit_with(err);
+        }
+        return;
+    }
+
+    let btree = match BTree::<i32, AccountRecord>::open(&index_path, options) {
+        Ok(btree) => Arc::new(btree),
+        Err(err) => exit_with(err),
+    };
+    let mut bank = Bank::with_index(btree.clone());
     let mut p = Pool::new(4);
     let mut isrunning = true;
 
@@ -17,14 +32,19 @@ fn main() {
     while isrunning {
         let mut line = String::new();
         println!(
-            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
+            "{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n{}\n",
             "请选择您的操作序号：",
             "1.存款",
             "2.取款",
             "3.转账",
             "4.发工资",
             "5.发利息",
-            "6.退出"
+            "6.退出",
+            "7.销户",
+            "8.账户列表",
+            "9.冻结/解冻",
+            "10.备份索引",
+            "11.日终汇总"
         );
         std::io::stdin().read_line(&mut line).unwrap();
         match line.trim().parse::<u32>().unwrap() {
@@ -36,56 +56,37 @@ fn main() {
                 let account = account.trim().to_string();
                 println!("{}", "请输入存款金额：");
                 std::io::stdin().read_line(&mut amount).unwrap();
-                let amount = amount.trim().parse::<i32>().unwrap();
+                let amount = match parse_amount(amount.trim()) {
+                    Some(amount) => amount,
+                    None => {
+                        println!("金额无效");
+                        continue;
+                    }
+                };
                 let start = Instant::now(); //计时开始
-                if !bank.check_account(account.clone()) {
-                    match btree.get(&str::parse::<i32>(&account.clone()).unwrap()) {
-                        Some(balance) => {
-                            bank.add_account(account.clone(), balance);
-                            let mut bank = bank.clone();
-                            p.execute(
-                                move || {
-                                    println!("{}", account.clone());
-                                    match bank.deposit(account.clone(), amount) {
-                                        Ok(()) => {}
-                                        Err(err) => println!("{}", err),
-                                    };
-                                    let duration = start.elapsed(); //操作成功计时点
-                                    println!(
-                                        "账户{}余额：{}，操作用时{}",
-                                        account.clone(),
-                                        bank.showbalance(account.clone()),
-                                        duration.to_string()
-                                    );
-                                },
-                                true,
-                            )
-                        }
-                        None => {
+                let mut bank = bank.clone();
+                p.execute(
+                    move || {
+                        if !bank.check_account(account.clone()) {
                             let duration = start.elapsed(); //查询账户不存在操作用时的计时点
-                            println!("{}", format!("账号不存在，用时{}", duration.to_string()));
+                            println!("账号不存在，用时{}", duration);
+                            return;
                         }
-                    };
-                } else {
-                    let mut bank = bank.clone();
-                    p.execute(
-                        move || {
-                            println!("{}", account.clone());
-                            match bank.deposit(account.clone(), amount) {
-                                Ok(()) => {}
-                                Err(err) => println!("{}", err),
-                            };
-                            let duration = start.elapsed(); //操作成功计时点
-                            println!(
-                                "账户{}余额：{}，操作用时{}",
-                                account.clone(),
-                                bank.showbalance(account.clone()),
-                                duration.to_string()
-                            );
-                        },
-                        true,
-                    )
-                }
+                        println!("{}", account.clone());
+                        match bank.deposit(account.clone(), amount) {
+                            Ok(()) => {}
+                            Err(err) => println!("{}", err),
+                        };
+                        let duration = start.elapsed(); //操作成功计时点
+                        println!(
+                            "账户{}余额：{}，操作用时{}",
+                            account.clone(),
+                            format_amount(bank.showbalance(account.clone())),
+                            duration
+                        );
+                    },
+                    true,
+                )
             }
             2 => {
                 let mut account = String::new();
@@ -95,55 +96,36 @@ fn main() {
                 let account = account.trim().to_string();
                 println!("{}", "请输入取款金额：");
                 std::io::stdin().read_line(&mut amount).unwrap();
-                let amount = amount.trim().parse::<i32>().unwrap();
+                let amount = match parse_amount(amount.trim()) {
+                    Some(amount) => amount,
+                    None => {
+                        println!("金额无效");
+                        continue;
+                    }
+                };
                 let start = Instant::now(); //计时开始
-                if !bank.check_account(account.clone()) {
-                    match btree.get(&str::parse::<i32>(&account.clone()).unwrap()) {
-                        Some(balance) => {
-                            bank.add_account(account.clone(), balance);
-                            let mut bank = bank.clone();
-                            p.execute(
-                                move || {
-                                    match bank.withdraw(account.clone(), amount) {
-                                        Ok(()) => {}
-                                        Err(err) => println!("{}", err),
-                                    };
-                                    let duration = start.elapsed(); //操作成功计时点
-                                    println!(
-                                        "账户{}余额：{}，操作用时{}",
-                                        account.clone(),
-                                        bank.showbalance(account.clone()),
-                                        duration.to_string()
-                                    );
-                                },
-                                true,
-                            )
-                        }
-                        None => {
-                            let duration = start.elapsed(); //操作成功计时点
-                            println!("{}", format!("账号不存在，用时{}", duration.to_string()));
+                let mut bank = bank.clone();
+                p.execute(
+                    move || {
+                        if !bank.check_account(account.clone()) {
+                            let duration = start.elapsed(); //查询账户不存在操作用时的计时点
+                            println!("账号不存在，用时{}", duration);
+                            return;
                         }
-                    };
-                } else {
-                    let mut bank = bank.clone();
-                    p.execute(
-                        move || {
-                            // println!("{}",account.clone());
-                            match bank.withdraw(account.clone(), amount) {
-                                Ok(()) => {}
-                                Err(err) => println!("{}", err),
-                            };
-                            let duration = start.elapsed(); //操作成功计时点
-                            println!(
-                                "账户{}余额：{}，操作用时{}",
-                                account.clone(),
-                                bank.showbalance(account.clone()),
-                                duration.to_string()
-                            );
-                        },
-                        true,
-                    )
-                }
+                        match bank.withdraw(account.clone(), amount) {
+                            Ok(()) => {}
+                            Err(err) => println!("{}", err),
+                        };
+                        let duration = start.elapsed(); //操作成功计时点
+                        println!(
+                            "账户{}余额：{}，操作用时{}",
+                            account.clone(),
+                            format_amount(bank.showbalance(account.clone())),
+                            duration
+                        );
+                    },
+                    true,
+                )
             }
             3 => {
                 let mut fromaccount = String::new();
@@ -157,63 +139,55 @@ fn main() {
                 let toaccount = toaccount.trim().to_string();
                 println!("{}", "请输入转账金额：");
                 std::io::stdin().read_line(&mut amount).unwrap();
-                let amount = amount.trim().parse::<i32>().unwrap();
+                let amount = match parse_amount(amount.trim()) {
+                    Some(amount) => amount,
+                    None => {
+                        println!("金额无效");
+                        continue;
+                    }
+                };
 
                 let start = Instant::now(); //计时开始
-                if !bank.check_account(fromaccount.clone()) {
-                    match btree.get(&str::parse::<i32>(&fromaccount.clone()).unwrap()) {
-                        Some(balance) => {
-                            bank.add_account(fromaccount.clone(), balance);
-                        }
-                        None => {
+                let mut bank = bank.clone();
+                let btree = btree.clone();
+                p.execute(
+                    move || {
+                        if !bank.check_account(fromaccount.clone()) {
                             let duration = start.elapsed(); //操作成功计时点
-                            println!("付款账号不存在！，操作用时{}", duration.to_string());
+                            println!("付款账号不存在！，操作用时{}", duration);
+                            return;
                         }
-                    }
-                }
-                if !bank.check_account(toaccount.clone()) {
-                    match btree.get(&str::parse::<i32>(&toaccount.clone()).unwrap()) {
-                        Some(balance) => {
-                            bank.add_account(toaccount.clone(), balance);
-                        }
-                        None => {
+                        if !bank.check_account(toaccount.clone()) {
                             let duration = start.elapsed(); //操作成功计时点
-                            println!("收款账号不存在！，操作用时{}", duration.to_string());
+                            println!("收款账号不存在！，操作用时{}", duration);
+                            return;
                         }
-                    }
-                }
-                if bank.check_account(fromaccount.clone()) && bank.check_account(toaccount.clone())
-                {
-                    let mut bank = bank.clone();
-                    p.execute(
-                        move || {
-                            match bank.transfer(amount, fromaccount.clone(), toaccount.clone()) {
-                                Ok(()) => {
-                                    let duration = start.elapsed(); //操作成功计时点
-                                    println!(
-                                        "账户{}余额：{}",
-                                        fromaccount.clone(),
-                                        bank.showbalance(fromaccount.clone())
-                                    );
-                                    println!(
-                                        "账户{}余额：{}",
-                                        toaccount.clone(),
-                                        bank.showbalance(toaccount.clone())
-                                    );
-                                    println!("操作用时{}", duration.to_string())
-                                }
-                                Err(err) => println!("{}", err),
-                            };
-                        },
-                        true,
-                    )
-                }
-
-                for (account, balance) in bank.get_accounts() {
-                    btree
-                        .set(&str::parse::<i32>(&account).unwrap(), &balance)
-                        .unwrap();
-                }
+                        match bank.transfer(amount, fromaccount.clone(), toaccount.clone()) {
+                            Ok(()) => {
+                                let duration = start.elapsed(); //操作成功计时点
+                                println!(
+                                    "账户{}余额：{}",
+                                    fromaccount.clone(),
+                                    format_amount(bank.showbalance(fromaccount.clone()))
+                                );
+                                println!(
+                                    "账户{}余额：{}",
+                                    toaccount.clone(),
+                                    format_amount(bank.showbalance(toaccount.clone()))
+                                );
+                                println!("操作用时{}", duration)
+                            }
+                            Err(err) => println!("{}", err),
+                        };
+                        // 转账双方的余额立即写回索引
+                        for account in [fromaccount, toaccount] {
+                            btree
+                                .set(&str::parse::<i32>(&account).unwrap(), &bank.get_record(&account).unwrap())
+                                .unwrap();
+                        }
+                    },
+                    true,
+                )
             }
             4 => {
                 let mut accounts = Vec::new();
@@ -225,20 +199,11 @@ fn main() {
                     let account = account.trim().to_string();
                     if account == "0" {
                         flag = false;
-                    } else {
-                        if !bank.check_account(account.clone()) {
-                            match btree.get(&str::parse::<i32>(&account.clone()).unwrap()) {
-                                Some(balance) => {
-                                    bank.add_account(account.clone(), balance);
-                                    accounts.push(account.clone());
-                                }
-                                None => {
-                                    println!("账号不存在！");
-                                }
-                            };
-                        }
+                    } else if !accounts.contains(&account) {
+                        accounts.push(account);
                     }
                 }
+                let accounts = load_accounts(bank, &btree, accounts);
                 println!("{}", "正在给每个人发工资！");
                 for account in accounts {
                     let mut bank = bank.clone();
@@ -249,7 +214,7 @@ fn main() {
                                     println!(
                                         "账户{}余额：{}",
                                         account.clone(),
-                                        bank.showbalance(account.clone())
+                                        format_amount(bank.showbalance(account.clone()))
                                     );
                                 }
                                 Err(err) => println!("{}", err),
@@ -269,20 +234,11 @@ fn main() {
                     let account = account.trim().to_string();
                     if account == "0" {
                         flag = false;
-                    } else {
-                        if !bank.check_account(account.clone()) {
-                            match btree.get(&str::parse::<i32>(&account.clone()).unwrap()) {
-                                Some(balance) => {
-                                    bank.add_account(account.clone(), balance);
-                                    accounts.push(account.clone());
-                                }
-                                None => {
-                                    println!("账号不存在！");
-                                }
-                            };
-                        }
+                    } else if !accounts.contains(&account) {
+                        accounts.push(account);
                     }
                 }
+                let accounts = load_accounts(bank, &btree, accounts);
                 println!("{}", "正在给每个人发利息！");
                 for account in accounts {
                     let mut bank = bank.clone();
@@ -293,7 +249,7 @@ fn main() {
                                     println!(
                                         "账户{}余额：{}",
                                         account.clone(),
-                                        bank.showbalance(account.clone())
+                                        format_amount(bank.showbalance(account.clone()))
                                     );
                                 }
                                 Err(err) => println!("{}", err),
@@ -305,16 +261,181 @@ fn main() {
             }
             6 => {
                 isrunning = false;
-                for (account, balance) in bank.get_accounts() {
-                    btree
-                        .set(&str::parse::<i32>(&account).unwrap(), &balance)
-                        .unwrap();
-                }
+                let records: Vec<(i32, AccountRecord)> = bank
+                    .get_accounts()
+                    .into_iter()
+                    .map(|(account, record)| (str::parse::<i32>(&account).unwrap(), record))
+                    .collect();
+                btree.set_many(&records).unwrap();
                 println!("{}", "bye");
             }
+            7 => {
+                let mut account = String::new();
+                println!("请输入账号：");
+                std::io::stdin().read_line(&mut account).unwrap();
+                let account = account.trim().to_string();
+                let start = Instant::now(); //计时开始
+                // 先从内存中移除，避免退出时被重新写回索引
+                let cached = bank.remove_account(account.clone());
+                match btree.remove(&str::parse::<i32>(&account).unwrap()).unwrap() {
+                    Some(record) => {
+                        let duration = start.elapsed(); //操作成功计时点
+                        println!(
+                            "账户{}已销户，退还余额：{}，操作用时{}",
+                            account,
+                            format_amount(cached.unwrap_or(record).balance),
+                            duration
+                        );
+                    }
+                    None => {
+                        let duration = start.elapsed(); //查询账户不存在操作用时的计时点
+                        println!("账号不存在，用时{}", duration);
+                    }
+                }
+            }
+            8 => {
+                let mut start = String::new();
+                let mut end = String::new();
+                println!("请输入起始账号：");
+                std::io::stdin().read_line(&mut start).unwrap();
+                let start = start.trim().parse::<i32>().unwrap();
+                println!("请输入结束账号：");
+                std::io::stdin().read_line(&mut end).unwrap();
+                let end = end.trim().parse::<i32>().unwrap();
+                for item in btree.range(start..=end).unwrap() {
+                    let (account, record) = item.unwrap();
+                    let account = account.to_string();
+                    // 内存中的账户可能还没写回索引
+                    let record = bank.get_record(&account).unwrap_or(record);
+                    println!(
+                        "账户{}余额：{} {}，状态：{}，开户日期：{}，最近交易：{}",
+                        account,
+                        format_amount(record.balance),
+                        record.currency,
+                        record.status,
+                        record.opened_on,
+                        record.last_activity.date()
+                    );
+                }
+            }
+            9 => {
+                let mut account = String::new();
+                println!("请输入账号：");
+                std::io::stdin().read_line(&mut account).unwrap();
+                let account = account.trim().to_string();
+                if !bank.check_account(account.clone()) {
+                    println!("账号不存在！");
+                    continue;
+                }
+                let status = match bank.get_record(&account).unwrap().status {
+                    AccountStatus::Active => AccountStatus::Frozen,
+                    AccountStatus::Frozen => AccountStatus::Active,
+                    AccountStatus::Closed => {
+                        println!("账户{}已销户", account);
+                        continue;
+                    }
+                };
+                bank.set_status(&account, status).unwrap();
+                // 状态立即写回索引
+                btree
+                    .set(&str::parse::<i32>(&account).unwrap(), &bank.get_record(&account).unwrap())
+                    .unwrap();
+                println!("账户{}状态：{}", account, status);
+            }
+            10 => {
+                let mut path = String::new();
+                println!("请输入备份文件路径：");
+                std::io::stdin().read_line(&mut path).unwrap();
+                let path = path.trim().to_string();
+                // 内存中的账户先写回索引，备份期间其他操作照常进行
+                let records: Vec<(i32, AccountRecord)> = bank
+                    .get_accounts()
+                    .into_iter()
+                    .map(|(account, record)| (str::parse::<i32>(&account).unwrap(), record))
+                    .collect();
+                btree.set_many(&records).unwrap();
+                let btree = btree.clone();
+                p.execute(
+                    move || {
+                        let start = Instant::now(); //计时开始
+                        match btree.backup_to(&path) {
+                            Ok(report) => println!("已备份到{}，共{}个账户，用时{}", path, report.keys, start.elapsed()),
+                            Err(err) => println!("备份失败：{:#}", err),
+                        }
+                    },
+                    false,
+                );
+            }
+            11 => {
+                // 内存中的账户先写回索引，汇总的是此刻的快照，期间的存取款不影响结果
+                let records: Vec<(i32, AccountRecord)> = bank
+                    .get_accounts()
+                    .into_iter()
+                    .map(|(account, record)| (str::parse::<i32>(&account).unwrap(), record))
+                    .collect();
+                btree.set_many(&records).unwrap();
+                let btree = btree.clone();
+                p.execute(
+                    move || {
+                        let start = Instant::now(); //计时开始
+                        let snapshot = match btree.snapshot() {
+                            Ok(snapshot) => snapshot,
+                            Err(err) => {
+                                println!("无法建立快照：{:#}", err);
+                                return;
+                            }
+                        };
+                        if snapshot.is_empty() {
+                            println!("索引里还没有账户");
+                            return;
+                        }
+                        // 每种币种的账户数和余额合计
+                        let mut totals: Vec<(Currency, usize, i64)> = Vec::new();
+                        for item in snapshot.iter().unwrap() {
+                            let (_, record) = item.unwrap();
+                            match totals.iter_mut().find(|(currency, _, _)| *currency == record.currency) {
+                                Some((_, count, sum)) => {
+                                    *count += 1;
+                                    *sum += record.balance;
+                                }
+                                None => totals.push((record.currency, 1, record.balance)),
+                            }
+                        }
+                        for (currency, count, sum) in totals {
+                            println!("{}：{}个账户，余额合计{}", currency, count, format_amount(sum));
+                        }
+                        println!(
+                            "共{}个账户，汇总期间为快照保留了{}页旧页，用时{}",
+                            snapshot.len(),
+                            snapshot.kept_pages(),
+                            start.elapsed()
+                        );
+                    },
+                    false,
+                );
+            }
             _ => {
                 println!("{}", "请重新输入")
             }
         }
     }
 }
+
+// 打印错误并以非零状态退出
+fn exit_with(err: anyhow::Error) -> ! {
+    eprintln!("{:#}", err);
+    std::process::exit(1);
+}
+
+// 内存中没有的账户一次性从索引批量加载，返回存在的账户
+fn load_accounts(bank: &mut Bank, btree: &BTree<i32, AccountRecord>, accounts: Vec<String>) -> Vec<String> {
+    let missing: Vec<&String> = accounts.iter().filter(|account| !bank.is_loaded(account)).collect();
+    let keys: Vec<i32> = missing.iter().map(|account| str::parse::<i32>(account).unwrap()).collect();
+    for (account, record) in missing.into_iter().zip(btree.get_many(&keys).unwrap()) {
+        match record {
+            Some(record) => bank.add_account(account.clone(), record),
+            None => println!("账号{}不存在！", account),
+        }
+    }
+    accounts.into_iter().filter(|account| bank.is_loaded(account)).collect()
+}
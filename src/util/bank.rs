@@ -1,92 +1,231 @@
 use std::collections::HashMap;
+use std::fmt::{Display, Formatter};
 
 
 use std::sync::{Arc, Mutex};
+use time::{Date, OffsetDateTime};
 
 use super::btree;
+use super::byte::{BinSizer, Decodable, Encodable};
+
+/// 索引里存的账户记录，金额都以分为单位
+#[derive(Debug, Clone, PartialEq, BinSizer, Encodable, Decodable)]
+pub struct AccountRecord {
+    pub balance: i64,
+    pub currency: Currency,
+    pub status: AccountStatus,
+    pub opened_on: Date,
+    pub last_activity: OffsetDateTime,
+    pub owner_id: u64,
+    // 可透支的额度，余额最低到 -overdraft_limit
+    pub overdraft_limit: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BinSizer, Encodable, Decodable)]
+pub enum Currency {
+    Cny,
+    Usd,
+    Eur,
+    Hkd,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, BinSizer, Encodable, Decodable)]
+pub enum AccountStatus {
+    Active,
+    Frozen,
+    Closed,
+}
+
+impl AccountRecord {
+    /// 现在开户的人民币账户
+    pub fn open(balance: i64) -> Self {
+        let now = now();
+        AccountRecord {
+            balance,
+            currency: Currency::Cny,
+            status: AccountStatus::Active,
+            opened_on: now.date(),
+            last_activity: now,
+            owner_id: 0,
+            overdraft_limit: 0,
+        }
+    }
+}
+
+impl Display for Currency {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let code = match self {
+            Currency::Cny => "CNY",
+            Currency::Usd => "USD",
+            Currency::Eur => "EUR",
+            Currency::Hkd => "HKD",
+        };
+        write!(f, "{}", code)
+    }
+}
+
+impl Display for AccountStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let status = match self {
+            AccountStatus::Active => "正常",
+            AccountStatus::Frozen => "冻结",
+            AccountStatus::Closed => "已销户",
+        };
+        write!(f, "{}", status)
+    }
+}
+
+// 索引只精确到秒，内存里的时间也截到秒，写回再读出来不变
+fn now() -> OffsetDateTime {
+    OffsetDateTime::from_unix_timestamp(OffsetDateTime::now_utc().unix_timestamp()).unwrap()
+}
+
+/// 把 `12`、`12.3`、`-12.34` 这样的金额解析成分，格式不对或溢出时返回 None
+pub fn parse_amount(s: &str) -> Option<i64> {
+    let (negative, s) = match s.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, s),
+    };
+    let (yuan, fen) = match s.split_once('.') {
+        Some((yuan, fen)) if fen.len() <= 2 => (yuan, fen),
+        Some(_) => return None,
+        None => (s, "0"),
+    };
+    let digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+    if !digits(yuan) || !digits(fen) {
+        return None;
+    }
+    let fen = format!("{:0<2}", fen).parse::<i64>().ok()?;
+    let amount = yuan.parse::<i64>().ok()?.checked_mul(100)?.checked_add(fen)?;
+    Some(if negative { -amount } else { amount })
+}
+
+/// 把以分为单位的金额格式化成 `12.34`
+pub fn format_amount(amount: i64) -> String {
+    let sign = if amount < 0 { "-" } else { "" };
+    let amount = amount.unsigned_abs();
+    format!("{}{}.{:02}", sign, amount / 100, amount % 100)
+}
 
 #[derive(Clone)]
 pub struct Bankaccount {
     account_number: String,
-    balance:i32,
+    record: AccountRecord,
 }
 #[derive(Clone)]
 pub struct Bank{
     // Arc是rust中的原子引用计数，线程安全的线程间数据共享的操作
     // Mutex互斥锁，保护共享数据
-    accounts:HashMap<String,Arc<Mutex<Bankaccount>>>,
-    payroll:i32,
-    interest:i32,
+    // 账户表本身也是共享的，线程池里的任务从索引加载的账户对所有克隆可见
+    accounts:Arc<Mutex<HashMap<String,Arc<Mutex<Bankaccount>>>>>,
+    payroll:i64,
+    interest:i64,
+    // 内存中没有的账户从索引查找
+    index:Option<Arc<btree::BTree<i32,AccountRecord>>>,
 }
 
 impl Bank{
 
     pub fn new() -> Self{
-        Bank{accounts:HashMap::new(),payroll:200,interest:10}
+        Bank{accounts:Arc::new(Mutex::new(HashMap::new())),payroll:20000,interest:10,index:None}
+    }
+
+    /// 内存中没有的账户到索引里查找并加载
+    pub fn with_index(index: Arc<btree::BTree<i32,AccountRecord>>) -> Self{
+        Bank{index:Some(index),..Bank::new()}
     }
 
     pub fn init(&mut self){
-        self.accounts.insert("123".to_string(),Arc::new(Mutex::new(Bankaccount{account_number:"123".to_string(),balance: 0})));
-        self.accounts.insert("234".to_string(),Arc::new(Mutex::new(Bankaccount{account_number:"234".to_string(),balance: 0})));
-        self.accounts.insert("345".to_string(),Arc::new(Mutex::new(Bankaccount{account_number:"345".to_string(),balance: 200})));
-        self.accounts.insert("456".to_string(),Arc::new(Mutex::new(Bankaccount{account_number:"456".to_string(),balance: 200})));
+        for (account, balance) in [("123", 0), ("234", 0), ("345", 200), ("456", 200)] {
+            self.add_account(account.to_string(), AccountRecord::open(balance));
+        }
     }
 
-    pub fn get_accounts(&self)->Vec<(String,i32)> {
+    pub fn get_accounts(&self)->Vec<(String,AccountRecord)> {
         let mut result = Vec::new();
-        for (k,v) in self.accounts.iter() {
-            result.push((k.clone(),v.try_lock().unwrap().balance));
+        for (k,v) in self.accounts.lock().unwrap().iter() {
+            result.push((k.clone(),v.try_lock().unwrap().record.clone()));
         }
         result
     }
 
-    pub fn add_account(&mut self, account: String, amount:i32){
-        self.accounts.insert(account.clone(),Arc::new(Mutex::new(Bankaccount{account_number:account,balance: amount})));
+    // 已经在内存中的账户不会被覆盖，两个任务同时从索引加载同一个账户时保留先加载的那份
+    pub fn add_account(&mut self, account: String, record: AccountRecord){
+        self.accounts.lock().unwrap().entry(account.clone()).or_insert_with(|| Arc::new(Mutex::new(Bankaccount{account_number:account,record})));
+    }
+
+    pub fn remove_account(&mut self, account: String) -> Option<AccountRecord> {
+        self.accounts.lock().unwrap().remove(&account).map(|a| a.try_lock().unwrap().record.clone())
     }
 
+    /// 账户是否已经加载到内存
+    pub fn is_loaded(&self,account:&str)->bool {
+        self.accounts.lock().unwrap().contains_key(account)
+    }
+
+    /// 账户是否存在。内存中没有时先问索引的布隆过滤器，过滤器确定没有的账号不读索引页；
+    /// 可能存在时从索引加载。读索引出错时打印错误并按不存在处理
     pub fn check_account(&mut self,account:String)->bool {
-        if self.accounts.contains_key(&account.clone()) {
+        if self.is_loaded(&account) {
             return true
         }
-        false
+        let Some(index) = self.index.clone() else { return false };
+        let Ok(key) = account.parse::<i32>() else { return false };
+        if !index.may_contain(&key) {
+            return false
+        }
+        match index.get(&key) {
+            Ok(Some(record)) => {
+                self.add_account(account, record);
+                true
+            }
+            Ok(None) => false,
+            Err(err) => {
+                println!("读取账号{}失败：{:#}", account, err);
+                false
+            }
+        }
     }
 
-    pub fn deposit(&mut self ,account:String, amount:i32)->Result<(),String>{
+    pub fn deposit(&mut self ,account:String, amount:i64)->Result<(),String>{
 
-        let accounts = &self.accounts;  
-        let account = match accounts.get(&account){
+        let account = match self.get_account(&account){
             Some(account) => account,
             None => return Err(format!("账户不存在"))
         };
-        match account.try_lock().unwrap().deposit(amount) {
+        let mut account = account.try_lock().unwrap();
+        match account.deposit(amount) {
             Ok(()) =>{Ok(())},
             Err(err) => Err(err),
         }
     }
 
-    pub fn withdraw(&mut self,account:String,amount:i32)->Result<(),String>{
-        let accounts = &self.accounts; 
-        let account = match accounts.get(&account){
+    pub fn withdraw(&mut self,account:String,amount:i64)->Result<(),String>{
+        let account = match self.get_account(&account){
             Some(account) => account,
             None => return Err(format!("账户不存在"))
         };
-        match account.try_lock().unwrap().withdraw(amount) {
+        let mut account = account.try_lock().unwrap();
+        match account.withdraw(amount) {
             Ok(())=>{Ok(())},
             Err(err) => return Err(err),
         }
 
     }
     
-    pub fn transfer(&mut self,amount:i32,from:String, to:String)->Result<(),String>{
-        let accounts = &self.accounts; 
-        let fromaccount= accounts.get(&from).unwrap();
-        match fromaccount.try_lock().unwrap().withdraw(amount){
+    pub fn transfer(&mut self,amount:i64,from:String, to:String)->Result<(),String>{
+        let toaccount = self.get_account(&to).ok_or_else(|| "账户不存在".to_string())?;
+        // 收款账户不能入账时不能先扣款
+        toaccount.try_lock().unwrap().check_active()?;
+        let fromaccount= self.get_account(&from).ok_or_else(|| "账户不存在".to_string())?;
+        let mut fromaccount = fromaccount.try_lock().unwrap();
+        match fromaccount.withdraw(amount){
             Err(err) =>{
                 Err(err)
             },
             _=>{
-                let toaccount = accounts.get(&to).unwrap();
-                match toaccount.try_lock().unwrap().deposit(amount){
+                let mut toaccount = toaccount.try_lock().unwrap();
+                match toaccount.deposit(amount){
                     Ok(()) =>{Ok(())},
                     Err(err) =>{Err(err)},
                 }
@@ -101,10 +240,10 @@ impl Bank{
     }
 
     pub fn payinterest(&mut self,account:String)->Result<(),String>{
-        let accounts = &self.accounts;  
+        let accounts = self.accounts.lock().unwrap();  
         match accounts.get(&account){
             Some(tempaccount) => {
-                let amount = self.showbalance(account.clone())/ self.interest;
+                let amount = tempaccount.try_lock().unwrap().record.balance / self.interest;
                 match tempaccount.try_lock().unwrap().deposit(amount) {
                     Ok(()) =>{Ok(())},
                     Err(err) => Err(err),
@@ -114,29 +253,61 @@ impl Bank{
         }
     }
 
-    pub fn showbalance(&self,account_number: String)->i32{
-        self.accounts.get(&account_number).unwrap().try_lock().unwrap().balance
+    pub fn set_status(&mut self, account: &str, status: AccountStatus) -> Result<(), String> {
+        let account = self.get_account(account).ok_or_else(|| "账户不存在".to_string())?;
+        account.try_lock().unwrap().record.status = status;
+        Ok(())
+    }
+
+    // 只在取出账户时锁住账户表，不会和其他账户的操作互相等待
+    fn get_account(&self, account: &str) -> Option<Arc<Mutex<Bankaccount>>> {
+        self.accounts.lock().unwrap().get(account).cloned()
+    }
+
+    pub fn get_record(&self, account: &str) -> Option<AccountRecord> {
+        self.get_account(account).map(|account| account.try_lock().unwrap().record.clone())
+    }
+
+    pub fn showbalance(&self,account_number: String)->i64{
+        self.accounts.lock().unwrap().get(&account_number).unwrap().try_lock().unwrap().record.balance
     }
 }
 
 impl Bankaccount{
-    pub fn deposit(&mut self,amount:i32)->Result<(),String>{
-        if amount>0 {
-            self.balance += amount;
-            Ok(())
-        }else{
-            Err(format!("存款失败"))
+    pub fn deposit(&mut self,amount:i64)->Result<(),String>{
+        self.check_active()?;
+        match self.record.balance.checked_add(amount) {
+            Some(balance) if amount > 0 => {
+                self.record.balance = balance;
+                self.record.last_activity = now();
+                Ok(())
+            }
+            _ => Err("存款失败".to_string()),
         }
     }
 
-    pub fn withdraw(&mut self,amount:i32)->Result<(),String>{
+    pub fn withdraw(&mut self,amount:i64)->Result<(),String>{
+        self.check_active()?;
         if amount<0{
             Err(format!("取款失败"))
-        }else if self.balance < amount{
-            Err(format!("余额不足"))
         }else{
-            self.balance -= amount;
-            Ok(())
+            match self.record.balance.checked_sub(amount) {
+                Some(balance) if balance >= -self.record.overdraft_limit => {
+                    self.record.balance = balance;
+                    self.record.last_activity = now();
+                    Ok(())
+                }
+                _ => Err("余额不足".to_string()),
+            }
+        }
+    }
+
+    // 冻结和销户的账户不能存取款
+    pub fn check_active(&self)->Result<(),String>{
+        match self.record.status {
+            AccountStatus::Active => Ok(()),
+            AccountStatus::Frozen => Err(format!("账户{}已冻结", self.account_number)),
+            AccountStatus::Closed => Err(format!("账户{}已销户", self.account_number)),
         }
     }
 
@@ -148,6 +319,7 @@ mod tests {
     use super::btree;
 
     use super::*;
+    use crate::util::test_util::{remove_files, temp_path};
     use std::{thread, io::{Read, BufReader, BufRead, Write}};
     #[test]
     pub fn test_transfer_succeeds(){
@@ -265,13 +437,123 @@ mod tests {
         assert_eq!(bank.check_account("222".to_string()),false);
     }
 
+    #[test]
+    pub fn test_check_account_in_index(){
+        let path = temp_path("bank-index");
+        let bloom_path = crate::util::bloom::BloomFilter::path_for(path);
+        let options = btree::Options::default().bloom_filter(0.01);
+        let index = Arc::new(btree::BTree::<i32, AccountRecord>::open(path, options.clone()).unwrap());
+        for account in 0..100 {
+            index.set(&(account * 2), &AccountRecord::open(account as i64)).unwrap();
+        }
+        let mut bank = Bank::with_index(index.clone());
+        assert!(bank.check_account("10".to_string()));
+        assert!(bank.is_loaded("10"));
+        assert_eq!(bank.showbalance("10".to_string()),5);
+        // 过滤器排除的账号不读索引页
+        let reads = || { let stats = index.pool_stats(); stats.hits + stats.misses };
+        let before = reads();
+        assert!((0..100).filter(|account| !bank.check_account((account * 2 + 1).to_string())).count() > 90);
+        assert!(reads() - before < 20);
+        assert!(!bank.check_account("abc".to_string()));
+
+        // 崩溃后过滤器文件没有标记为正常关闭，重新打开时从索引重建，不会漏掉账户
+        index.set(&7001, &AccountRecord::open(7001)).unwrap();
+        std::mem::forget(bank);
+        std::mem::forget(index);
+        let index = Arc::new(btree::BTree::<i32, AccountRecord>::open(path, options).unwrap());
+        let mut bank = Bank::with_index(index.clone());
+        assert!(bank.check_account("7001".to_string()));
+        assert!(!bank.check_account("7003".to_string()));
+        drop(bank);
+        drop(index);
+        remove_files(path);
+        std::fs::remove_file(bloom_path).unwrap();
+    }
+
     #[test]
     pub fn test_add_account(){
         let mut bank = Bank::new();
         bank.init();
         assert_eq!(bank.check_account("222".to_string()),false);
-        bank.add_account("222".to_string(),222);
+        bank.add_account("222".to_string(),AccountRecord::open(222));
         assert_eq!(bank.check_account("222".to_string()),true);
         assert_eq!(bank.showbalance("222".to_string()),222);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_add_account_shared_between_clones(){
+        let mut bank = Bank::new();
+        let mut worker = bank.clone();
+        worker.add_account("222".to_string(),AccountRecord::open(222));
+        assert_eq!(bank.showbalance("222".to_string()),222);
+        // an account already in memory keeps its balance
+        bank.deposit("222".to_string(), 8).unwrap();
+        worker.add_account("222".to_string(),AccountRecord::open(222));
+        assert_eq!(worker.showbalance("222".to_string()),230);
+    }
+
+    #[test]
+    pub fn test_remove_account(){
+        let mut bank = Bank::new();
+        bank.init();
+        assert_eq!(bank.remove_account("345".to_string()).map(|r| r.balance),Some(200));
+        assert!(!bank.check_account("345".to_string()));
+        assert_eq!(bank.remove_account("345".to_string()),None);
+    }
+
+    #[test]
+    pub fn test_frozen_account(){
+        let mut bank = Bank::new();
+        bank.init();
+        bank.set_status("345", AccountStatus::Frozen).unwrap();
+        assert_eq!(bank.deposit("345".to_string(), 20),Err("账户345已冻结".to_string()));
+        assert_eq!(bank.withdraw("345".to_string(), 20),Err("账户345已冻结".to_string()));
+        assert_eq!(bank.transfer(20, "345".to_string(), "456".to_string()),Err("账户345已冻结".to_string()));
+        // nothing leaves the payer when the payee cannot take it
+        assert_eq!(bank.transfer(20, "456".to_string(), "345".to_string()),Err("账户345已冻结".to_string()));
+        assert_eq!(bank.showbalance("456".to_string()),200);
+        bank.set_status("345", AccountStatus::Active).unwrap();
+        assert_eq!(bank.withdraw("345".to_string(), 20),Ok(()));
+        assert_eq!(bank.set_status("999", AccountStatus::Closed),Err("账户不存在".to_string()));
+    }
+
+    #[test]
+    pub fn test_overdraft_limit(){
+        let mut bank = Bank::new();
+        let mut record = AccountRecord::open(100);
+        record.overdraft_limit = 50;
+        bank.add_account("777".to_string(), record);
+        assert_eq!(bank.withdraw("777".to_string(), 151),Err("余额不足".to_string()));
+        assert_eq!(bank.withdraw("777".to_string(), 150),Ok(()));
+        assert_eq!(bank.showbalance("777".to_string()),-50);
+        assert_eq!(bank.withdraw("777".to_string(), i64::MAX),Err("余额不足".to_string()));
+        assert_eq!(bank.get_record("777").unwrap().overdraft_limit,50);
+    }
+
+    #[test]
+    pub fn test_account_record_round_trip(){
+        let mut record = AccountRecord::open(-12345);
+        record.currency = Currency::Hkd;
+        record.status = AccountStatus::Closed;
+        record.owner_id = u64::MAX;
+        let mut buf = vec![0; AccountRecord::bin_size()];
+        record.encode(&mut buf).unwrap();
+        assert_eq!(AccountRecord::decode(&buf).unwrap(), (record, buf.len()));
+    }
+
+    #[test]
+    pub fn test_amounts(){
+        assert_eq!(parse_amount("12"), Some(1200));
+        assert_eq!(parse_amount("12.3"), Some(1230));
+        assert_eq!(parse_amount("-12.34"), Some(-1234));
+        assert_eq!(parse_amount("0.05"), Some(5));
+        for bad in ["", "-", ".5", "12.", "12.345", "1,2", "+1", "92233720368547758.08"] {
+            assert_eq!(parse_amount(bad), None, "{}", bad);
+        }
+        assert_eq!(parse_amount("92233720368547758.07"), Some(i64::MAX));
+        assert_eq!(format_amount(1234), "12.34");
+        assert_eq!(format_amount(-5), "-0.05");
+        assert_eq!(format_amount(i64::MIN), "-92233720368547758.08");
+    }
+}
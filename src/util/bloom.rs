@@ -0,0 +1,296 @@
+use super::page::PAGE_SIZE;
+use super::wal::crc32;
+use anyhow::{anyhow, Result};
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+// "BLOM"
+const MAGIC: u32 = 0x424c_4f4d;
+const VERSION: u32 = 1;
+// every page ends with a CRC32 of the bytes before it, like the pages of the btree file
+const PAGE_BODY_SIZE: usize = PAGE_SIZE - 4;
+// a filter is never sized for fewer keys than this
+pub const MIN_CAPACITY: u64 = 1024;
+
+/// Bloom filter over the encoded keys of a `BTree`, saved in `.bloom` next to the `.btree` file.
+///
+/// The first page of the file is a header: magic, version, whether the file was closed
+/// cleanly, the number of hash functions, the number of bits, the capacity in keys, the keys
+/// inserted so far and the false-positive rate it was sized for. The bits follow, one page
+/// after another. The whole filter is kept in memory and only the pages whose bits changed
+/// are written back.
+///
+/// A filter opened for writing marks its file as not clean until it is dropped, so after a
+/// crash the file may miss bits of keys that made it into the tree and is rebuilt instead of
+/// loaded. Bits are never cleared, a removed key is a false positive until the filter is
+/// rebuilt.
+pub(crate) struct BloomFilter {
+    // where the filter is saved, None while it is not attached to a file
+    path: Option<PathBuf>,
+    bits: Vec<u8>,
+    num_bits: u64,
+    hashes: u32,
+    capacity: u64,
+    inserted: u64,
+    fp_rate: f64,
+    // whether the file it was loaded from had been closed cleanly
+    clean: bool,
+    // pages of bits changed since the filter was last saved, counted from the first bit page
+    dirty: BTreeSet<usize>,
+}
+
+impl BloomFilter {
+    pub fn path_for(btree_path: impl AsRef<Path>) -> PathBuf {
+        let mut path = btree_path.as_ref().as_os_str().to_owned();
+        path.push(".bloom");
+        path.into()
+    }
+
+    /// An empty filter for up to `capacity` keys with about `fp_rate` false positives.
+    pub fn new(capacity: u64, fp_rate: f64) -> Self {
+        let capacity = capacity.max(MIN_CAPACITY);
+        // the usual m = -n ln p / (ln 2)^2 bits and k = m / n ln 2 hash functions
+        let num_bits = (-(capacity as f64) * fp_rate.ln() / std::f64::consts::LN_2.powi(2)).ceil() as u64;
+        let num_bits = num_bits.max(64);
+        let hashes = ((num_bits as f64 / capacity as f64) * std::f64::consts::LN_2).round() as u32;
+        let pages = (num_bits as usize).div_ceil(PAGE_BODY_SIZE * 8);
+        BloomFilter {
+            path: None,
+            bits: vec![0; pages * PAGE_BODY_SIZE],
+            num_bits,
+            hashes: hashes.clamp(1, 30),
+            capacity,
+            inserted: 0,
+            fp_rate,
+            clean: true,
+            dirty: (0..pages).collect(),
+        }
+    }
+
+    pub fn check_fp_rate(fp_rate: f64) -> Result<()> {
+        if !(fp_rate > 0.0 && fp_rate < 1.0) {
+            return Err(anyhow!("false-positive rate must be in (0, 1), got {}", fp_rate));
+        }
+        Ok(())
+    }
+
+    /// Loads the filter saved at path, None if there is none. A file that was not closed
+    /// cleanly or has a damaged page is loaded as not clean.
+    pub fn load(path: &Path) -> Result<Option<Self>> {
+        let mut file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err.into()),
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let pages: Vec<&[u8]> = data.chunks(PAGE_SIZE).collect();
+        let intact = |page: &[u8]| {
+            page.len() == PAGE_SIZE && crc32(&page[..PAGE_BODY_SIZE]).to_be_bytes() == page[PAGE_BODY_SIZE..]
+        };
+        let header = match pages.first() {
+            Some(header) if intact(header) => *header,
+            _ => return Ok(None),
+        };
+        let u32_at = |pos: usize| u32::from_be_bytes(header[pos..pos + 4].try_into().unwrap());
+        let u64_at = |pos: usize| u64::from_be_bytes(header[pos..pos + 8].try_into().unwrap());
+        if u32_at(0) != MAGIC || u32_at(4) != VERSION {
+            return Ok(None);
+        }
+        let num_bits = u64_at(16);
+        let bit_pages = (num_bits as usize).div_ceil(PAGE_BODY_SIZE * 8);
+        if num_bits == 0 || pages.len() != 1 + bit_pages {
+            return Ok(None);
+        }
+        let mut filter = BloomFilter {
+            path: None,
+            bits: vec![0; bit_pages * PAGE_BODY_SIZE],
+            num_bits,
+            hashes: u32_at(12),
+            capacity: u64_at(24),
+            inserted: u64_at(32),
+            fp_rate: f64::from_bits(u64_at(40)),
+            clean: u32_at(8) == 1,
+            dirty: BTreeSet::new(),
+        };
+        for (i, chunk) in filter.bits.chunks_mut(PAGE_BODY_SIZE).enumerate() {
+            match pages.get(i + 1) {
+                Some(page) if intact(page) => chunk.copy_from_slice(&page[..PAGE_BODY_SIZE]),
+                _ => filter.clean = false,
+            }
+        }
+        Ok(Some(filter))
+    }
+
+    pub fn is_clean(&self) -> bool {
+        self.clean
+    }
+
+    pub fn fp_rate(&self) -> f64 {
+        self.fp_rate
+    }
+
+    /// Whether more keys went in than the filter was sized for, its false-positive rate is
+    /// then above `fp_rate`.
+    pub fn is_overfull(&self) -> bool {
+        self.inserted > self.capacity
+    }
+
+    pub fn insert(&mut self, key: &[u8]) {
+        for bit in self.bit_indexes(key) {
+            self.bits[bit / 8] |= 1 << (bit % 8);
+            self.dirty.insert(bit / 8 / PAGE_BODY_SIZE);
+        }
+        self.inserted += 1;
+    }
+
+    /// False if the key was never inserted, true if it probably was.
+    pub fn may_contain(&self, key: &[u8]) -> bool {
+        self.bit_indexes(key).all(|bit| self.bits[bit / 8] & (1 << (bit % 8)) != 0)
+    }
+
+    // double hashing, the i-th hash function is h1 + i * h2
+    fn bit_indexes(&self, key: &[u8]) -> impl Iterator<Item = usize> {
+        let h1 = hash(key, 0);
+        let h2 = hash(key, 0x9e37_79b9_7f4a_7c15) | 1;
+        let num_bits = self.num_bits;
+        (0..self.hashes as u64).map(move |i| (h1.wrapping_add(i.wrapping_mul(h2)) % num_bits) as usize)
+    }
+
+    /// Saves the filter to path from now on, writing it out and marking the file as not clean
+    /// until the filter is dropped.
+    pub fn attach(&mut self, path: PathBuf) -> Result<()> {
+        self.path = Some(path);
+        // the file may hold a clean header of another filter, which must not end up in front
+        // of these bits
+        let mut file = self.open_file()?;
+        self.write_header(&mut file, false)?;
+        self.save(false)
+    }
+
+    /// Stops saving the filter, the file is left as it is.
+    pub fn detach(&mut self) {
+        self.path = None;
+    }
+
+    /// Writes the changed pages and the header and fsyncs the file.
+    pub fn save(&mut self, clean: bool) -> Result<()> {
+        if self.path.is_none() {
+            return Ok(());
+        }
+        let mut file = self.open_file()?;
+        let pages = self.bits.len() / PAGE_BODY_SIZE;
+        file.set_len(((1 + pages) * PAGE_SIZE) as u64)?;
+        for &i in &self.dirty {
+            file.seek(SeekFrom::Start(((1 + i) * PAGE_SIZE) as u64))?;
+            file.write_all(&page_image(&self.bits[i * PAGE_BODY_SIZE..(i + 1) * PAGE_BODY_SIZE]))?;
+        }
+        // the header goes last, so a file marked clean has all of its bits
+        file.sync_data()?;
+        self.write_header(&mut file, clean)?;
+        self.dirty.clear();
+        Ok(())
+    }
+
+    fn open_file(&self) -> Result<File> {
+        let path = self.path.as_ref().expect("the filter is attached");
+        Ok(OpenOptions::new().create(true).truncate(false).write(true).open(path)?)
+    }
+
+    fn write_header(&self, file: &mut File, clean: bool) -> Result<()> {
+        let mut header = vec![0; PAGE_BODY_SIZE];
+        header[0..4].copy_from_slice(&MAGIC.to_be_bytes());
+        header[4..8].copy_from_slice(&VERSION.to_be_bytes());
+        header[8..12].copy_from_slice(&(clean as u32).to_be_bytes());
+        header[12..16].copy_from_slice(&self.hashes.to_be_bytes());
+        header[16..24].copy_from_slice(&self.num_bits.to_be_bytes());
+        header[24..32].copy_from_slice(&self.capacity.to_be_bytes());
+        header[32..40].copy_from_slice(&self.inserted.to_be_bytes());
+        header[40..48].copy_from_slice(&self.fp_rate.to_bits().to_be_bytes());
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&page_image(&header))?;
+        file.sync_data()?;
+        Ok(())
+    }
+}
+
+// the header is written last, a filter that cannot be saved leaves its file marked not clean
+// and it is rebuilt when the tree is opened again
+impl Drop for BloomFilter {
+    fn drop(&mut self) {
+        if self.path.is_some() {
+            if let Err(err) = self.save(true) {
+                eprintln!("could not save the Bloom filter: {:#}", err);
+            }
+        }
+    }
+}
+
+fn page_image(body: &[u8]) -> Vec<u8> {
+    let mut image = body.to_vec();
+    image.extend_from_slice(&crc32(body).to_be_bytes());
+    image
+}
+
+// FNV-1a finished with the murmur3 mixer. The bits are saved, so unlike std's hashers this
+// must give the same hash on every build
+fn hash(key: &[u8], seed: u64) -> u64 {
+    let mut h = 0xcbf2_9ce4_8422_2325 ^ seed;
+    for byte in key {
+        h ^= *byte as u64;
+        h = h.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51_afd7_ed55_8ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ce_b9fe_1a85_ec53);
+    h ^ (h >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bloom_filter() {
+        let mut filter = BloomFilter::new(10000, 0.01);
+        for i in 0..10000u32 {
+            filter.insert(&i.to_be_bytes());
+        }
+        assert!((0..10000u32).all(|i| filter.may_contain(&i.to_be_bytes())));
+        let false_positives = (10000..110000u32).filter(|i| filter.may_contain(&i.to_be_bytes())).count();
+        assert!(false_positives < 1500, "{} false positives in 100000", false_positives);
+        assert!(!filter.is_overfull());
+        filter.insert(b"one more");
+        assert!(filter.is_overfull());
+
+        let path = std::env::temp_dir().join(format!("banksys-bloom-{}.bloom", std::process::id()));
+        filter.attach(path.clone()).unwrap();
+        assert!(!BloomFilter::load(&path).unwrap().unwrap().is_clean());
+        filter.insert(b"after attach");
+        drop(filter);
+        let filter = BloomFilter::load(&path).unwrap().unwrap();
+        assert!(filter.is_clean());
+        assert_eq!(filter.fp_rate(), 0.01);
+        assert!(filter.may_contain(b"after attach"));
+        assert!((0..10000u32).all(|i| filter.may_contain(&i.to_be_bytes())));
+
+        // a damaged page of bits is not trusted
+        let mut data = std::fs::read(&path).unwrap();
+        data[PAGE_SIZE + 100] ^= 1;
+        std::fs::write(&path, data).unwrap();
+        assert!(!BloomFilter::load(&path).unwrap().unwrap().is_clean());
+        std::fs::remove_file(&path).unwrap();
+        assert!(BloomFilter::load(&path).unwrap().is_none());
+
+        // a filter that cannot be saved is dropped without a panic
+        let mut filter = BloomFilter::new(100, 0.01);
+        filter.attach(path.clone()).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        std::fs::create_dir(&path).unwrap();
+        drop(filter);
+        std::fs::remove_dir(&path).unwrap();
+    }
+}
@@ -1,19 +1,508 @@
 pub use super::byte::*;
-use super::page::{Page, PageError, PageType, Pos};
-use anyhow::Result;
-use std::cell::RefCell;
-use std::fmt::Debug;
+use super::bloom::BloomFilter;
+use super::page::{internal_record_space, record_space, Page, PageError, PageType, Pos, Value, PAGE_SIZE};
+use super::store::{FileStore, MmapStore, StoreRef};
+use super::wal::{GroupFlusher, LogSync, Wal};
+pub use super::wal::SyncMode;
+use anyhow::{anyhow, Context, Result};
+use parking_lot::{ArcRwLockReadGuard, ArcRwLockWriteGuard, Mutex, MutexGuard, RawRwLock, RwLock};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt::{Debug, Display, Formatter};
 use std::fs::{File, OpenOptions};
-use std::rc::Rc;
+use std::io::Write;
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
 
 // mod page;
 // mod byte;
 
+pub const DEFAULT_POOL_CAPACITY: usize = 1024;
+// the write-ahead log is checkpointed once it grows past this
+pub const CHECKPOINT_WAL_SIZE: u64 = 8 << 20;
+
+pub(crate) type PageRef<K, V> = Arc<RwLock<Page<K, V>>>;
+type ReadLatch<K, V> = ArcRwLockReadGuard<RawRwLock, Page<K, V>>;
+type WriteLatch<K, V> = ArcRwLockWriteGuard<RawRwLock, Page<K, V>>;
+// first key and page index of every page on one level of a bulk load
+type Level = Vec<(Vec<u8>, u32)>;
+
+/// A B+ tree that can be shared between threads behind an `Arc`.
+///
+/// Every page in the buffer pool carries a read/write latch. Readers crab down from the root
+/// holding at most a parent and a child latch, so any number of `get`s and cursors run in
+/// parallel. Writers are serialized by `writer` and crab down with write latches, letting go
+/// of every ancestor as soon as the child below it is safe, i.e. cannot split (`set`) or
+/// underflow (`remove`). Readers only wait for the part of the tree a writer is restructuring.
+///
+/// Each `set` and `remove` commits to a write-ahead log next to the file before any page it
+/// changed can be written back, see `Wal`.
+// fields drop in order, so the pool writes back its pages before the meta page, and both
+// before the log is checkpointed
 pub struct BTree<K, V> {
-    path: &'static str,
-    fd: Rc<RefCell<File>>,
-    meta_page: Option<Page<K, V>>,
-    root_page: Option<Page<K, V>>,
+    path: PathBuf,
+    store: StoreRef,
+    writer: Mutex<()>,
+    // guards the root index in the meta page, taken before the root page latch
+    root_latch: RwLock<()>,
+    // bumped before every split, merge, borrow or root change, cursors re-seek when it moves
+    version: AtomicU64,
+    txn: Mutex<Txn<K, V>>,
+    pool: Mutex<BufferPool<K, V>>,
+    meta_page: Mutex<Page<K, V>>,
+    // None unless the log is fsynced by groups, stopped before the log is dropped
+    _flusher: Option<GroupFlusher>,
+    // None when opened read-only
+    wal: Option<Arc<Mutex<Wal>>>,
+    // None unless a false-positive rate was given or the file has a filter
+    bloom: RwLock<Option<BloomFilter>>,
+    // where the filter is saved, None for a tree not kept in a file
+    bloom_path: Option<PathBuf>,
+    // the pages live snapshots share with the tree, see `BTree::snapshot`
+    snapshots: Mutex<Snapshots>,
+}
+
+impl<K, V> BTree<K, V> {
+    // like `sync`, for `drop`, which cannot name the bounds `sync` needs
+    fn write_back(&self) -> Result<()> {
+        for frame in self.pool.lock().frames.values() {
+            frame.page.write().sync()?;
+        }
+        self.meta_page.lock().sync()
+    }
+}
+
+// the log is fsynced and the pages are written back here, before the fields drop. If either
+// fails, nothing more is written back and the log is left for the next open to replay
+impl<K, V> Drop for BTree<K, V> {
+    fn drop(&mut self) {
+        let mut wal = self.wal.as_ref().map(|wal| wal.lock());
+        let result = match wal.as_mut() {
+            Some(wal) => wal.flush().context("could not sync the write-ahead log"),
+            None => Ok(()),
+        };
+        let result = result.and_then(|_| self.write_back().context("could not write back the pages"));
+        if let Err(err) = result {
+            eprintln!("{:#}", err);
+            for frame in self.pool.lock().frames.values() {
+                frame.page.write().discard();
+            }
+            self.meta_page.lock().discard();
+            if let Some(wal) = wal.as_mut() {
+                wal.abandon();
+            }
+        }
+    }
+}
+
+/// How `BTree::open` opens the file. The defaults open an existing tree for reading and
+/// writing, creating it if it is missing, and fsync every operation. A Bloom filter is used
+/// if the tree has one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    read_only: bool,
+    create: bool,
+    create_new: bool,
+    sync: SyncMode,
+    pool_capacity: usize,
+    bloom_fp_rate: Option<f64>,
+    mmap: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            read_only: false,
+            create: true,
+            create_new: false,
+            sync: SyncMode::Full,
+            pool_capacity: DEFAULT_POOL_CAPACITY,
+            bloom_fp_rate: None,
+            mmap: false,
+        }
+    }
+}
+
+impl Options {
+    /// Opens the file without write access, it is never created. Writes fail, and opening
+    /// fails if the write-ahead log holds operations that still need to be replayed.
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    /// Creates an empty tree if the file does not exist.
+    pub fn create(mut self, create: bool) -> Self {
+        self.create = create;
+        self
+    }
+
+    /// Creates an empty tree and fails if the file already exists.
+    pub fn create_new(mut self, create_new: bool) -> Self {
+        self.create_new = create_new;
+        self
+    }
+
+    /// When an operation is durable, see `SyncMode`.
+    pub fn sync(mut self, sync: SyncMode) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    pub fn pool_capacity(mut self, pool_capacity: usize) -> Self {
+        self.pool_capacity = pool_capacity;
+        self
+    }
+
+    /// Keeps a Bloom filter of the keys with about `fp_rate` false positives in `.bloom` next
+    /// to the file, so that `get` can tell most missing keys apart without reading a page. The
+    /// filter is built from the keys if the file has none yet, it was built for another rate or
+    /// it was not closed cleanly, since after a crash it may miss keys the log put back. Once
+    /// built it is used and kept up to date whenever the file is opened.
+    pub fn bloom_filter(mut self, fp_rate: f64) -> Self {
+        self.bloom_fp_rate = Some(fp_rate);
+        self
+    }
+
+    /// Loads pages in place from a memory mapping of the file instead of reading and copying
+    /// each one, see `MmapStore`. A page is copied when it is first changed, and pages and the
+    /// write-ahead log are written as usual.
+    pub fn mmap(mut self, mmap: bool) -> Self {
+        self.mmap = mmap;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PoolStats {
+    pub capacity: usize,
+    pub cached: usize,
+    pub pinned: usize,
+    pub hits: u64,
+    pub misses: u64,
+    // misses served by a page lent in place from a mapped file rather than read and copied
+    pub mapped: u64,
+    pub evictions: u64,
+    pub write_backs: u64,
+}
+
+/// The pages on one level of the tree, level 0 being the root.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LevelStats {
+    pub level: usize,
+    pub pages: usize,
+    pub keys: usize,
+    // bytes taken by records and their slots, out of pages * Page::capacity()
+    pub used_bytes: usize,
+    pub capacity_bytes: usize,
+}
+
+impl LevelStats {
+    pub fn fill_factor(&self) -> f64 {
+        self.used_bytes as f64 / self.capacity_bytes as f64
+    }
+}
+
+/// What `BTree::verify` found. The tree is healthy if there are no problems.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct VerifyReport {
+    // pages according to META and according to the file length
+    pub total_pages: u32,
+    pub file_pages: u64,
+    // levels from the root down to the leaves
+    pub depth: usize,
+    pub internal_pages: usize,
+    pub leaf_pages: usize,
+    pub overflow_pages: usize,
+    // pages on the free list, including the pages holding the list
+    pub free_pages: usize,
+    // pages kept for snapshots, see `BTree::snapshot`
+    pub retired_pages: usize,
+    pub keys: usize,
+    pub problems: Vec<Problem>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Problem {
+    // the page the problem was found on, 0 for META and the file as a whole
+    pub page: u32,
+    pub message: String,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+impl Display for VerifyReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "pages:          {} in META, {} in the file", self.total_pages, self.file_pages)?;
+        writeln!(f, "depth:          {}", self.depth)?;
+        writeln!(f, "internal pages: {}", self.internal_pages)?;
+        writeln!(f, "leaf pages:     {}", self.leaf_pages)?;
+        writeln!(f, "overflow pages: {}", self.overflow_pages)?;
+        writeln!(f, "free pages:     {}", self.free_pages)?;
+        writeln!(f, "retired pages:  {}", self.retired_pages)?;
+        writeln!(f, "keys:           {}", self.keys)?;
+        write!(f, "problems:       {}", self.problems.len())?;
+        for problem in &self.problems {
+            write!(f, "\n  page {}: {}", problem.page, problem.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// The size and shape of the tree, see `BTree::stats`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    pub height: usize,
+    pub internal_pages: usize,
+    pub leaf_pages: usize,
+    // as counted in META
+    pub keys: u64,
+    pub total_pages: u32,
+    pub file_bytes: u64,
+    // from the root down to the leaves
+    pub levels: Vec<LevelStats>,
+}
+
+impl Display for TreeStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "height:         {}", self.height)?;
+        writeln!(f, "internal pages: {}", self.internal_pages)?;
+        writeln!(f, "leaf pages:     {}", self.leaf_pages)?;
+        writeln!(f, "keys:           {}", self.keys)?;
+        writeln!(f, "total pages:    {}", self.total_pages)?;
+        write!(f, "file size:      {} bytes", self.file_bytes)
+    }
+}
+
+/// What `BTree::compact` did to the file.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CompactReport {
+    pub keys: usize,
+    pub old_pages: u32,
+    pub new_pages: u32,
+    pub old_bytes: u64,
+    pub new_bytes: u64,
+}
+
+impl Display for CompactReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "keys:  {}", self.keys)?;
+        writeln!(f, "pages: {} -> {}", self.old_pages, self.new_pages)?;
+        write!(f, "bytes: {} -> {}", self.old_bytes, self.new_bytes)
+    }
+}
+
+// what the running operation has touched, its pages stay pinned until it is committed to the log.
+// Every page is copied before its first change, so an operation that fails can be rolled back
+struct Txn<K, V> {
+    pages: Vec<PageRef<K, V>>,
+    meta: bool,
+    undo: HashMap<u32, Before<K, V>>,
+    meta_undo: Option<(Page<K, V>, bool)>,
+    // pages taken out of the tree that a snapshot still reads, with the epoch they were
+    // allocated in. They are handed to `Snapshots` once the operation is committed
+    retired: Vec<(u32, u64)>,
+}
+
+impl<K, V> Txn<K, V> {
+    fn new() -> Self {
+        Txn { pages: Vec::new(), meta: false, undo: HashMap::new(), meta_undo: None, retired: Vec::new() }
+    }
+}
+
+// a page as it was before the running operation changed it
+enum Before<K, V> {
+    // its copy and whether it was waiting to be written back
+    Page(Box<Page<K, V>>, bool),
+    // added to the end of the file by the operation
+    New,
+}
+
+// every snapshot starts a new epoch and reads the pages allocated in earlier ones. A writer
+// copies such a page instead of changing it while a snapshot that reads it lives
+#[derive(Default)]
+struct Snapshots {
+    epoch: u64,
+    // the epochs of the live snapshots
+    live: BTreeSet<u64>,
+    // the epoch of every page allocated while a snapshot was live, the other pages are older
+    // than any live snapshot
+    born: HashMap<u32, u64>,
+    // pages taken out of the tree with the epochs they were allocated and retired in, freed
+    // once no live snapshot was taken in between
+    retired: Vec<(u32, u64, u64)>,
+}
+
+impl Snapshots {
+    fn born(&self, index: u32) -> u64 {
+        self.born.get(&index).copied().unwrap_or(0)
+    }
+
+    // a page allocated now is not read by any live snapshot
+    fn allocated(&mut self, index: u32) {
+        if !self.live.is_empty() {
+            self.born.insert(index, self.epoch);
+        }
+    }
+
+    // whether a live snapshot may read page index, which must then be left as it is
+    fn is_shared(&self, index: u32) -> bool {
+        self.live.last().is_some_and(|&last| self.born(index) < last)
+    }
+
+    // whether a live snapshot reads a page allocated in epoch born and retired in epoch retired
+    fn reads(&self, born: u64, retired: u64) -> bool {
+        self.live.range(born + 1..=retired).next().is_some()
+    }
+}
+
+struct Frame<K, V> {
+    page: PageRef<K, V>,
+    last_used: u64,
+}
+
+/// Keeps up to `capacity` pages in memory and evicts the least recently used one when full.
+/// A page is pinned for as long as a `PageRef` to it is held outside the pool and pinned pages
+/// are never evicted, so an operation holding many pages can push the pool over capacity
+/// until they are unpinned. Dirty pages are written back when evicted or flushed.
+struct BufferPool<K, V> {
+    store: StoreRef,
+    // fsynced before a dirty page is written back, None when opened read-only
+    log_sync: Option<Arc<LogSync>>,
+    capacity: usize,
+    frames: HashMap<u32, Frame<K, V>>,
+    // last use tick -> page index, least recently used first
+    lru: BTreeMap<u64, u32>,
+    tick: u64,
+    hits: u64,
+    misses: u64,
+    mapped: u64,
+    evictions: u64,
+    write_backs: u64,
+}
+
+impl<K, V> BufferPool<K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    fn new(store: StoreRef, capacity: usize, log_sync: Option<Arc<LogSync>>) -> Self {
+        assert!(capacity > 0, "buffer pool capacity must be greater than zero");
+        BufferPool {
+            store,
+            log_sync,
+            capacity,
+            frames: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            hits: 0,
+            misses: 0,
+            mapped: 0,
+            evictions: 0,
+            write_backs: 0,
+        }
+    }
+
+    // returns the page pinned, loading it from the file on a miss
+    fn fetch(&mut self, index: u32) -> Result<PageRef<K, V>> {
+        self.tick += 1;
+        if let Some(frame) = self.frames.get_mut(&index) {
+            self.hits += 1;
+            self.lru.remove(&frame.last_used);
+            frame.last_used = self.tick;
+            self.lru.insert(self.tick, index);
+            return Ok(frame.page.clone());
+        }
+        self.misses += 1;
+        let page = Page::<K, V>::load(self.store.clone(), index)?;
+        if page.is_mapped() {
+            self.mapped += 1;
+        }
+        self.insert(page)
+    }
+
+    // caches a page that was just created or loaded. A new page that finds no room is dropped
+    // without being written, its operation fails
+    fn insert(&mut self, mut page: Page<K, V>) -> Result<PageRef<K, V>> {
+        if let Err(err) = self.evict() {
+            page.discard();
+            return Err(err);
+        }
+        self.tick += 1;
+        let index = page.index;
+        let page = Arc::new(RwLock::new(page));
+        self.frames.insert(index, Frame { page: page.clone(), last_used: self.tick });
+        self.lru.insert(self.tick, index);
+        Ok(page)
+    }
+
+    // makes room for one more page by evicting unpinned pages, least recently used first.
+    // latches hold a reference too, so nobody can be waiting on an unpinned page's latch
+    fn evict(&mut self) -> Result<()> {
+        if self.frames.len() < self.capacity {
+            return Ok(());
+        }
+        let victims: Vec<(u64, u32)> = self
+            .lru
+            .iter()
+            .filter(|(_, index)| Arc::strong_count(&self.frames[index].page) == 1)
+            .take(self.frames.len() + 1 - self.capacity)
+            .map(|(tick, index)| (*tick, *index))
+            .collect();
+        for (tick, index) in victims {
+            {
+                let mut page = self.frames[&index].page.write();
+                if page.is_dirty() {
+                    self.flush_log()?;
+                    page.sync()?;
+                    self.write_backs += 1;
+                }
+            }
+            self.frames.remove(&index);
+            self.lru.remove(&tick);
+            self.evictions += 1;
+        }
+        Ok(())
+    }
+
+    // makes the records of every page in the pool durable before one is written back
+    fn flush_log(&self) -> Result<()> {
+        match &self.log_sync {
+            Some(log_sync) => log_sync.flush(),
+            None => Ok(()),
+        }
+    }
+
+    // drops a page without writing it back
+    fn remove(&mut self, index: u32) {
+        if let Some(frame) = self.frames.remove(&index) {
+            self.lru.remove(&frame.last_used);
+        }
+    }
+
+    // the pages are latched by the caller after the pool is unlocked, a thread holding a latch
+    // may be waiting for the pool
+    fn pages(&self) -> Vec<PageRef<K, V>> {
+        self.frames.values().map(|frame| frame.page.clone()).collect()
+    }
+
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            capacity: self.capacity,
+            cached: self.frames.len(),
+            pinned: self.frames.values().filter(|f| Arc::strong_count(&f.page) > 1).count(),
+            hits: self.hits,
+            misses: self.misses,
+            mapped: self.mapped,
+            evictions: self.evictions,
+            write_backs: self.write_backs,
+        }
+    }
 }
 
 impl<K, V> BTree<K, V>
@@ -21,311 +510,3987 @@ where
     K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
     V: Encodable + Decodable + BinSizer + Debug + Clone,
 {
-    pub fn new(path: &'static str) -> Self {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self::open(path, Options::default()).expect("could not open btree file")
+    }
+
+    pub fn with_pool_capacity(path: impl AsRef<Path>, pool_capacity: usize) -> Self {
+        Self::open(path, Options::default().pool_capacity(pool_capacity)).expect("could not open btree file")
+    }
+
+    /// Opens the tree stored at `path`, replaying its write-ahead log if the last process
+    /// using it did not shut down cleanly.
+    pub fn open(path: impl AsRef<Path>, options: Options) -> Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        Self::check_options(&options)?;
         let fd = OpenOptions::new()
-            .create(true)
+            .create(options.create && !options.read_only)
+            .create_new(options.create_new)
             .read(true)
-            .write(true)
-            .open(path)
-            .expect("could not open btree file");
-        let mut btree = BTree::<K, V> {
-            path,
-            fd: Rc::new(RefCell::new(fd)),
-            meta_page: None,
-            root_page: None,
+            .write(!options.read_only)
+            .open(&path)
+            .map_err(|err| anyhow!("could not open {}: {}", path.display(), err))?;
+        let wal_path = Wal::path_for(&path);
+        let wal: Option<StoreRef> = if options.read_only {
+            if std::fs::metadata(&wal_path).map(|m| m.len() > 0).unwrap_or(false) {
+                return Err(anyhow!("{} needs recovery, open it for writing first", wal_path.display()));
+            }
+            None
+        } else {
+            let wal = OpenOptions::new()
+                .create(true)
+                .truncate(false)
+                .read(true)
+                .write(true)
+                .open(&wal_path)?;
+            Some(Arc::new(FileStore::new(wal)))
         };
-        let file_len = btree.fd.as_ref().borrow().metadata().unwrap().len();
-        if file_len == 0 {
-            btree.init_as_empty()
+        let store: StoreRef = if options.mmap {
+            Arc::new(MmapStore::new(fd, !options.read_only)?)
         } else {
-            btree.init_load()
+            Arc::new(FileStore::new(fd))
+        };
+        let mut btree = Self::open_stores(path, store, wal, &options)?;
+        btree.bloom_path = Some(BloomFilter::path_for(&btree.path));
+        btree.open_bloom(options.bloom_fp_rate)?;
+        Ok(btree)
+    }
+
+    /// Opens a tree kept in `data` with its write-ahead log in `wal` rather than in files, e.g.
+    /// in `MemoryStore`s. `name` only shows up in messages. The log is not touched when the
+    /// tree is opened read-only, a Bloom filter needs a tree kept in files and `mmap` has no
+    /// effect.
+    pub fn open_in(name: impl AsRef<Path>, data: StoreRef, wal: StoreRef, options: Options) -> Result<Self> {
+        let name = name.as_ref().to_path_buf();
+        Self::check_options(&options)?;
+        if options.bloom_fp_rate.is_some() {
+            return Err(anyhow!("a Bloom filter needs a btree kept in a file"));
+        }
+        if data.is_empty()? && !options.create && !options.read_only {
+            return Err(anyhow!("could not open {}: it does not exist", name.display()));
         }
-        btree
+        if !data.is_empty()? && options.create_new {
+            return Err(anyhow!("could not open {}: it already exists", name.display()));
+        }
+        let wal = if options.read_only {
+            if !wal.is_empty()? {
+                return Err(anyhow!("{} needs recovery, open it for writing first", name.display()));
+            }
+            None
+        } else {
+            Some(wal)
+        };
+        Self::open_stores(name, data, wal, &options)
     }
 
-    fn sync(&mut self) -> Result<()> {
-        if let Some(p) = self.meta_page.as_mut() {
-            p.sync()?;
+    fn check_options(options: &Options) -> Result<()> {
+        if options.read_only && options.create_new {
+            return Err(anyhow!("a read-only btree cannot be created"));
         }
-        if let Some(p) = self.root_page.as_mut() {
-            p.sync()?;
+        if options.pool_capacity == 0 {
+            return Err(anyhow!("buffer pool capacity must be greater than zero"));
+        }
+        if let Some(fp_rate) = options.bloom_fp_rate {
+            BloomFilter::check_fp_rate(fp_rate)?;
+        }
+        if let SyncMode::Group { ops, interval } = options.sync {
+            if ops == 0 || interval.is_zero() {
+                return Err(anyhow!("group commit needs at least one operation and a non-zero interval"));
+            }
         }
         Ok(())
     }
 
-    fn init_as_empty(&mut self) {
-        println!("init empty btree");
-        let mut meta_page = Page::<K, V>::new(self.fd.clone(), 0, PageType::META).unwrap();
-        meta_page.set_total_page(2);
-        meta_page.set_root_index(1);
-        let mut root_page = Page::<K, V>::new(self.fd.clone(), 1, PageType::LEAF).unwrap();
-        root_page.set_item_count(0).unwrap();
+    // replays the log and loads the tree, or initializes an empty one. Without a log the tree
+    // is read-only
+    fn open_stores(path: PathBuf, store: StoreRef, wal: Option<StoreRef>, options: &Options) -> Result<Self> {
+        let wal = match wal {
+            Some(wal) => {
+                let (wal, replayed) = Wal::open(wal, store.clone(), options.sync)?;
+                if replayed > 0 {
+                    eprintln!("replayed {} operations from the write-ahead log", replayed);
+                }
+                Some(Arc::new(Mutex::new(wal)))
+            }
+            None => None,
+        };
+        let log_sync = wal.as_ref().map(|wal| wal.lock().log_sync());
+        let flusher = match (&log_sync, options.sync) {
+            (Some(log_sync), SyncMode::Group { interval, .. }) => Some(GroupFlusher::spawn(log_sync.clone(), interval)),
+            _ => None,
+        };
+        let pool = BufferPool::new(store.clone(), options.pool_capacity, log_sync);
+        let meta_page = if !store.is_empty()? {
+            Self::init_load(&store)?
+        } else if options.read_only {
+            return Err(PageError::Truncated(0).into());
+        } else {
+            Self::init_as_empty(&store)?
+        };
+        let btree = BTree::<K, V> {
+            path,
+            store,
+            writer: Mutex::new(()),
+            root_latch: RwLock::new(()),
+            version: AtomicU64::new(0),
+            txn: Mutex::new(Txn::new()),
+            pool: Mutex::new(pool),
+            meta_page: Mutex::new(meta_page),
+            _flusher: flusher,
+            wal,
+            bloom: RwLock::new(None),
+            bloom_path: None,
+            snapshots: Mutex::new(Snapshots::default()),
+        };
+        btree.reclaim_retired()?;
+        Ok(btree)
+    }
 
-        self.meta_page = Some(meta_page);
-        self.root_page = Some(root_page);
-        self.sync().unwrap();
+    // uses the filter saved next to the file if it can be trusted and has the rate asked for,
+    // otherwise builds one from the keys. A tree opened read-only goes without
+    fn open_bloom(&self, fp_rate: Option<f64>) -> Result<()> {
+        let stored = match &self.bloom_path {
+            Some(path) => BloomFilter::load(path)?,
+            None => None,
+        };
+        let fp_rate = match fp_rate.or_else(|| stored.as_ref().map(|filter| filter.fp_rate())) {
+            Some(fp_rate) => fp_rate,
+            None => return Ok(()),
+        };
+        let filter = match stored {
+            Some(filter) if filter.is_clean() && filter.fp_rate() == fp_rate => filter,
+            _ if self.is_read_only() => return Ok(()),
+            _ => self.build_bloom(fp_rate)?,
+        };
+        self.install_bloom(filter)
     }
 
-    fn init_load(&mut self) {
-        let meta_page = Page::<K, V>::load(self.fd.clone(), 0).unwrap();
-        assert_eq!(meta_page.page_type, PageType::META);
+    // called with the writer lock held, or before the tree is shared
+    fn build_bloom(&self, fp_rate: f64) -> Result<BloomFilter> {
+        let key_count = self.meta_page.lock().key_count();
+        let mut filter = BloomFilter::new(key_count.saturating_mul(2), fp_rate);
+        let mut next = Some(self.seek_leaf(None, false)?.read().index);
+        while let Some(index) = next {
+            let leaf = self.fetch(index)?;
+            let leaf = leaf.read();
+            for i in 0..leaf.item_count() {
+                let record = leaf.record_at(i);
+                filter.insert(&record[..K::decode(record)?.1]);
+            }
+            next = leaf.next_leaf();
+        }
+        Ok(filter)
+    }
 
-        let root_page = Page::<K, V>::load(self.fd.clone(), meta_page.root_index()).unwrap();
-        println!(
-            "root page index: {}; total pages:{}; root page keys: {};",
-            meta_page.root_index(),
-            meta_page.total_pages(),
-            root_page.item_count()
-        );
-        self.meta_page = Some(meta_page);
-        self.root_page = Some(root_page);
+    fn install_bloom(&self, mut filter: BloomFilter) -> Result<()> {
+        let mut bloom = self.bloom.write();
+        // the filter being replaced must not save itself over the new one
+        if let Some(old) = bloom.as_mut() {
+            old.detach();
+        }
+        if let Some(path) = self.bloom_path.as_ref().filter(|_| !self.is_read_only()) {
+            filter.attach(path.clone())?;
+        }
+        *bloom = Some(filter);
+        Ok(())
     }
 
-    pub fn set(&mut self, key: &K, value: &V) -> Result<()> {
-        let mut p = self.root_page.as_mut().unwrap();
-        let mut pages = Vec::new();
-        loop {
-            match p.page_type {
-                PageType::INTERNAL => match p.find(key) {
-                    Some((i, pos)) => {
-                        let ptr_index = match pos {
-                            Pos::Left => i,
-                            _ => i + 1,
-                        };
-                        let child_page_index = p.ptr_at(ptr_index).unwrap();
-                        pages.push(Page::<K, V>::load(self.fd.clone(), child_page_index).unwrap());
-                        let len = pages.len();
-                        p = &mut pages[len - 1];
-                    }
-                    None => {
-                        panic!("impossible for an empty internal page")
-                    }
-                },
-                PageType::LEAF => {
-                    match p.insert(key, value) {
-                        Ok(_) => {
-                            // inserted, done!
-                            return Ok(());
-                        }
-                        Err(err) => {
-                            match err.downcast_ref::<PageError>() {
-                                Some(PageError::Full) => {
-                                    // eh..., the page is full, we need to split it
-                                    break;
-                                }
-                                _ => {
-                                    return Err(err);
-                                }
-                            }
-                        }
-                    }
-                }
-                _ => {
-                    panic!("impossible a meta page")
-                }
-            }
+    /// False if the key is certainly not in the tree according to the Bloom filter, true if
+    /// it may be or there is no filter.
+    pub fn may_contain(&self, key: &K) -> bool {
+        match &*self.bloom.read() {
+            Some(filter) => Page::<K, V>::encode_key(key).map_or(true, |key| filter.may_contain(&key)),
+            None => true,
         }
-        // page is full, split it!
-        // println!("page is full");
-        let mut kp = None;
-        for p in pages.iter_mut().rev() {
-            match p.page_type {
-                PageType::LEAF => {
-                    // leaf page must be full in this case
-                    kp = Some(self.split_leaf_page(p, key, value)?);
-                }
-                PageType::INTERNAL => {
-                    let (k, ptr) = kp.unwrap();
-                    if p.is_full() {
-                        kp = Some(self.split_internal_page(p, &k, ptr)?);
-                    } else {
-                        p.insert_ptr(&k, ptr)?;
-                        return Ok(());
-                    }
-                }
-                _ => {
-                    panic!("impossible a meta page")
-                }
-            }
+    }
+
+    pub fn bloom_fp_rate(&self) -> Option<f64> {
+        self.bloom.read().as_ref().map(|filter| filter.fp_rate())
+    }
+
+    fn bloom_insert(&self, key: &[u8]) {
+        if let Some(filter) = self.bloom.write().as_mut() {
+            filter.insert(key);
         }
+    }
 
-        // so root page must be changed
-        match kp {
-            Some((k, ptr)) => {
-                let is_root_full;
-                {
-                    let root_page = self.root_page.as_mut().unwrap();
-                    assert_eq!(root_page.page_type, PageType::INTERNAL);
-                    is_root_full = root_page.is_full();
-                }
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
 
-                if is_root_full {
-                    let mut root_page = self.root_page.take().unwrap();
-                    let (k2, ptr2) = self.split_internal_page(&mut root_page, &k, ptr)?;
-                    let mut new_root_page = self.new_page(PageType::INTERNAL)?;
-                    new_root_page.set_item_count(1)?;
-                    new_root_page.set_ptr_at(0, root_page.index)?;
-                    new_root_page.set_key_at(0, &k2)?;
-                    new_root_page.set_ptr_at(1, ptr2)?;
+    pub fn is_read_only(&self) -> bool {
+        self.wal.is_none()
+    }
 
-                    let meta_page = self.meta_page.as_mut().unwrap();
-                    meta_page.set_root_index(new_root_page.index);
-                    self.root_page = Some(new_root_page);
-                } else {
-                    let root_page = self.root_page.as_mut().unwrap();
-                    root_page.insert_ptr(&k, ptr)?;
-                }
-            }
-            None => {
-                // root page is full, do split !!!
-                let mut root_page = self.root_page.take().unwrap();
-                assert!(root_page.is_full() && root_page.page_type == PageType::LEAF);
-                let (k, ptr) = self.split_leaf_page(&mut root_page, key, value)?;
-                let mut new_root_page = self.new_page(PageType::INTERNAL)?;
-                new_root_page.set_item_count(1)?;
-                new_root_page.set_ptr_at(0, root_page.index)?;
-                new_root_page.set_key_at(0, &k)?;
-                new_root_page.set_ptr_at(1, ptr)?;
+    // taken by every operation that writes, before it touches a page
+    fn lock_writer(&self) -> Result<MutexGuard<'_, ()>> {
+        if self.is_read_only() {
+            return Err(anyhow!("{} is opened read-only", self.path.display()));
+        }
+        Ok(self.writer.lock())
+    }
 
-                let meta_page = self.meta_page.as_mut().unwrap();
-                meta_page.set_root_index(new_root_page.index);
+    fn wal(&self) -> MutexGuard<'_, Wal> {
+        self.wal.as_ref().expect("writes are rejected by lock_writer").lock()
+    }
 
-                self.root_page = Some(new_root_page);
-            }
+    // must not be called while holding a page latch. The log goes first, a page only reaches
+    // the file once the record that changed it is durable
+    fn flush_pages(&self) -> Result<()> {
+        let pages = {
+            let pool = self.pool.lock();
+            pool.flush_log()?;
+            pool.pages()
+        };
+        for page in pages {
+            page.write().sync()?;
         }
-        self.sync()?;
         Ok(())
     }
 
-    pub fn get(&mut self, key: &K) -> Option<V> {
-        let mut p = self.root_page.as_ref().unwrap();
-        let mut pages = Vec::new();
-        loop {
-            // println!("{:?} {}", p.page_type, p.item_count());
-            match p.find(key) {
-                Some((i, pos)) => {
-                    match p.page_type {
-                        PageType::LEAF => {
-                            // println!("i: {}, pos: {:?}", i, pos);
-                            // println!("{:?}", p);
-                            return if pos == Pos::Current {
-                                p.value_at(i)
-                            } else {
-                                None
-                            };
-                        }
-                        PageType::INTERNAL => match pos {
-                            Pos::Left => {
-                                pages.push(
-                                    Page::<K, V>::load(self.fd.clone(), p.ptr_at(i).unwrap())
-                                        .unwrap(),
-                                );
-                                p = &pages[pages.len() - 1];
-                            }
-                            _ => {
-                                pages.push(
-                                    Page::<K, V>::load(self.fd.clone(), p.ptr_at(i + 1).unwrap())
-                                        .unwrap(),
-                                );
-                                p = &pages[pages.len() - 1];
-                            }
-                        },
-                        _ => {
-                            // impossible
-                            return None;
-                        }
-                    }
-                }
-                None => {
-                    return None;
-                }
-            }
+    // pages first, so the meta page never points at a root that is not on disk yet
+    fn sync(&self) -> Result<()> {
+        self.flush_pages()?;
+        self.meta_page.lock().sync()
+    }
+
+    /// Writes every dirty page back to the file, fsyncs it and truncates the write-ahead log.
+    /// Dirty pages otherwise stay in the buffer pool until they are evicted, the log grows past
+    /// `CHECKPOINT_WAL_SIZE` or the tree is dropped.
+    pub fn checkpoint(&self) -> Result<()> {
+        let _writer = self.lock_writer()?;
+        let mut wal = self.wal();
+        self.sync()?;
+        self.save_bloom()?;
+        wal.checkpoint()
+    }
+
+    fn save_bloom(&self) -> Result<()> {
+        match self.bloom.write().as_mut() {
+            Some(filter) => filter.save(false),
+            None => Ok(()),
         }
     }
 
-    fn new_page(&mut self, pt: PageType) -> Result<Page<K, V>> {
-        let meta_page = self.meta_page.as_mut().unwrap();
-        let max_index = meta_page.total_pages();
-        meta_page.set_total_page(max_index + 1);
-        Ok(Page::<K, V>::new(self.fd.clone(), max_index, pt)?)
+    // commits the running operation if it succeeded and rolls it back if it failed, called with
+    // the writer lock held after every page latch is released
+    fn finish<T>(&self, result: Result<T>) -> Result<T> {
+        match result {
+            Ok(value) => self.commit().map(|_| value),
+            Err(err) => {
+                self.rollback(std::mem::replace(&mut *self.txn.lock(), Txn::new()));
+                Err(err)
+            }
+        }
     }
 
-    fn split_leaf_page(&mut self, p: &mut Page<K, V>, key: &K, value: &V) -> Result<(K, u32)> {
-        assert_eq!(p.page_type, PageType::LEAF);
-        let mut new_page = self.new_page(PageType::LEAF)?;
-        let mut keys = Vec::new();
-        let mut values = Vec::new();
-        let mut inserted = false;
-        for i in 0..p.item_count() {
-            let k = p.key_at(i).unwrap();
-            if !inserted && k > *key {
-                keys.push(key.clone());
-                values.push(value.clone());
-                inserted = true;
+    // logs the pages the operation dirtied and lets them go. Nothing of an operation that could
+    // not be logged stays behind
+    fn commit(&self) -> Result<()> {
+        let txn = std::mem::replace(&mut *self.txn.lock(), Txn::new());
+        // a filter holding more keys than it was sized for is rebuilt for twice the keys
+        let overfull = self.bloom.read().as_ref().filter(|filter| filter.is_overfull()).map(|f| f.fp_rate());
+        if let Some(fp_rate) = overfull {
+            if let Err(err) = self.build_bloom(fp_rate).and_then(|filter| self.install_bloom(filter)) {
+                self.rollback(txn);
+                return Err(err);
+            }
+        }
+        let mut images = BTreeMap::new();
+        for page in &txn.pages {
+            let page = page.read();
+            if page.is_dirty() {
+                images.entry(page.index).or_insert_with(|| Box::new(page.image()));
             }
-            keys.push(k);
-            values.push(p.value_at(i).unwrap())
         }
-        if !inserted {
-            keys.push(key.clone());
-            values.push(value.clone());
-            // inserted = true;
+        if txn.meta {
+            let meta_page = self.meta_page.lock();
+            images.insert(meta_page.index, Box::new(meta_page.image()));
         }
-        let cut_i = (keys.len() + 1) / 2;
-        p.set_item_count(cut_i)?;
-        new_page.set_item_count(keys.len() - cut_i)?;
-
-        for i in 0..cut_i {
-            p.set_key_at(i, &keys[i])?;
-            p.set_value_at(i, &values[i])?;
+        if images.is_empty() {
+            return Ok(());
+        }
+        let images: Vec<(u32, &[u8; PAGE_SIZE])> = images.iter().map(|(index, image)| (*index, &**image)).collect();
+        let mut wal = self.wal();
+        if let Err(err) = wal.append(&images) {
+            drop(wal);
+            self.rollback(txn);
+            return Err(err);
+        }
+        if !txn.retired.is_empty() {
+            let mut snapshots = self.snapshots.lock();
+            let epoch = snapshots.epoch;
+            snapshots.retired.extend(txn.retired.iter().map(|&(index, born)| (index, born, epoch)));
         }
+        drop(txn);
+        // the operation is durable once it is logged. A checkpoint that fails leaves the log as
+        // it is and is tried again after the next operation
+        if wal.len() >= CHECKPOINT_WAL_SIZE {
+            let result = self.sync().and_then(|_| self.save_bloom()).and_then(|_| wal.checkpoint());
+            if let Err(err) = result {
+                eprintln!("could not checkpoint the write-ahead log: {:#}", err);
+            }
+        }
+        Ok(())
+    }
 
-        for i in cut_i..keys.len() {
-            new_page.set_key_at(i - cut_i, &keys[i])?;
-            new_page.set_value_at(i - cut_i, &values[i])?;
+    // puts the pages and META back the way they were before the operation and drops the pages
+    // it added, so none of its changes are logged or written back
+    fn rollback(&self, txn: Txn<K, V>) {
+        let Txn { pages, mut undo, meta_undo, .. } = txn;
+        let mut added = Vec::new();
+        for page in &pages {
+            let mut page = page.write();
+            match undo.remove(&page.index) {
+                Some(Before::Page(copy, dirty)) => page.restore(&copy, dirty),
+                Some(Before::New) => {
+                    page.discard();
+                    added.push(page.index);
+                }
+                None => {}
+            }
+        }
+        let mut pool = self.pool.lock();
+        for index in added {
+            pool.remove(index);
+        }
+        drop(pool);
+        if let Some((copy, dirty)) = meta_undo {
+            self.meta_page.lock().restore(&copy, dirty);
         }
+    }
 
-        Ok((keys[cut_i].clone(), new_page.index))
+    pub fn pool_stats(&self) -> PoolStats {
+        self.pool.lock().stats()
     }
 
-    fn split_internal_page(&mut self, p: &mut Page<K, V>, key: &K, ptr: u32) -> Result<(K, u32)> {
-        assert_eq!(p.page_type, PageType::INTERNAL);
-        let mut new_page = self.new_page(PageType::INTERNAL)?;
-        let mut keys = Vec::new();
-        let mut ptrs = Vec::new();
-        let mut inserted = false;
-        ptrs.push(p.ptr_at(0).unwrap());
-        for i in 0..p.item_count() {
-            let k = p.key_at(i).unwrap();
-            if !inserted && k > *key {
-                keys.push(key.clone());
-                ptrs.push(ptr);
-                inserted = true;
-            }
-            keys.push(k);
-            ptrs.push(p.ptr_at(i + 1).unwrap());
+    /// Runs f on page index as the tree has it now, META included, for inspecting the file.
+    pub(crate) fn with_page<T>(&self, index: u32, f: impl FnOnce(&Page<K, V>) -> T) -> Result<T> {
+        if index == 0 {
+            return Ok(f(&self.meta_page.lock()));
+        }
+        let total_pages = self.meta_page.lock().total_pages();
+        if index >= total_pages {
+            return Err(anyhow!("page {} does not exist, the file has {} pages", index, total_pages));
         }
+        let page = self.fetch(index)?;
+        let page = page.read();
+        Ok(f(&page))
+    }
 
-        if !inserted {
-            keys.push(key.clone());
-            ptrs.push(ptr);
-            // inserted = true;
+    /// Counts pages, keys and the space they take on each level, from the root down to the
+    /// leaves. Writers wait until it is done.
+    pub fn levels(&self) -> Result<Vec<LevelStats>> {
+        let _writer = self.writer.lock();
+        let mut levels = Vec::new();
+        let mut level = vec![self.meta_page.lock().root_index()];
+        while !level.is_empty() {
+            let mut stats = LevelStats { level: levels.len(), ..LevelStats::default() };
+            let mut children = Vec::new();
+            for index in level {
+                let page = self.fetch(index)?;
+                let page = page.read();
+                stats.pages += 1;
+                stats.keys += page.item_count();
+                stats.used_bytes += page.used_space();
+                stats.capacity_bytes += Page::<K, V>::capacity();
+                if page.page_type == PageType::INTERNAL {
+                    children.extend((0..=page.item_count()).filter_map(|slot| page.ptr_at(slot)));
+                }
+            }
+            levels.push(stats);
+            level = children;
         }
+        Ok(levels)
+    }
 
-        let up_i = (keys.len() - 1) / 2;
-        p.set_item_count(up_i)?;
-        new_page.set_item_count(keys.len() - up_i - 1)?;
+    /// Height, page counts and fill factor per level of the tree, its key count and the size of
+    /// the file. The key count is kept in META, the rest walks every internal and leaf page
+    /// like `levels`.
+    pub fn stats(&self) -> Result<TreeStats> {
+        let levels = self.levels()?;
+        let (keys, total_pages) = {
+            let meta_page = self.meta_page.lock();
+            (meta_page.key_count(), meta_page.total_pages())
+        };
+        Ok(TreeStats {
+            height: levels.len(),
+            internal_pages: levels[..levels.len() - 1].iter().map(|level| level.pages).sum(),
+            leaf_pages: levels.last().map_or(0, |level| level.pages),
+            keys,
+            total_pages,
+            file_bytes: self.store.len()?,
+            levels,
+        })
+    }
 
-        for i in 0..up_i {
-            p.set_key_at(i, &keys[i])?;
-            p.set_ptr_at(i + 1, ptrs[i + 1])?;
+    /// Walks every page reachable from the root in META and checks that the tree is well formed:
+    /// keys are sorted within and across pages and fall between the separators above them, all
+    /// leaves are at the same depth and chained in order, no page is reachable twice, every
+    /// page is either reachable or on the free list, and META counts as many pages as the file
+    /// holds. Pages that cannot be read are reported like any other problem.
+    ///
+    /// Writers wait until it is done. A tree opened for writing has its dirty pages written
+    /// back first, so that the file matches the tree.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let _writer = self.writer.lock();
+        if !self.is_read_only() {
+            self.sync()?;
+        }
+        let (total_pages, key_count, retired_pages) = {
+            let meta_page = self.meta_page.lock();
+            (meta_page.total_pages(), meta_page.key_count(), meta_page.retired_pages())
+        };
+        let file_len = self.store.len()?;
+        let mut verifier = self.walk_pages()?;
+        verifier.report.file_pages = file_len / PAGE_SIZE as u64;
+        if file_len != total_pages as u64 * PAGE_SIZE as u64 {
+            verifier.problem(0, format!("META counts {} pages but the file is {} bytes", total_pages, file_len));
         }
+        verifier.check_orphans(retired_pages);
+        if verifier.report.keys as u64 != key_count {
+            verifier.problem(0, format!("META counts {} keys but the leaves hold {}", key_count, verifier.report.keys));
+        }
+        Ok(verifier.report)
+    }
+
+    // walks the tree and the free list META links to, called with the writer lock held
+    fn walk_pages(&self) -> Result<Verifier<'_, K, V>> {
+        let (root_index, total_pages, free, next_free_list) = {
+            let meta_page = self.meta_page.lock();
+            (meta_page.root_index(), meta_page.total_pages(), meta_page.free_list(), meta_page.next_free_list())
+        };
+        let mut verifier = Verifier {
+            btree: self,
+            seen: vec![false; total_pages as usize],
+            leaf_depth: None,
+            leaves: Vec::new(),
+            report: VerifyReport { total_pages, ..VerifyReport::default() },
+        };
+        if let Some(seen) = verifier.seen.first_mut() {
+            *seen = true;
+        }
+        if verifier.visit(root_index, 0) {
+            verifier.walk(root_index, 1, None, None);
+        }
+        verifier.check_leaf_chain();
+        verifier.walk_free_list(free, next_free_list);
+        Ok(verifier)
+    }
+
+    // frees the pages retired for the snapshots that were live when the last process using the
+    // tree stopped. Only their number is kept, they are the pages neither in the tree nor on the
+    // free list, so the whole tree is walked. A tree with problems is left as it is
+    fn reclaim_retired(&self) -> Result<()> {
+        let retired_pages = self.meta_page.lock().retired_pages();
+        if retired_pages == 0 || self.is_read_only() {
+            return Ok(());
+        }
+        let _writer = self.writer.lock();
+        let verifier = self.walk_pages()?;
+        if !verifier.report.is_ok() {
+            eprintln!("{} pages retired for snapshots are left as they are, the tree needs verifying", retired_pages);
+            return Ok(());
+        }
+        let orphans = verifier.orphans();
+        let result = orphans.iter().try_for_each(|&index| {
+            self.free_page(&mut *self.fetch_mut(index)?);
+            Ok(())
+        });
+        self.finish(result.map(|()| self.meta_mut().set_retired_pages(0)))?;
+        eprintln!("freed {} pages retired for snapshots", orphans.len());
+        Ok(())
+    }
+
+    fn fetch(&self, index: u32) -> Result<PageRef<K, V>> {
+        self.pool.lock().fetch(index)
+    }
+
+    fn root(&self) -> Result<PageRef<K, V>> {
+        let root_index = self.meta_page.lock().root_index();
+        self.fetch(root_index)
+    }
+
+    // latches a page the running operation is about to change, keeping it pinned until commit.
+    // A page a live snapshot reads has to be copied by `unshare_path` first
+    fn fetch_mut(&self, index: u32) -> Result<WriteLatch<K, V>> {
+        if self.snapshots.lock().is_shared(index) {
+            return Err(anyhow!("page {} is shared with a snapshot and cannot be changed", index));
+        }
+        self.latch_mut(index)
+    }
+
+    // like fetch_mut, also for a page a snapshot shares: only to change the links between
+    // leaves, which snapshots do not follow, or to free a page no snapshot reads any more
+    fn latch_mut(&self, index: u32) -> Result<WriteLatch<K, V>> {
+        let page = self.fetch(index)?;
+        self.track(&page);
+        Ok(page.write_arc())
+    }
+
+    // called before the running operation latches page to change it: pins it until commit and
+    // keeps a copy to roll back to
+    fn track(&self, page: &PageRef<K, V>) {
+        self.save_undo(page);
+        self.txn.lock().pages.push(page.clone());
+    }
+
+    // copies page as it is before the running operation first changes it
+    fn save_undo(&self, page: &PageRef<K, V>) {
+        let page = page.read();
+        self.txn
+            .lock()
+            .undo
+            .entry(page.index)
+            .or_insert_with(|| Before::Page(Box::new(page.copy()), page.is_dirty()));
+    }
+
+    // the META page for the running operation to change
+    fn meta_mut(&self) -> MutexGuard<'_, Page<K, V>> {
+        let mut txn = self.txn.lock();
+        let meta_page = self.meta_page.lock();
+        if !txn.meta {
+            txn.meta = true;
+            txn.meta_undo = Some((meta_page.copy(), meta_page.is_dirty()));
+        }
+        meta_page
+    }
+
+    // a key that was not in the tree before the running operation
+    fn key_added(&self, key: &K) -> Result<()> {
+        self.add_keys(1);
+        if self.bloom.read().is_some() {
+            self.bloom_insert(&Page::<K, V>::encode_key(key)?);
+        }
+        Ok(())
+    }
+
+    // every new or removed key changes the count in META, which is logged with the operation
+    fn add_keys(&self, delta: i64) {
+        let mut meta_page = self.meta_mut();
+        let key_count = meta_page.key_count().wrapping_add_signed(delta);
+        meta_page.set_key_count(key_count);
+    }
+
+    // latches the root page for reading; the root latch is let go once the root page is held
+    fn read_root(&self) -> Result<ReadLatch<K, V>> {
+        let _root_latch = self.root_latch.read();
+        Ok(self.root()?.read_arc())
+    }
+
+    // an SMO (structure modification) moves items between pages, so a cursor's saved
+    // position may no longer be valid
+    fn begin_smo(&self) {
+        self.version.fetch_add(1, Ordering::AcqRel);
+    }
+
+    fn init_as_empty(store: &StoreRef) -> Result<Page<K, V>> {
+        eprintln!("init empty btree");
+        let mut meta_page = Page::<K, V>::new(store.clone(), 0, PageType::META)?;
+        meta_page.set_total_page(2);
+        meta_page.set_root_index(1);
+        let mut root_page = Page::<K, V>::new(store.clone(), 1, PageType::LEAF)?;
+        root_page.sync()?;
+        meta_page.sync()?;
+        store.sync()?;
+        Ok(meta_page)
+    }
+
+    // fails if the file is not a btree file or was created for a different page or K, V layout
+    fn init_load(store: &StoreRef) -> Result<Page<K, V>> {
+        let meta_page = Page::<K, V>::load_meta(store.clone())?;
+
+        let root_page = Page::<K, V>::load(store.clone(), meta_page.root_index())?;
+        eprintln!(
+            "root page index: {}; total pages:{}; root page keys: {};",
+            meta_page.root_index(),
+            meta_page.total_pages(),
+            root_page.item_count()
+        );
+        Ok(meta_page)
+    }
+
+    pub fn set(&self, key: &K, value: &V) -> Result<()> {
+        let _writer = self.lock_writer()?;
+        let result = self.set_locked(key, value).and_then(|old| {
+            if old.is_none() {
+                self.key_added(key)?;
+            }
+            self.free_value(old)
+        });
+        self.finish(result)
+    }
+
+    // returns the value that was replaced, its overflow pages are freed once every latch is let go
+    fn set_locked(&self, key: &K, value: &V) -> Result<Option<Value<V>>> {
+        let record = self.leaf_record(key, value)?;
+        self.set_record_locked(key, &record)
+    }
+
+    fn leaf_record(&self, key: &K, value: &V) -> Result<Vec<u8>> {
+        let mut record = Page::<K, V>::encode_key(key)?;
+        record.extend(self.value_cell(value, true)?);
+        Ok(record)
+    }
+
+    fn set_record_locked(&self, key: &K, record: &[u8]) -> Result<Option<Value<V>>> {
+        self.unshare_path(key, false)?;
+        let mut root_latch = Some(self.root_latch.write());
+        // pages[i + 1] is the child of pages[i] on the way to key. pages[0] is the root while
+        // root_latch is held, otherwise the lowest page that has room for one more item
+        let root_index = self.meta_page.lock().root_index();
+        let mut pages = vec![self.fetch_mut(root_index)?];
+        if !pages[0].is_full() {
+            root_latch = None;
+        }
+        loop {
+            let child_page_index = {
+                let p = &pages[pages.len() - 1];
+                match p.page_type {
+                    PageType::INTERNAL => p.ptr_at(p.child_slot(key)).unwrap(),
+                    PageType::LEAF => break,
+                    _ => {
+                        panic!("impossible a meta page")
+                    }
+                }
+            };
+            let child = self.fetch_mut(child_page_index)?;
+            // a split below stops at this child, the pages above are left alone
+            if !child.is_full() {
+                pages.clear();
+                root_latch = None;
+            }
+            pages.push(child);
+        }
+
+        let old = {
+            let leaf = pages.last().unwrap();
+            match leaf.find(key) {
+                Some((i, Pos::Current)) => leaf.value_at(i),
+                _ => None,
+            }
+        };
+        match pages.last_mut().unwrap().insert(key, record) {
+            Ok(_) => {
+                // inserted, done!
+                return Ok(old);
+            }
+            Err(err) => {
+                if !is_full_error(&err) {
+                    return Err(err);
+                }
+                // eh..., the page is full, we need to split it
+            }
+        }
+
+        // page is full, split it!
+        self.begin_smo();
+        let mut kp = None;
+        for p in pages.iter_mut().rev() {
+            match p.page_type {
+                PageType::LEAF => {
+                    // leaf page must be full in this case
+                    kp = Some(self.split_leaf_page(p, key, record)?);
+                }
+                PageType::INTERNAL => {
+                    let (k, ptr) = kp.take().unwrap();
+                    match p.insert_ptr(&k, ptr) {
+                        Ok(()) => return Ok(old),
+                        Err(err) if is_full_error(&err) => kp = Some(self.split_internal_page(p, &k, ptr)?),
+                        Err(err) => return Err(err),
+                    }
+                }
+                _ => {
+                    panic!("impossible a meta page")
+                }
+            }
+        }
+
+        // so root page was split too, a new root goes on top of both halves
+        assert!(root_latch.is_some());
+        let (k, ptr) = kp.unwrap();
+        let new_root_page = self.new_page(PageType::INTERNAL)?;
+        let new_root_index = {
+            let mut new_root_page = new_root_page.write();
+            new_root_page.set_ptr_at(0, pages[0].index)?;
+            new_root_page.insert_ptr(&k, ptr)?;
+            new_root_page.index
+        };
+        self.meta_mut().set_root_index(new_root_index);
+        Ok(old)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        if !self.may_contain(key) {
+            return Ok(None);
+        }
+        let mut p = self.read_root()?;
+        while p.page_type == PageType::INTERNAL {
+            let child_page_index = p.ptr_at(p.child_slot(key)).unwrap();
+            // the parent latch is released only once the child is held
+            p = self.fetch(child_page_index)?.read_arc();
+        }
+        match p.find(key) {
+            // overflow pages are read with the leaf still latched, so they cannot be freed meanwhile
+            Some((i, Pos::Current)) => Ok(Some(self.load_value(p.value_at(i).unwrap())?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Looks up every key and returns their values in the same order. The keys are looked up
+    /// in ascending order and a leaf stays latched while the next keys fall in its range, so
+    /// each leaf is read once however many of the keys it holds. The pages above it are let go
+    /// on the way down and a key past the leaf is looked up from the root again, so writers
+    /// only wait for the leaf being read.
+    pub fn get_many(&self, keys: &[K]) -> Result<Vec<Option<V>>> {
+        let mut values = vec![None; keys.len()];
+        let mut leaf: Option<(ReadLatch<K, V>, Option<K>)> = None;
+        for i in sorted_order(keys.len(), |i| &keys[i]) {
+            let key = &keys[i];
+            if !self.may_contain(key) {
+                continue;
+            }
+            // the leaf is let go before the descent, a writer may hold the pages above it
+            if matches!(&leaf, Some((_, Some(upper))) if key >= upper) {
+                leaf = None;
+            }
+            if leaf.is_none() {
+                leaf = Some(self.read_leaf(key)?);
+            }
+            let (page, _) = leaf.as_ref().unwrap();
+            if let Some((slot, Pos::Current)) = page.find(key) {
+                values[i] = Some(self.load_value(page.value_at(slot).unwrap())?);
+            }
+        }
+        Ok(values)
+    }
+
+    // latches the leaf key belongs to, with the key bounding its range from above. Each parent
+    // is let go once the child is held
+    fn read_leaf(&self, key: &K) -> Result<(ReadLatch<K, V>, Option<K>)> {
+        let mut p = self.read_root()?;
+        let mut upper = None;
+        while p.page_type == PageType::INTERNAL {
+            let (child, bound) = Self::child_with_bound(&p, key, &upper);
+            upper = bound;
+            p = self.fetch(child)?.read_arc();
+        }
+        Ok((p, upper))
+    }
+
+    /// Sets every pair as a single operation, committed to the log at once. The keys are set
+    /// in ascending order, sharing the path between neighbours, and a leaf is changed in place
+    /// unless it has to split. A key given twice keeps its last value. Stops
+    /// at the first error and rolls back the pairs set before it.
+    pub fn set_many(&self, items: &[(K, V)]) -> Result<()> {
+        let _writer = self.lock_writer()?;
+        let result = self.set_many_locked(items);
+        self.finish(result)
+    }
+
+    fn set_many_locked(&self, items: &[(K, V)]) -> Result<()> {
+        // the writer lock keeps the tree as it is, so the path is kept as page indexes and only
+        // the leaf is latched
+        let mut path: Vec<(u32, Option<K>)> = Vec::new();
+        let mut leaf: Option<WriteLatch<K, V>> = None;
+        let mut replaced = Vec::new();
+        for i in sorted_order(items.len(), |i| &items[i].0) {
+            let (key, value) = &items[i];
+            let record = self.leaf_record(key, value)?;
+            while matches!(path.last(), Some((_, Some(upper))) if key >= upper) {
+                path.pop();
+                leaf = None;
+            }
+            if leaf.is_none() {
+                self.unshare_path(key, false)?;
+                if path.is_empty() {
+                    path.push((self.meta_page.lock().root_index(), None));
+                }
+                loop {
+                    let (index, upper) = path.last().unwrap();
+                    let page = self.fetch(*index)?;
+                    let page = page.read();
+                    if page.page_type == PageType::LEAF {
+                        break;
+                    }
+                    let child = Self::child_with_bound(&page, key, upper);
+                    drop(page);
+                    path.push(child);
+                }
+                leaf = Some(self.fetch_mut(path.last().unwrap().0)?);
+            }
+            let page = leaf.as_mut().unwrap();
+            let old = match page.find(key) {
+                Some((slot, Pos::Current)) => page.value_at(slot),
+                _ => None,
+            };
+            match page.insert(key, &record) {
+                Ok(()) => {}
+                Err(err) if is_full_error(&err) => {
+                    // the split changes the pages on the path
+                    leaf = None;
+                    path.clear();
+                    self.set_record_locked(key, &record)?;
+                }
+                Err(err) => return Err(err),
+            }
+            if old.is_none() {
+                self.key_added(key)?;
+            }
+            replaced.push(old);
+        }
+        drop(leaf);
+        for old in replaced {
+            self.free_value(old)?;
+        }
+        Ok(())
+    }
+
+    // the child of an internal page that key belongs to, and the separator bounding the child's
+    // range from above; the rightmost child shares the bound of the page, upper
+    fn child_with_bound(page: &Page<K, V>, key: &K, upper: &Option<K>) -> (u32, Option<K>) {
+        let slot = page.child_slot(key);
+        let bound = if slot < page.item_count() { page.key_at(slot) } else { upper.clone() };
+        (page.ptr_at(slot).unwrap(), bound)
+    }
+
+    /// Passes the value under `key`, if any, to `f` and stores what `f` returns in its place,
+    /// removing the key if `f` returns None. No other write can come in between. The leaf is
+    /// changed in place unless it has to split or underflows, and the new value is returned.
+    pub fn update<F>(&self, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        let _writer = self.lock_writer()?;
+        let result = self.update_locked(key, f);
+        self.finish(result)
+    }
+
+    fn update_locked<F>(&self, key: &K, f: F) -> Result<Option<V>>
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        // the writer lock keeps the tree as it is, only the leaf needs a write latch
+        self.unshare_path(key, false)?;
+        let index = self.seek_leaf(Some(key), false)?.read().index;
+        let mut leaf = self.fetch_mut(index)?;
+        let (slot, old_cell) = match leaf.find(key) {
+            Some((i, Pos::Current)) => (Some(i), leaf.value_at(i)),
+            _ => (None, None),
+        };
+        // freed once the record no longer points at them
+        let old_overflow = match old_cell {
+            Some(Value::Overflow { first_page, .. }) => Some(first_page),
+            _ => None,
+        };
+        let old = old_cell.map(|cell| self.load_value(cell)).transpose()?;
+        let new = f(old);
+        match (&new, slot) {
+            (Some(value), _) => {
+                let record = self.leaf_record(key, value)?;
+                match leaf.insert(key, &record) {
+                    Ok(()) => drop(leaf),
+                    Err(err) if is_full_error(&err) => {
+                        drop(leaf);
+                        self.set_record_locked(key, &record)?;
+                    }
+                    Err(err) => return Err(err),
+                }
+                if slot.is_none() {
+                    self.key_added(key)?;
+                }
+            }
+            (None, Some(i)) => {
+                let root_index = self.meta_page.lock().root_index();
+                let left = leaf.used_space() - record_space(leaf.record_at(i).len());
+                if leaf.index == root_index || left >= Page::<K, V>::capacity() / 2 {
+                    leaf.remove_at(i)?;
+                    drop(leaf);
+                } else {
+                    drop(leaf);
+                    self.remove_locked(key)?;
+                }
+                self.add_keys(-1);
+            }
+            (None, None) => return Ok(None),
+        }
+        if let Some(first_page) = old_overflow {
+            self.free_overflow(first_page)?;
+        }
+        Ok(new)
+    }
+
+    pub fn remove(&self, key: &K) -> Result<Option<V>> {
+        let _writer = self.lock_writer()?;
+        let result = self
+            .remove_locked(key)
+            .and_then(|value| value.map(|value| self.take_value(value)).transpose())
+            .inspect(|value| {
+                if value.is_some() {
+                    self.add_keys(-1);
+                }
+            });
+        self.finish(result)
+    }
+
+    // returns the value as its leaf stored it, its overflow pages are read and freed once every
+    // latch is let go
+    fn remove_locked(&self, key: &K) -> Result<Option<Value<V>>> {
+        self.unshare_path(key, true)?;
+        let mut root_latch = Some(self.root_latch.write());
+        // pages[i + 1] is the child at slots[i] of pages[i]. pages[0] is the root while
+        // root_latch is held, otherwise the lowest page that can lose an item without underflow
+        let root_index = self.meta_page.lock().root_index();
+        let mut pages = vec![self.fetch_mut(root_index)?];
+        let mut slots = Vec::new();
+        if pages[0].page_type == PageType::LEAF || pages[0].item_count() > 1 {
+            root_latch = None;
+        }
+        loop {
+            let child_page_index = {
+                let p = &pages[pages.len() - 1];
+                match p.page_type {
+                    PageType::INTERNAL => {
+                        let ptr_index = p.child_slot(key);
+                        slots.push(ptr_index);
+                        p.ptr_at(ptr_index).unwrap()
+                    }
+                    PageType::LEAF => break,
+                    _ => {
+                        panic!("impossible a meta page")
+                    }
+                }
+            };
+            let child = self.fetch_mut(child_page_index)?;
+            if child.can_lose_record() {
+                pages.clear();
+                slots.clear();
+                root_latch = None;
+            }
+            pages.push(child);
+        }
+
+        let value = {
+            let leaf = pages.last_mut().unwrap();
+            match leaf.find(key) {
+                Some((i, Pos::Current)) => {
+                    let value = leaf.value_at(i);
+                    leaf.remove_at(i)?;
+                    value
+                }
+                _ => return Ok(None),
+            }
+        };
+        if pages.len() == 1 {
+            // nothing underflowed
+            return Ok(value);
+        }
+
+        self.begin_smo();
+        self.rebalance(&mut pages, &slots)?;
+
+        // an internal root left without keys only has a single child, which becomes the new root
+        if root_latch.is_some() && pages[0].page_type == PageType::INTERNAL && pages[0].item_count() == 0 {
+            let new_root_index = pages[0].ptr_at(0).unwrap();
+            self.meta_mut().set_root_index(new_root_index);
+            self.free_page(&mut pages[0]);
+        }
+        Ok(value)
+    }
+
+    // walks up from the leaf, fixing every underflowed page by borrowing from or merging with
+    // one of its siblings
+    fn rebalance(&self, pages: &mut [WriteLatch<K, V>], slots: &[usize]) -> Result<()> {
+        for depth in (1..pages.len()).rev() {
+            let (upper, lower) = pages.split_at_mut(depth);
+            let parent = &mut upper[depth - 1];
+            let node = &mut lower[0];
+            if !node.is_underflow() {
+                break;
+            }
+            let slot = slots[depth - 1];
+            // prefer the left sibling, the leftmost child can only use its right one
+            let (sibling_slot, sep_i) = if slot > 0 {
+                (slot - 1, slot - 1)
+            } else {
+                (slot + 1, slot)
+            };
+            let mut sibling = self.fetch_mut(parent.ptr_at(sibling_slot).unwrap())?;
+            let merged = {
+                let (left, right) = if slot > 0 {
+                    (&mut *sibling, &mut **node)
+                } else {
+                    (&mut **node, &mut *sibling)
+                };
+                match left.page_type {
+                    PageType::LEAF => self.rebalance_leaf_pages(parent, sep_i, left, right)?,
+                    _ => self.rebalance_internal_pages(parent, sep_i, left, right)?,
+                }
+            };
+            if !merged {
+                // borrowed from the sibling, the parent keeps its item count
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    // returns true if right was merged into left and removed from parent
+    fn rebalance_leaf_pages(
+        &self,
+        parent: &mut Page<K, V>,
+        sep_i: usize,
+        left: &mut Page<K, V>,
+        right: &mut Page<K, V>,
+    ) -> Result<bool> {
+        let mut records = Vec::new();
+        for p in [&*left, &*right] {
+            for i in 0..p.item_count() {
+                records.push(p.record_at(i).to_vec());
+            }
+        }
+        let sizes = record_sizes(&records);
+
+        // the sibling has nothing to lend, so both pages fit in one
+        if sizes.iter().sum::<usize>() <= Page::<K, V>::capacity() {
+            left.set_records(&records)?;
+            parent.remove_ptr_at(sep_i)?;
+
+            let next = right.next_leaf();
+            if let Some(next) = next {
+                // the latch is let go right away, the next leaf may belong to a subtree a
+                // reader is crabbing through
+                self.latch_mut(next)?.set_prev_leaf(Some(left.index));
+            }
+            left.set_next_leaf(next);
+            self.free_page(right);
+            return Ok(true);
+        }
+
+        let cut_i = split_point(&sizes);
+        let key_len = K::decode(&records[cut_i])?.1;
+        // a longer separator may not fit in the parent, the page is then left underfull
+        if let Err(err) = parent.set_key_at(sep_i, &records[cut_i][..key_len]) {
+            return if is_full_error(&err) { Ok(false) } else { Err(err) };
+        }
+        left.set_records(&records[..cut_i])?;
+        right.set_records(&records[cut_i..])?;
+        Ok(false)
+    }
+
+    // returns true if right was merged into left and removed from parent
+    fn rebalance_internal_pages(
+        &self,
+        parent: &mut Page<K, V>,
+        sep_i: usize,
+        left: &mut Page<K, V>,
+        right: &mut Page<K, V>,
+    ) -> Result<bool> {
+        // the separator comes down from the parent between the two halves, with the first child
+        // of right as its pointer
+        let mut records = Vec::new();
+        for i in 0..left.item_count() {
+            records.push(left.record_at(i).to_vec());
+        }
+        let (sep, _) = Page::<K, V>::internal_record_parts(parent.record_at(sep_i));
+        records.push(Page::<K, V>::internal_record(sep, right.ptr_at(0).unwrap()));
+        for i in 0..right.item_count() {
+            records.push(right.record_at(i).to_vec());
+        }
+        let sizes = record_sizes(&records);
+
+        if sizes.iter().sum::<usize>() <= Page::<K, V>::capacity() {
+            left.set_records(&records)?;
+            parent.remove_ptr_at(sep_i)?;
+            self.free_page(right);
+            return Ok(true);
+        }
+
+        // the key of record up_i goes up to the parent and its pointer becomes the first child
+        // of right
+        let up_i = split_point(&sizes).clamp(1, records.len() - 2);
+        let (up_key, up_ptr) = Page::<K, V>::internal_record_parts(&records[up_i]);
+        if let Err(err) = parent.set_key_at(sep_i, up_key) {
+            return if is_full_error(&err) { Ok(false) } else { Err(err) };
+        }
+        left.set_records(&records[..up_i])?;
+        right.set_ptr_at(0, up_ptr)?;
+        right.set_records(&records[up_i + 1..])?;
+        Ok(false)
+    }
+
+    /// Builds the tree bottom-up from pairs in strictly ascending key order. The tree must be
+    /// empty. Pages are packed to `fill_factor` of their capacity in bytes, see
+    /// `check_fill_factor`, and each page is written once; the META page only points at the new
+    /// root at the end, so a failed load leaves the tree empty. Returns the number of pairs
+    /// loaded.
+    pub fn bulk_load<I>(&self, items: I, fill_factor: f64) -> Result<usize>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        Self::check_fill_factor(fill_factor)?;
+        let _writer = self.lock_writer()?;
+        if !self.snapshots.lock().live.is_empty() {
+            return Err(anyhow!("bulk load cannot run while a snapshot is live"));
+        }
+        {
+            let root_page = self.read_root()?;
+            if root_page.page_type != PageType::LEAF || root_page.item_count() != 0 {
+                return Err(anyhow!("bulk load needs an empty btree"));
+            }
+        }
+        // the new pages skip the log and may reuse free pages, replaying an older image of one
+        // of them after a crash would overwrite it
+        let mut wal = self.wal();
+        self.sync()?;
+        wal.checkpoint()?;
+        drop(wal);
+        let (level, count) = match self.build_levels(items, fill_factor) {
+            Ok(built) => built,
+            Err(err) => {
+                self.free_unused_pages()?;
+                return Err(err);
+            }
+        };
+        if count == 0 {
+            return Ok(0);
+        }
+
+        // nothing points at the new pages yet, so instead of going through the log they are
+        // written out before the META page that links them in is committed
+        self.flush_pages()?;
+        self.store.sync()?;
+        {
+            let _root_latch = self.root_latch.write();
+            self.begin_smo();
+            let old_root_index = self.meta_page.lock().root_index();
+            let mut meta_page = self.meta_mut();
+            meta_page.set_root_index(level[0].1);
+            meta_page.set_key_count(count as u64);
+            drop(meta_page);
+            self.free_page(&mut *self.fetch_mut(old_root_index)?);
+        }
+        self.commit()?;
+        Ok(count)
+    }
+
+    /// A fill factor must be in [0.5, 1]: a page packed to less than half of its capacity would
+    /// already be below the underflow threshold used by `remove`.
+    pub fn check_fill_factor(fill_factor: f64) -> Result<()> {
+        if !(0.5..=1.0).contains(&fill_factor) {
+            return Err(anyhow!("fill factor must be in [0.5, 1], got {}", fill_factor));
+        }
+        Ok(())
+    }
+
+    // writes the leaves and internal pages of a bulk load, returning the top level and the
+    // number of pairs
+    fn build_levels<I>(&self, items: I, fill_factor: f64) -> Result<(Level, usize)>
+    where
+        I: IntoIterator<Item = (K, V)>,
+    {
+        let max = Page::<K, V>::capacity();
+        let per = ((max as f64 * fill_factor).ceil() as usize).min(max);
+
+        // first key and page index of every page on the level built last
+        let mut level = Vec::new();
+        // a leaf is only written once the next one has started filling, so the last two can
+        // still be evened out
+        let mut pending = Vec::new();
+        let mut filling = Vec::new();
+        let mut filling_size = 0;
+        let mut last_key: Option<K> = None;
+        let mut count = 0;
+        for (key, value) in items {
+            if let Some(last_key) = &last_key {
+                if key <= *last_key {
+                    return Err(anyhow!("bulk load input is not sorted: {:?} after {:?}", key, last_key));
+                }
+            }
+            let mut record = Page::<K, V>::encode_key(&key)?;
+            self.bloom_insert(&record);
+            record.extend(self.value_cell(&value, false)?);
+            let size = record_space(record.len());
+            if !filling.is_empty() && filling_size + size > per {
+                if !pending.is_empty() {
+                    self.write_leaf_page(&pending, &mut level)?;
+                }
+                pending = std::mem::take(&mut filling);
+                filling_size = 0;
+            }
+            filling.push(record);
+            filling_size += size;
+            last_key = Some(key);
+            count += 1;
+        }
+        if count == 0 {
+            return Ok((level, 0));
+        }
+
+        pending.append(&mut filling);
+        let mut start = 0;
+        for n in chunk_sizes(&record_sizes(&pending), per, max / 2, max) {
+            self.write_leaf_page(&pending[start..start + n], &mut level)?;
+            start += n;
+        }
+
+        while level.len() > 1 {
+            let sizes: Vec<usize> = level
+                .iter()
+                .map(|(key, _)| internal_record_space(key.len()))
+                .collect();
+            let mut children = level.into_iter();
+            level = Vec::new();
+            for n in chunk_sizes(&sizes, per, max / 2, max) {
+                let new_page = self.allocate_page(PageType::INTERNAL, false)?;
+                let mut new_page = new_page.write();
+                // the first child only takes a pointer, its key goes up a level
+                let (first_key, first_ptr) = children.next().unwrap();
+                new_page.set_ptr_at(0, first_ptr)?;
+                let records: Vec<Vec<u8>> = children
+                    .by_ref()
+                    .take(n - 1)
+                    .map(|(key, ptr)| Page::<K, V>::internal_record(&key, ptr))
+                    .collect();
+                new_page.set_records(&records)?;
+                level.push((first_key, new_page.index));
+            }
+        }
+        Ok((level, count))
+    }
+
+    // a bulk load that failed part way has allocated pages but not linked any of them in. The
+    // tree is still empty, so every page other than META and the root goes back on the free
+    // list and the pages the load took are not left orphaned
+    fn free_unused_pages(&self) -> Result<()> {
+        let (root_index, total_pages) = {
+            let meta_page = self.meta_page.lock();
+            (meta_page.root_index(), meta_page.total_pages())
+        };
+        self.meta_mut().set_free_list(&[], None);
+        for index in (1..total_pages).filter(|&index| index != root_index) {
+            self.free_page(&mut self.fetch(index)?.write());
+        }
+        // like the pages of a finished load the free-list pages are written before META
+        self.flush_pages()?;
+        self.store.sync()?;
+        self.commit()
+    }
+
+    /// Rewrites the tree at `path` into a new file, with its pages packed to `fill_factor` in
+    /// key order by `bulk_load`, and renames the new file over the old one. A crash leaves
+    /// either the old tree or the new one at `path`. The write-ahead log of the old tree is
+    /// replayed first. A Bloom filter is rebuilt along with it, without the keys removed since
+    /// it was last built.
+    ///
+    /// This is an offline operation, nothing else may have the file open while it runs.
+    pub fn compact(path: impl AsRef<Path>, fill_factor: f64) -> Result<CompactReport> {
+        Self::check_fill_factor(fill_factor)?;
+        let path = path.as_ref();
+        let mut new_path = path.as_os_str().to_owned();
+        new_path.push(".compact");
+        let new_path = PathBuf::from(new_path);
+        let cleanup = || -> std::io::Result<()> {
+            remove_if_exists(&new_path)?;
+            remove_if_exists(&Wal::path_for(&new_path))?;
+            remove_if_exists(&BloomFilter::path_for(&new_path))
+        };
+        // left behind by a compaction that did not finish
+        cleanup()?;
+
+        let old = Self::open(path, Options::default().create(false))?;
+        old.checkpoint()?;
+        let bloom_fp_rate = old.bloom_fp_rate();
+        let report = Self::copy_compacted(&old, &new_path, fill_factor, bloom_fp_rate);
+        drop(old);
+        let report = match report {
+            Ok(report) => report,
+            Err(err) => {
+                cleanup()?;
+                return Err(err);
+            }
+        };
+        std::fs::rename(&new_path, path)?;
+        // the old filter is still right for the new file, it has the same keys
+        if bloom_fp_rate.is_some() {
+            std::fs::rename(BloomFilter::path_for(&new_path), BloomFilter::path_for(path))?;
+        }
+        cleanup()?;
+        // the rename is only durable once the directory holding both names is synced
+        sync_parent_dir(path)?;
+        Ok(report)
+    }
+
+    /// Copies the tree as it is at one point in time to a new file at `path`, while the tree
+    /// stays open. Readers carry on; writers wait while the pages committed so far are written
+    /// back and the file is copied, about as long as reading the file once takes. The copy is
+    /// fsynced and checked with `verify`, whose report is returned, and removed again if it
+    /// has problems. The Bloom filter is not copied, open the copy with
+    /// `Options::bloom_filter` to build one.
+    pub fn backup_to(&self, path: impl AsRef<Path>) -> Result<VerifyReport> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(path)
+            .map_err(|err| anyhow!("could not create {}: {}", path.display(), err))?;
+        let report = self.copy_to(file).and_then(|()| {
+            sync_parent_dir(path)?;
+            Self::open(path, Options::default().read_only(true))?.verify()
+        });
+        match report {
+            Ok(report) if report.is_ok() => Ok(report),
+            Ok(report) => {
+                remove_if_exists(path)?;
+                Err(anyhow!("the copy at {} is damaged:\n{}", path.display(), report))
+            }
+            Err(err) => {
+                remove_if_exists(path)?;
+                Err(err)
+            }
+        }
+    }
+
+    fn copy_to(&self, file: File) -> Result<()> {
+        let mut out = std::io::BufWriter::new(file);
+        {
+            // with every committed page in the file and no writer, nothing changes the file,
+            // a page evicted by a reader is clean
+            let _writer = self.writer.lock();
+            if !self.is_read_only() {
+                self.sync()?;
+            }
+            let total_pages = self.meta_page.lock().total_pages();
+            let mut buf = [0; PAGE_SIZE];
+            for index in 0..total_pages {
+                self.store.read_page(index, &mut buf)?;
+                out.write_all(&buf)?;
+            }
+        }
+        out.into_inner().map_err(|err| err.into_error())?.sync_all()?;
+        Ok(())
+    }
+
+    fn copy_compacted(
+        old: &Self,
+        new_path: &Path,
+        fill_factor: f64,
+        bloom_fp_rate: Option<f64>,
+    ) -> Result<CompactReport> {
+        let mut options = Options::default().create_new(true).sync(SyncMode::Off);
+        // removed keys leave bits behind, the new filter starts without them
+        options.bloom_fp_rate = bloom_fp_rate;
+        let new = Self::open(new_path, options)?;
+        let mut error = None;
+        let items = old.iter()?.map_while(|item| item.map_err(|err| error = Some(err)).ok());
+        let keys = new.bulk_load(items, fill_factor)?;
+        if let Some(err) = error {
+            return Err(err);
+        }
+        // bulk_load wrote the pages, the checkpoint makes META durable too
+        new.checkpoint()?;
+        let file_len = |btree: &Self| btree.store.len();
+        let report = CompactReport {
+            keys,
+            old_pages: old.meta_page.lock().total_pages(),
+            new_pages: new.meta_page.lock().total_pages(),
+            old_bytes: file_len(old)?,
+            new_bytes: file_len(&new)?,
+        };
+        Ok(report)
+    }
+
+    // free pages are reused, so leaves are not necessarily back to back and each one is linked
+    // to the leaf written before it
+    fn write_leaf_page(&self, records: &[Vec<u8>], level: &mut Level) -> Result<()> {
+        let new_page = self.allocate_page(PageType::LEAF, false)?;
+        let mut new_page = new_page.write();
+        new_page.set_records(records)?;
+        if let Some((_, prev)) = level.last() {
+            new_page.set_prev_leaf(Some(*prev));
+            self.fetch(*prev)?.write().set_next_leaf(Some(new_page.index));
+        }
+        let key_len = K::decode(&records[0])?.1;
+        level.push((records[0][..key_len].to_vec(), new_page.index));
+        Ok(())
+    }
+
+    /// Cursors hold no latch between items and may run alongside writers; an item inserted or
+    /// removed ahead of a cursor is seen or skipped depending on timing.
+    pub fn iter(&self) -> Result<Cursor<'_, K, V>> {
+        self.range(..)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<Cursor<'_, K, V>> {
+        Ok(Cursor {
+            btree: self,
+            lower: range.start_bound().cloned(),
+            upper: range.end_bound().cloned(),
+            front: None,
+            back: None,
+            done: false,
+        })
+    }
+
+    /// A frozen view of the tree as it is now, e.g. for end-of-day totals while deposits carry
+    /// on. Taking it waits for the running write and pins the root in META.
+    ///
+    /// While a snapshot lives the tree is shadow paged: a writer about to change a page the
+    /// snapshot reads writes a copy of it instead, along with every page above it up to a new
+    /// root, which is published in META. The originals are retired, left as they are until no
+    /// live snapshot reads them and then freed. A snapshot reads from its own root down and
+    /// never follows the links between leaves, which writers keep pointing at the copies.
+    /// Without a live snapshot writers change pages in place as always.
+    ///
+    /// The retired pages take room in the file, at most as much as the tree took when the
+    /// snapshot was taken. META counts them, so if the process stops while a snapshot lives
+    /// they are found and freed when the tree is opened for writing again.
+    pub fn snapshot(&self) -> Result<Snapshot<'_, K, V>> {
+        let _writer = self.writer.lock();
+        let meta_page = self.meta_page.lock();
+        let mut snapshots = self.snapshots.lock();
+        snapshots.epoch += 1;
+        let epoch = snapshots.epoch;
+        snapshots.live.insert(epoch);
+        Ok(Snapshot {
+            btree: self,
+            root_index: meta_page.root_index(),
+            keys: meta_page.key_count(),
+            epoch,
+        })
+    }
+
+    // frees the retired pages that no snapshot reads once the one of epoch is gone, as one
+    // operation. Pages that could not be freed stay retired
+    fn release_snapshot(&self, epoch: u64) -> Result<()> {
+        let _writer = self.writer.lock();
+        let freed = {
+            let mut snapshots = self.snapshots.lock();
+            snapshots.live.remove(&epoch);
+            if snapshots.live.is_empty() {
+                snapshots.born.clear();
+            }
+            let retired = std::mem::take(&mut snapshots.retired);
+            let (kept, freed): (Vec<_>, Vec<_>) =
+                retired.into_iter().partition(|&(_, born, retired)| snapshots.reads(born, retired));
+            snapshots.retired = kept;
+            freed
+        };
+        if freed.is_empty() {
+            return Ok(());
+        }
+        let result = freed.iter().try_for_each(|&(index, ..)| {
+            self.free_page(&mut *self.latch_mut(index)?);
+            Ok(())
+        });
+        let result = result.map(|()| {
+            let mut meta_page = self.meta_mut();
+            let retired_pages = meta_page.retired_pages();
+            meta_page.set_retired_pages(retired_pages.saturating_sub(freed.len() as u32));
+        });
+        if let Err(err) = self.finish(result) {
+            self.snapshots.lock().retired.extend(freed);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    // descends to the leaf where key belongs, or to the leftmost / rightmost leaf without a key
+    fn seek_leaf(&self, key: Option<&K>, rightmost: bool) -> Result<PageRef<K, V>> {
+        let mut p = self.read_root()?;
+        loop {
+            let child_page_index = match p.page_type {
+                PageType::LEAF => break,
+                PageType::INTERNAL => {
+                    let ptr_index = match key {
+                        Some(key) => p.child_slot(key),
+                        None if rightmost => p.item_count(),
+                        None => 0,
+                    };
+                    p.ptr_at(ptr_index).unwrap()
+                }
+                _ => {
+                    panic!("impossible a meta page")
+                }
+            };
+            p = self.fetch(child_page_index)?.read_arc();
+        }
+        Ok(ArcRwLockReadGuard::rwlock(&p).clone())
+    }
+
+    // copies every page on the way to key that a live snapshot reads, from the root down, and
+    // links each copy in place of its original, so that the running operation can change the
+    // path in place. A removal may merge with or borrow from a sibling on every level, those
+    // are copied too unless key is not in the tree
+    fn unshare_path(&self, key: &K, removing: bool) -> Result<()> {
+        if self.snapshots.lock().live.is_empty() {
+            return Ok(());
+        }
+        if removing && !matches!(self.seek_leaf(Some(key), false)?.read().find(key), Some((_, Pos::Current))) {
+            return Ok(());
+        }
+        let mut index = {
+            let _root_latch = self.root_latch.write();
+            let root_index = self.meta_page.lock().root_index();
+            if self.snapshots.lock().is_shared(root_index) {
+                let copy = self.copy_page(root_index)?;
+                self.meta_mut().set_root_index(copy);
+                copy
+            } else {
+                root_index
+            }
+        };
+        loop {
+            let mut page = self.fetch_mut(index)?;
+            if page.page_type != PageType::INTERNAL {
+                return Ok(());
+            }
+            let slot = page.child_slot(key);
+            // the sibling `rebalance` picks
+            if removing && page.item_count() > 0 {
+                self.unshare_child(&mut page, if slot > 0 { slot - 1 } else { slot + 1 })?;
+            }
+            index = self.unshare_child(&mut page, slot)?;
+        }
+    }
+
+    // the child at slot of page, which the running operation may change, copied first if a live
+    // snapshot reads it
+    fn unshare_child(&self, page: &mut Page<K, V>, slot: usize) -> Result<u32> {
+        let child = page.ptr_at(slot).unwrap();
+        if !self.snapshots.lock().is_shared(child) {
+            return Ok(child);
+        }
+        let copy = self.copy_page(child)?;
+        page.set_ptr_at(slot, copy)?;
+        Ok(copy)
+    }
+
+    // copies a page a live snapshot reads to a new page and retires the original, returning the
+    // copy for the caller to link in its place. A copied leaf is put in the leaf chain here
+    fn copy_page(&self, index: u32) -> Result<u32> {
+        self.begin_smo();
+        let (copy_index, links) = {
+            let page = self.fetch(index)?;
+            let page = page.read();
+            let is_leaf = page.page_type == PageType::LEAF;
+            let copy = self.new_page(if is_leaf { PageType::LEAF } else { PageType::INTERNAL })?;
+            let mut copy = copy.write();
+            copy.copy_from(&page);
+            let links = is_leaf.then(|| (page.prev_leaf(), page.next_leaf()));
+            (copy.index, links)
+        };
+        if let Some((prev, next)) = links {
+            if let Some(prev) = prev {
+                self.latch_mut(prev)?.set_next_leaf(Some(copy_index));
+            }
+            if let Some(next) = next {
+                self.latch_mut(next)?.set_prev_leaf(Some(copy_index));
+            }
+        }
+        self.retire_page(index);
+        Ok(copy_index)
+    }
+
+    // takes a page a live snapshot reads out of the tree. It is left as it is until no snapshot
+    // reads it, META counts it in case the process stops before
+    fn retire_page(&self, index: u32) {
+        let born = self.snapshots.lock().born(index);
+        self.txn.lock().retired.push((index, born));
+        let mut meta_page = self.meta_mut();
+        let retired_pages = meta_page.retired_pages();
+        meta_page.set_retired_pages(retired_pages + 1);
+    }
+
+    // a new page for the running operation, pinned until commit. It was free, so no snapshot
+    // reads it
+    fn new_page(&self, pt: PageType) -> Result<PageRef<K, V>> {
+        let page = self.allocate_page(pt, true)?;
+        self.txn.lock().pages.push(page.clone());
+        Ok(page)
+    }
+
+    // takes a page off the free list, the file only grows once the list is empty. A tracked
+    // page can be rolled back with the running operation
+    fn allocate_page(&self, pt: PageType, tracked: bool) -> Result<PageRef<K, V>> {
+        let (free_index, next_free_list) = {
+            let mut meta_page = self.meta_mut();
+            (meta_page.pop_free(), meta_page.next_free_list())
+        };
+        let index = match (free_index, next_free_list) {
+            (Some(index), _) => index,
+            // META has run out, the entries of the next free-list page move into it and the
+            // free-list page itself is handed out
+            (None, Some(list_index)) => {
+                let (indexes, next) = {
+                    let list_page = self.fetch(list_index)?;
+                    let list_page = list_page.read();
+                    (list_page.free_list(), list_page.next_free_list())
+                };
+                self.meta_mut().set_free_list(&indexes, next);
+                list_index
+            }
+            (None, None) => {
+                let max_index = {
+                    let mut meta_page = self.meta_mut();
+                    let max_index = meta_page.total_pages();
+                    meta_page.set_total_page(max_index + 1);
+                    max_index
+                };
+                if tracked {
+                    self.txn.lock().undo.insert(max_index, Before::New);
+                }
+                self.snapshots.lock().allocated(max_index);
+                return self.pool.lock().insert(Page::<K, V>::new(self.store.clone(), max_index, pt)?);
+            }
+        };
+        self.snapshots.lock().allocated(index);
+        let page = self.fetch(index)?;
+        if tracked {
+            self.save_undo(&page);
+        }
+        page.write().reset(pt);
+        Ok(page)
+    }
+
+    // puts a page that is no longer linked into the tree on the free list. The page must be
+    // latched by the running operation, it may become a free-list page itself
+    fn free_page(&self, page: &mut Page<K, V>) {
+        let mut meta_page = self.meta_mut();
+        if meta_page.push_free(page.index).is_ok() {
+            return;
+        }
+        let indexes = meta_page.free_list();
+        let next = meta_page.next_free_list();
+        page.reset(PageType::FREELIST);
+        page.set_free_list(&indexes, next);
+        meta_page.set_free_list(&[], Some(page.index));
+    }
+
+    // the cell stored for value in its leaf, spilling the value into overflow pages if it is
+    // too large. Pages for a regular operation are tracked, bulk_load writes its own directly
+    fn value_cell(&self, value: &V, tracked: bool) -> Result<Vec<u8>> {
+        let bytes = Page::<K, V>::encode_value(value)?;
+        if let Some(cell) = Page::<K, V>::inline_value_cell(&bytes) {
+            return Ok(cell);
+        }
+        // written back to front, so each page knows the one after it
+        let mut next = None;
+        for chunk in bytes.chunks(Page::<K, V>::overflow_capacity()).rev() {
+            let page = if tracked {
+                self.new_page(PageType::OVERFLOW)?
+            } else {
+                self.allocate_page(PageType::OVERFLOW, false)?
+            };
+            let mut page = page.write();
+            page.set_overflow(chunk, next);
+            next = Some(page.index);
+        }
+        Ok(Page::<K, V>::overflow_value_cell(bytes.len(), next.unwrap()))
+    }
+
+    // the caller holds the latch of the leaf the value came from, or has unlinked it under the
+    // writer lock, so its overflow pages cannot be freed meanwhile
+    fn load_value(&self, value: Value<V>) -> Result<V> {
+        match value {
+            Value::Inline(value) => Ok(value),
+            Value::Overflow { len, first_page } => {
+                let mut bytes = Vec::with_capacity(len);
+                let mut next = Some(first_page);
+                while let Some(index) = next {
+                    let page = self.fetch(index)?;
+                    let page = page.read();
+                    bytes.extend_from_slice(page.overflow_data());
+                    next = page.next_overflow();
+                }
+                if bytes.len() != len {
+                    return Err(anyhow!("value in overflow pages is {} bytes, expected {}", bytes.len(), len));
+                }
+                Ok(V::decode(&bytes)?.0)
+            }
+        }
+    }
+
+    // reads a value that was unlinked from its leaf and frees its overflow pages
+    fn take_value(&self, value: Value<V>) -> Result<V> {
+        let first_page = match value {
+            Value::Overflow { first_page, .. } => Some(first_page),
+            Value::Inline(_) => None,
+        };
+        let value = self.load_value(value)?;
+        if let Some(first_page) = first_page {
+            self.free_overflow(first_page)?;
+        }
+        Ok(value)
+    }
+
+    fn free_value(&self, value: Option<Value<V>>) -> Result<()> {
+        match value {
+            Some(Value::Overflow { first_page, .. }) => self.free_overflow(first_page),
+            _ => Ok(()),
+        }
+    }
+
+    // the pages of a value a live snapshot still reads are retired rather than freed
+    fn free_overflow(&self, first_page: u32) -> Result<()> {
+        let mut next = Some(first_page);
+        while let Some(index) = next {
+            if self.snapshots.lock().is_shared(index) {
+                next = self.fetch(index)?.read().next_overflow();
+                self.retire_page(index);
+                continue;
+            }
+            let mut page = self.fetch_mut(index)?;
+            next = page.next_overflow();
+            self.free_page(&mut page);
+        }
+        Ok(())
+    }
+
+    fn split_leaf_page(&self, p: &mut Page<K, V>, key: &K, record: &[u8]) -> Result<(K, u32)> {
+        assert_eq!(p.page_type, PageType::LEAF);
+        let new_page = self.new_page(PageType::LEAF)?;
+        let mut new_page = new_page.write();
+        let mut records: Vec<Vec<u8>> = (0..p.item_count()).map(|i| p.record_at(i).to_vec()).collect();
+        match p.find(key) {
+            Some((i, Pos::Current)) => records[i] = record.to_vec(),
+            Some((i, Pos::Left)) => records.insert(i, record.to_vec()),
+            Some((i, Pos::Right)) => records.insert(i + 1, record.to_vec()),
+            None => records.push(record.to_vec()),
+        }
+        let cut_i = split_point(&record_sizes(&records));
+        p.set_records(&records[..cut_i])?;
+        new_page.set_records(&records[cut_i..])?;
+
+        // the new page goes right after p in the leaf chain
+        let next = p.next_leaf();
+        if let Some(next) = next {
+            self.latch_mut(next)?.set_prev_leaf(Some(new_page.index));
+        }
+        new_page.set_next_leaf(next);
+        new_page.set_prev_leaf(Some(p.index));
+        p.set_next_leaf(Some(new_page.index));
+
+        Ok((K::decode(&records[cut_i])?.0, new_page.index))
+    }
+
+    fn split_internal_page(&self, p: &mut Page<K, V>, key: &K, ptr: u32) -> Result<(K, u32)> {
+        assert_eq!(p.page_type, PageType::INTERNAL);
+        let new_page = self.new_page(PageType::INTERNAL)?;
+        let mut new_page = new_page.write();
+        let mut records: Vec<Vec<u8>> = (0..p.item_count()).map(|i| p.record_at(i).to_vec()).collect();
+        let record = Page::<K, V>::internal_record(&Page::<K, V>::encode_key(key)?, ptr);
+        match p.find(key) {
+            Some((i, Pos::Current)) => records[i] = record,
+            Some((i, Pos::Left)) => records.insert(i, record),
+            Some((i, Pos::Right)) => records.insert(i + 1, record),
+            None => records.push(record),
+        }
+
+        // the key of record up_i goes up and its pointer becomes the first child of the new page
+        let up_i = split_point(&record_sizes(&records)).clamp(1, records.len() - 2);
+        let (up_key, up_ptr) = Page::<K, V>::internal_record_parts(&records[up_i]);
+        p.set_records(&records[..up_i])?;
+        new_page.set_ptr_at(0, up_ptr)?;
+        new_page.set_records(&records[up_i + 1..])?;
+        Ok((K::decode(up_key)?.0, new_page.index))
+    }
+}
+
+// indexes 0..len in ascending order of their keys, equal keys keep their order
+fn sorted_order<'a, K: PartialOrd + 'a>(len: usize, key: impl Fn(usize) -> &'a K) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..len).collect();
+    order.sort_by(|a, b| key(*a).partial_cmp(key(*b)).unwrap_or(std::cmp::Ordering::Equal));
+    order
+}
+
+fn is_full_error(err: &anyhow::Error) -> bool {
+    matches!(err.downcast_ref::<PageError>(), Some(PageError::Full))
+}
+
+fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()
+}
+
+fn remove_if_exists(path: &Path) -> std::io::Result<()> {
+    match std::fs::remove_file(path) {
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+// space each record takes up in a page
+fn record_sizes(records: &[Vec<u8>]) -> Vec<usize> {
+    records.iter().map(|record| record_space(record.len())).collect()
+}
+
+// how many of the items go left so both sides take up about the same space, at least one on each
+// side
+fn split_point(sizes: &[usize]) -> usize {
+    let total: usize = sizes.iter().sum();
+    let mut left = 0;
+    for (i, size) in sizes.iter().enumerate() {
+        if left * 2 >= total {
+            return i.clamp(1, sizes.len() - 1);
+        }
+        left += size;
+    }
+    sizes.len() - 1
+}
+
+// splits items of the given sizes into pages of up to per bytes, evening out a last page that
+// would take up less than min. Returns the number of items on each page
+fn chunk_sizes(sizes: &[usize], per: usize, min: usize, max: usize) -> Vec<usize> {
+    // items and bytes of every page
+    let mut chunks = Vec::new();
+    let (mut n, mut size) = (0, 0);
+    for item_size in sizes {
+        if n > 0 && size + item_size > per {
+            chunks.push((n, size));
+            (n, size) = (0, 0);
+        }
+        n += 1;
+        size += item_size;
+    }
+    if n == 0 {
+        return Vec::new();
+    }
+    match chunks.pop() {
+        Some((last_n, last_size)) if size < min && last_size + size <= max => chunks.push((last_n + n, 0)),
+        Some((last_n, _)) if size < min => {
+            let cut = split_point(&sizes[sizes.len() - last_n - n..]);
+            chunks.push((cut, 0));
+            chunks.push((last_n + n - cut, 0));
+        }
+        Some(last) => {
+            chunks.push(last);
+            chunks.push((n, size));
+        }
+        None => chunks.push((n, size)),
+    }
+    chunks.into_iter().map(|(n, _)| n).collect()
+}
+
+// the state of one BTree::verify walk
+struct Verifier<'a, K, V> {
+    btree: &'a BTree<K, V>,
+    // pages already reached, from the tree or the free list
+    seen: Vec<bool>,
+    leaf_depth: Option<usize>,
+    // every leaf in key order
+    leaves: Vec<LeafLinks<K>>,
+    report: VerifyReport,
+}
+
+struct LeafLinks<K> {
+    index: u32,
+    prev: Option<u32>,
+    next: Option<u32>,
+    first_key: Option<K>,
+    last_key: Option<K>,
+}
+
+impl<'a, K, V> Verifier<'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    fn problem(&mut self, page: u32, message: String) {
+        self.report.problems.push(Problem { page, message });
+    }
+
+    // marks a page linked from page from as reached, false if it should not be followed
+    fn visit(&mut self, index: u32, from: u32) -> bool {
+        match self.seen.get_mut(index as usize) {
+            Some(seen) if index > 0 && !*seen => {
+                *seen = true;
+                true
+            }
+            Some(_) if index > 0 => {
+                self.problem(index, format!("reachable twice, again from page {}", from));
+                false
+            }
+            _ => {
+                let total_pages = self.report.total_pages;
+                self.problem(from, format!("links to page {}, outside of pages 1..{}", index, total_pages));
+                false
+            }
+        }
+    }
+
+    fn fetch(&mut self, index: u32) -> Option<PageRef<K, V>> {
+        match self.btree.fetch(index) {
+            Ok(page) => Some(page),
+            Err(err) => {
+                self.problem(index, format!("{:#}", err));
+                None
+            }
+        }
+    }
+
+    // checks the subtree under page index, whose keys must be at least lower and below upper
+    fn walk(&mut self, index: u32, depth: usize, lower: Option<K>, upper: Option<K>) {
+        let page = match self.fetch(index) {
+            Some(page) => page,
+            None => return,
+        };
+        let page = page.read();
+        if page.page_type != PageType::LEAF && page.page_type != PageType::INTERNAL {
+            self.problem(index, format!("{:?} page linked into the tree", page.page_type));
+            return;
+        }
+        let mut keys = Vec::with_capacity(page.item_count());
+        for i in 0..page.item_count() {
+            match page.key_at(i) {
+                Some(key) => keys.push(key),
+                None => return self.problem(index, format!("key at slot {} cannot be decoded", i)),
+            }
+        }
+        if let Some(i) = keys.windows(2).position(|pair| pair[0] >= pair[1]) {
+            self.problem(index, format!("keys out of order at slot {}: {:?} after {:?}", i + 1, keys[i + 1], keys[i]));
+        }
+        if let Some(key) = keys.iter().find(|key| lower.as_ref().is_some_and(|lower| *key < lower)) {
+            self.problem(index, format!("key {:?} is below {:?}, the separator on its left", key, lower.as_ref().unwrap()));
+        }
+        if let Some(key) = keys.iter().find(|key| upper.as_ref().is_some_and(|upper| *key >= upper)) {
+            self.problem(index, format!("key {:?} is not below {:?}, the separator on its right", key, upper.as_ref().unwrap()));
+        }
+
+        if page.page_type == PageType::LEAF {
+            self.report.leaf_pages += 1;
+            self.report.keys += keys.len();
+            match self.leaf_depth {
+                None => {
+                    self.leaf_depth = Some(depth);
+                    self.report.depth = depth;
+                }
+                Some(leaf_depth) if leaf_depth != depth => {
+                    self.problem(index, format!("leaf at depth {} while the first leaf is at depth {}", depth, leaf_depth))
+                }
+                _ => {}
+            }
+            let values: Vec<Option<Value<V>>> = (0..keys.len()).map(|i| page.value_at(i)).collect();
+            self.leaves.push(LeafLinks {
+                index,
+                prev: page.prev_leaf(),
+                next: page.next_leaf(),
+                first_key: keys.first().cloned(),
+                last_key: keys.last().cloned(),
+            });
+            drop(page);
+            for (i, value) in values.into_iter().enumerate() {
+                match value {
+                    None => self.problem(index, format!("value at slot {} cannot be decoded", i)),
+                    Some(Value::Overflow { len, first_page }) => self.walk_overflow(index, i, len, first_page),
+                    Some(Value::Inline(_)) => {}
+                }
+            }
+            return;
+        }
+
+        self.report.internal_pages += 1;
+        let mut children = Vec::with_capacity(keys.len() + 1);
+        for slot in 0..=keys.len() {
+            match page.ptr_at(slot) {
+                Some(ptr) => children.push(ptr),
+                None => return self.problem(index, format!("pointer at slot {} cannot be decoded", slot)),
+            }
+        }
+        drop(page);
+        for (slot, child) in children.into_iter().enumerate() {
+            let lower = if slot == 0 { lower.clone() } else { Some(keys[slot - 1].clone()) };
+            let upper = if slot == keys.len() { upper.clone() } else { Some(keys[slot].clone()) };
+            if self.visit(child, index) {
+                self.walk(child, depth + 1, lower, upper);
+            }
+        }
+    }
+
+    fn walk_overflow(&mut self, leaf: u32, slot: usize, len: usize, first_page: u32) {
+        let mut bytes = 0;
+        let mut from = leaf;
+        let mut next = Some(first_page);
+        while let Some(index) = next {
+            if !self.visit(index, from) {
+                return;
+            }
+            let page = match self.fetch(index) {
+                Some(page) => page,
+                None => return,
+            };
+            let page = page.read();
+            if page.page_type != PageType::OVERFLOW {
+                return self.problem(index, format!("{:?} page in the value at slot {} of leaf {}", page.page_type, slot, leaf));
+            }
+            self.report.overflow_pages += 1;
+            bytes += page.overflow_data().len();
+            from = index;
+            next = page.next_overflow();
+        }
+        if bytes != len {
+            self.problem(leaf, format!("value at slot {} has {} bytes in overflow pages, expected {}", slot, bytes, len));
+        }
+    }
+
+    // the leaves must be chained in the order the tree has them, with keys ascending across them
+    fn check_leaf_chain(&mut self) {
+        let leaves = std::mem::take(&mut self.leaves);
+        let mut last_key: Option<(u32, K)> = None;
+        for (i, leaf) in leaves.iter().enumerate() {
+            let expected_prev = i.checked_sub(1).map(|i| leaves[i].index);
+            let expected_next = leaves.get(i + 1).map(|next| next.index);
+            if leaf.prev != expected_prev {
+                self.problem(leaf.index, format!("previous leaf is {:?}, expected {:?}", leaf.prev, expected_prev));
+            }
+            if leaf.next != expected_next {
+                self.problem(leaf.index, format!("next leaf is {:?}, expected {:?}", leaf.next, expected_next));
+            }
+            if let (Some((last_index, last_key)), Some(first)) = (&last_key, &leaf.first_key) {
+                if first <= last_key {
+                    self.problem(leaf.index, format!("first key {:?} is not above {:?}, the last key of leaf {}", first, last_key, last_index));
+                }
+            }
+            if let Some(last) = &leaf.last_key {
+                last_key = Some((leaf.index, last.clone()));
+            }
+        }
+    }
+
+    fn walk_free_list(&mut self, free: Vec<u32>, mut next_free_list: Option<u32>) {
+        let mut from = 0;
+        let mut free = free;
+        loop {
+            for index in free {
+                if self.visit(index, from) {
+                    self.report.free_pages += 1;
+                }
+            }
+            let list_index = match next_free_list {
+                Some(list_index) if self.visit(list_index, from) => list_index,
+                _ => return,
+            };
+            let page = match self.fetch(list_index) {
+                Some(page) => page,
+                None => return,
+            };
+            let page = page.read();
+            if page.page_type != PageType::FREELIST {
+                return self.problem(list_index, format!("{:?} page in the free list", page.page_type));
+            }
+            self.report.free_pages += 1;
+            free = page.free_list();
+            next_free_list = page.next_free_list();
+            from = list_index;
+        }
+    }
+
+    fn orphans(&self) -> Vec<u32> {
+        (1..self.report.total_pages).filter(|index| !self.seen[*index as usize]).collect()
+    }
+
+    // the pages retired for snapshots are orphans, as many as META counts
+    fn check_orphans(&mut self, retired_pages: u32) {
+        let orphans = self.orphans();
+        if orphans.len() == retired_pages as usize {
+            self.report.retired_pages = orphans.len();
+            return;
+        }
+        if retired_pages > 0 {
+            self.problem(0, format!("META counts {} retired pages but {} pages are orphaned", retired_pages, orphans.len()));
+        }
+        for index in orphans {
+            self.problem(index, "orphaned, neither in the tree nor on the free list".to_string());
+        }
+    }
+}
+
+/// Walks the leaf chain in key order, from the front with `next` and from the back with
+/// `next_back`. Created by `BTree::range` and `BTree::iter`.
+///
+/// A cursor only remembers the leaf it is on and the last key it yielded at each end. If the
+/// tree has been restructured since it last looked, it seeks again from the root.
+pub struct Cursor<'a, K, V> {
+    btree: &'a BTree<K, V>,
+    // what is left to yield, narrowed to exclude each key as it is yielded, so the two ends
+    // never cross
+    lower: Bound<K>,
+    upper: Bound<K>,
+    // leaf of the next item for each end with the tree version it was found at
+    front: Option<(PageRef<K, V>, u64)>,
+    back: Option<(PageRef<K, V>, u64)>,
+    done: bool,
+}
+
+impl<'a, K, V> Cursor<'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    // latches the leaf for an end, seeking it first if there is none yet or it is stale
+    fn latch(
+        &self,
+        leaf: &mut Option<(PageRef<K, V>, u64)>,
+        key: Option<&K>,
+        rightmost: bool,
+    ) -> Result<ReadLatch<K, V>> {
+        loop {
+            if leaf.is_none() {
+                // the version is read first, an SMO racing with the seek is caught below
+                let version = self.btree.version.load(Ordering::Acquire);
+                *leaf = Some((self.btree.seek_leaf(key, rightmost)?, version));
+            }
+            let (page, version) = leaf.as_ref().unwrap();
+            let latch = page.read_arc();
+            if self.btree.version.load(Ordering::Acquire) == *version {
+                return Ok(latch);
+            }
+            *leaf = None;
+        }
+    }
+
+    fn step_front(&mut self) -> Result<Option<(K, V)>> {
+        let mut front = self.front.take();
+        let key = match &self.lower {
+            Bound::Included(k) | Bound::Excluded(k) => Some(k.clone()),
+            Bound::Unbounded => None,
+        };
+        loop {
+            let p = self.latch(&mut front, key.as_ref(), false)?;
+            // slot of the first item above the lower bound
+            let i = match &self.lower {
+                Bound::Included(k) => match p.find(k) {
+                    Some((i, Pos::Right)) => i + 1,
+                    Some((i, _)) => i,
+                    None => 0,
+                },
+                Bound::Excluded(k) => match p.find(k) {
+                    Some((i, Pos::Left)) => i,
+                    Some((i, _)) => i + 1,
+                    None => 0,
+                },
+                Bound::Unbounded => 0,
+            };
+            if i < p.item_count() {
+                let key = p.key_at(i).unwrap();
+                if !self.upper_contains(&key) {
+                    return Ok(None);
+                }
+                // overflow pages are read before the leaf latch is let go
+                let value = self.btree.load_value(p.value_at(i).unwrap())?;
+                drop(p);
+                self.lower = Bound::Excluded(key.clone());
+                self.front = front;
+                return Ok(Some((key, value)));
+            }
+            // the latch is let go before moving on, a reader never waits on a sibling
+            let next = p.next_leaf();
+            drop(p);
+            match next {
+                Some(next) => {
+                    let version = front.as_ref().unwrap().1;
+                    front = Some((self.btree.fetch(next)?, version));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn step_back(&mut self) -> Result<Option<(K, V)>> {
+        let mut back = self.back.take();
+        let key = match &self.upper {
+            Bound::Included(k) | Bound::Excluded(k) => Some(k.clone()),
+            Bound::Unbounded => None,
+        };
+        loop {
+            let p = self.latch(&mut back, key.as_ref(), true)?;
+            // one past the slot of the last item below the upper bound
+            let j = match &self.upper {
+                Bound::Included(k) => match p.find(k) {
+                    Some((i, Pos::Left)) => i,
+                    Some((i, _)) => i + 1,
+                    None => 0,
+                },
+                Bound::Excluded(k) => match p.find(k) {
+                    Some((i, Pos::Right)) => i + 1,
+                    Some((i, _)) => i,
+                    None => 0,
+                },
+                Bound::Unbounded => p.item_count(),
+            };
+            if j > 0 {
+                let key = p.key_at(j - 1).unwrap();
+                if !self.lower_contains(&key) {
+                    return Ok(None);
+                }
+                let value = self.btree.load_value(p.value_at(j - 1).unwrap())?;
+                drop(p);
+                self.upper = Bound::Excluded(key.clone());
+                self.back = back;
+                return Ok(Some((key, value)));
+            }
+            let prev = p.prev_leaf();
+            drop(p);
+            match prev {
+                Some(prev) => {
+                    let version = back.as_ref().unwrap().1;
+                    back = Some((self.btree.fetch(prev)?, version));
+                }
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn lower_contains(&self, key: &K) -> bool {
+        match &self.lower {
+            Bound::Included(start) => *key >= *start,
+            Bound::Excluded(start) => *key > *start,
+            Bound::Unbounded => true,
+        }
+    }
+
+    fn upper_contains(&self, key: &K) -> bool {
+        match &self.upper {
+            Bound::Included(end) => *key <= *end,
+            Bound::Excluded(end) => *key < *end,
+            Bound::Unbounded => true,
+        }
+    }
+}
+
+impl<'a, K, V> Iterator for Cursor<'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step_front().transpose();
+        // stop after the last item or the first error
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+impl<'a, K, V> DoubleEndedIterator for Cursor<'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let item = self.step_back().transpose();
+        self.done = !matches!(item, Some(Ok(_)));
+        item
+    }
+}
+
+/// The tree as it was when `BTree::snapshot` was called. The pages it reads are kept until it
+/// is dropped.
+pub struct Snapshot<'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    btree: &'a BTree<K, V>,
+    root_index: u32,
+    keys: u64,
+    epoch: u64,
+}
+
+impl<'a, K, V> Snapshot<'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    /// The number of keys in the tree when the snapshot was taken.
+    pub fn len(&self) -> u64 {
+        self.keys
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.keys == 0
+    }
+
+    /// How many pages writers have replaced with copies since the snapshot was taken, kept for
+    /// it until it is dropped.
+    pub fn kept_pages(&self) -> usize {
+        let snapshots = self.btree.snapshots.lock();
+        snapshots.retired.iter().filter(|&&(_, born, retired)| born < self.epoch && self.epoch <= retired).count()
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>> {
+        let mut index = self.root_index;
+        loop {
+            let page = self.btree.fetch(index)?;
+            let page = page.read();
+            match page.page_type {
+                PageType::INTERNAL => index = page.ptr_at(page.child_slot(key)).unwrap(),
+                PageType::LEAF => {
+                    return match page.find(key) {
+                        Some((i, Pos::Current)) => Ok(Some(self.btree.load_value(page.value_at(i).unwrap())?)),
+                        _ => Ok(None),
+                    };
+                }
+                _ => return Err(anyhow!("{:?} page {} in the snapshot", page.page_type, index)),
+            }
+        }
+    }
+
+    pub fn iter(&self) -> Result<SnapshotIter<'_, 'a, K, V>> {
+        self.range(..)
+    }
+
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> Result<SnapshotIter<'_, 'a, K, V>> {
+        let mut iter = SnapshotIter {
+            snapshot: self,
+            stack: Vec::new(),
+            items: Vec::new(),
+            upper: range.end_bound().cloned(),
+            done: false,
+        };
+        iter.descend(self.root_index, range.start_bound())?;
+        Ok(iter)
+    }
+}
+
+impl<'a, K, V> Drop for Snapshot<'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    fn drop(&mut self) {
+        if let Err(err) = self.btree.release_snapshot(self.epoch) {
+            eprintln!("could not free the pages kept for a snapshot: {:#}", err);
+        }
+    }
+}
+
+/// Walks a `Snapshot` in key order. Created by `Snapshot::range` and `Snapshot::iter`.
+///
+/// It goes down from the root of the snapshot rather than along the leaf chain, remembering
+/// the children still to visit on every level, and reads one leaf at a time. The pages do not
+/// change while the snapshot lives, so no latch is held between steps.
+pub struct SnapshotIter<'s, 'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    snapshot: &'s Snapshot<'a, K, V>,
+    // for each internal page above the current leaf, the children still to visit, last first
+    stack: Vec<Vec<u32>>,
+    // the items of the current leaf still to yield, last first
+    items: Vec<(K, Value<V>)>,
+    upper: Bound<K>,
+    done: bool,
+}
+
+impl<'s, 'a, K, V> SnapshotIter<'s, 'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    // reads the pages from index down to the leaf where lower falls, or to the leftmost leaf
+    // without a bound
+    fn descend(&mut self, mut index: u32, lower: Bound<&K>) -> Result<()> {
+        loop {
+            let page = self.snapshot.btree.fetch(index)?;
+            let page = page.read();
+            match page.page_type {
+                PageType::INTERNAL => {
+                    let slot = match lower {
+                        Bound::Included(k) | Bound::Excluded(k) => page.child_slot(k),
+                        Bound::Unbounded => 0,
+                    };
+                    self.stack.push((slot + 1..=page.item_count()).rev().map(|i| page.ptr_at(i).unwrap()).collect());
+                    index = page.ptr_at(slot).unwrap();
+                }
+                PageType::LEAF => {
+                    // slot of the first item above the lower bound
+                    let first = match lower {
+                        Bound::Included(k) => match page.find(k) {
+                            Some((i, Pos::Right)) => i + 1,
+                            Some((i, _)) => i,
+                            None => 0,
+                        },
+                        Bound::Excluded(k) => match page.find(k) {
+                            Some((i, Pos::Left)) => i,
+                            Some((i, _)) => i + 1,
+                            None => 0,
+                        },
+                        Bound::Unbounded => 0,
+                    };
+                    self.items = (first..page.item_count())
+                        .rev()
+                        .map(|i| (page.key_at(i).unwrap(), page.value_at(i).unwrap()))
+                        .collect();
+                    return Ok(());
+                }
+                _ => return Err(anyhow!("{:?} page {} in the snapshot", page.page_type, index)),
+            }
+        }
+    }
+
+    fn step(&mut self) -> Result<Option<(K, V)>> {
+        while !self.done {
+            if let Some((key, value)) = self.items.pop() {
+                let below_upper = match &self.upper {
+                    Bound::Included(end) => key <= *end,
+                    Bound::Excluded(end) => key < *end,
+                    Bound::Unbounded => true,
+                };
+                if !below_upper {
+                    break;
+                }
+                return Ok(Some((key, self.snapshot.btree.load_value(value)?)));
+            }
+            // the leaf is used up, the next one is the leftmost under the next child to visit
+            let next = loop {
+                match self.stack.last_mut() {
+                    Some(children) => match children.pop() {
+                        Some(child) => break Some(child),
+                        None => {
+                            self.stack.pop();
+                        }
+                    },
+                    None => break None,
+                }
+            };
+            match next {
+                Some(child) => self.descend(child, Bound::Unbounded)?,
+                None => break,
+            }
+        }
+        self.done = true;
+        Ok(None)
+    }
+}
+
+impl<'s, 'a, K, V> Iterator for SnapshotIter<'s, 'a, K, V>
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let item = self.step().transpose();
+        // stop after the first error
+        if let Some(Err(_)) = item {
+            self.done = true;
+        }
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store::{Fault, FaultTrigger, FaultyStore, MemoryStore};
+    use super::super::test_util::{remove_files, temp_path};
+    use super::super::threadpool::Pool;
+    use super::*;
+
+    fn assert_healthy<K, V>(btree: &BTree<K, V>)
+    where
+        K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+        V: Encodable + Decodable + BinSizer + Debug + Clone,
+    {
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+    }
+
+    #[test]
+    fn test_remove() {
+        let path = temp_path("remove");
+        let btree = BTree::<i32, i32>::new(path);
+        for i in 0..5000 {
+            btree.set(&i, &(i * 2)).unwrap();
+        }
+        assert_eq!(btree.remove(&5000).unwrap(), None);
+        for i in (0..5000).step_by(2) {
+            assert_eq!(btree.remove(&i).unwrap(), Some(i * 2));
+        }
+        for i in 0..5000 {
+            let expected = if i % 2 == 0 { None } else { Some(i * 2) };
+            assert_eq!(btree.get(&i).unwrap(), expected);
+        }
+        assert_eq!(btree.remove(&0).unwrap(), None);
+        assert_healthy(&btree);
+        drop(btree);
+
+        // removals are persisted
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(btree.get(&2).unwrap(), None);
+        assert_eq!(btree.get(&3).unwrap(), Some(6));
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_remove_collapses_root() {
+        let path = temp_path("remove-collapse");
+        let btree = BTree::<i32, i32>::new(path);
+        for i in 0..20000 {
+            btree.set(&i, &i).unwrap();
+        }
+        assert_eq!(btree.root().unwrap().read().page_type, PageType::INTERNAL);
+        for i in (0..20000).rev() {
+            assert_eq!(btree.remove(&i).unwrap(), Some(i));
+        }
+        {
+            let root_page = btree.root().unwrap();
+            let root_page = root_page.read();
+            assert_eq!(root_page.page_type, PageType::LEAF);
+            assert_eq!(root_page.item_count(), 0);
+        }
+
+        btree.set(&7, &7).unwrap();
+        assert_eq!(btree.get(&7).unwrap(), Some(7));
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_range() {
+        let path = temp_path("range");
+        let btree = BTree::<i32, i32>::new(path);
+        // even keys only, spread over many leaves
+        for i in (0..3000).rev() {
+            btree.set(&(i * 2), &i).unwrap();
+        }
+        let keys = |c: Cursor<i32, i32>| c.map(|r| r.unwrap().0).collect::<Vec<_>>();
+
+        assert_eq!(keys(btree.iter().unwrap()), (0..3000).map(|i| i * 2).collect::<Vec<_>>());
+        assert_eq!(keys(btree.range(100..110).unwrap()), vec![100, 102, 104, 106, 108]);
+        assert_eq!(keys(btree.range(101..=110).unwrap()), vec![102, 104, 106, 108, 110]);
+        assert_eq!(keys(btree.range((Bound::Excluded(100), Bound::Included(104))).unwrap()), vec![102, 104]);
+        assert_eq!(keys(btree.range(5990..).unwrap()), vec![5990, 5992, 5994, 5996, 5998]);
+        assert_eq!(keys(btree.range(..3).unwrap()), vec![0, 2]);
+        assert!(keys(btree.range(101..102).unwrap()).is_empty());
+        assert!(keys(btree.range(7000..).unwrap()).is_empty());
+
+        let rev = btree.range(..=1000).unwrap().rev().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(rev, (0..=500).rev().map(|i| i * 2).collect::<Vec<_>>());
+
+        // both ends meet in the middle without yielding an item twice
+        let mut cursor = btree.range(1000..=5000).unwrap();
+        let mut seen = Vec::new();
+        loop {
+            match (cursor.next(), cursor.next_back()) {
+                (Some(a), Some(b)) => {
+                    seen.push(a.unwrap().0);
+                    seen.push(b.unwrap().0);
+                }
+                (Some(a), None) => seen.push(a.unwrap().0),
+                _ => break,
+            }
+        }
+        seen.sort();
+        assert_eq!(seen, (500..=2500).map(|i| i * 2).collect::<Vec<_>>());
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_range_after_remove() {
+        let path = temp_path("range-remove");
+        let btree = BTree::<i32, i32>::new(path);
+        for i in 0..5000 {
+            btree.set(&i, &i).unwrap();
+        }
+        for i in 0..5000 {
+            if i % 3 != 0 {
+                btree.remove(&i).unwrap();
+            }
+        }
+        let expected = (0..5000).filter(|i| i % 3 == 0).collect::<Vec<_>>();
+        let forward = btree.iter().unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(forward, expected);
+        let mut backward = btree.iter().unwrap().rev().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        backward.reverse();
+        assert_eq!(backward, expected);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_bulk_load() {
+        let path = temp_path("bulk-load");
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(btree.bulk_load((0..100000).map(|i| (i * 3, i)), 1.0).unwrap(), 100000);
+        // packed leaves plus a handful of internal pages
+        let leaves = 100000_usize.div_ceil(Page::<i32, i32>::capacity() / record_space(8));
+        assert!((btree.meta_page.lock().total_pages() as usize) < 2 + leaves + 5);
+        for i in (0..100000).step_by(7) {
+            assert_eq!(btree.get(&(i * 3)).unwrap(), Some(i));
+            assert_eq!(btree.get(&(i * 3 + 1)).unwrap(), None);
+        }
+        assert_eq!(btree.iter().unwrap().count(), 100000);
+        let last = btree.iter().unwrap().next_back().unwrap().unwrap();
+        assert_eq!(last, (299997, 99999));
+
+        // the loaded tree takes regular updates
+        btree.set(&1, &-1).unwrap();
+        assert_eq!(btree.remove(&3).unwrap(), Some(1));
+        drop(btree);
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(btree.get(&1).unwrap(), Some(-1));
+        assert_eq!(btree.get(&3).unwrap(), None);
+        assert_eq!(btree.get(&299997).unwrap(), Some(99999));
+        assert!(btree.bulk_load(vec![(1, 1)], 1.0).is_err());
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_bulk_load_rejects_bad_input() {
+        let path = temp_path("bulk-load-bad");
+        let btree = BTree::<i32, i32>::new(path);
+        for fill_factor in [0.0, 0.3, 0.49, 1.1, f64::NAN] {
+            assert!(btree.bulk_load(vec![(1, 1)], fill_factor).is_err());
+        }
+        assert!(btree.bulk_load(vec![(1, 1), (3, 3), (2, 2)], 0.8).is_err());
+        assert!(btree.bulk_load(vec![(1, 1), (1, 2)], 0.8).is_err());
+        // out of order after several full leaves and internal pages have been written, twice
+        // so the second load reuses the pages the first one gave back
+        for _ in 0..2 {
+            let items = (0..5000).map(|i| (i, i)).chain(std::iter::once((7, 7)));
+            assert!(btree.bulk_load(items, 1.0).is_err());
+            assert_healthy(&btree);
+        }
+        // still empty and usable
+        assert_eq!(btree.iter().unwrap().count(), 0);
+        assert_eq!(btree.bulk_load(vec![(1, 1), (2, 2)], 0.8).unwrap(), 2);
+        assert_eq!(btree.get(&2).unwrap(), Some(2));
+        assert_healthy(&btree);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_chunk_sizes() {
+        assert_eq!(chunk_sizes(&[1; 10], 5, 2, 6), vec![5, 5]);
+        assert_eq!(chunk_sizes(&[1; 11], 5, 2, 6), vec![5, 6]);
+        assert_eq!(chunk_sizes(&[1; 11], 5, 2, 5), vec![5, 3, 3]);
+        assert_eq!(chunk_sizes(&[1; 13], 5, 2, 6), vec![5, 5, 3]);
+        assert_eq!(chunk_sizes(&[1; 3], 5, 2, 6), vec![3]);
+        // pages are filled by size, not by count
+        assert_eq!(chunk_sizes(&[3, 1, 1, 2, 4, 1], 5, 2, 6), vec![3, 1, 2]);
+        assert_eq!(chunk_sizes(&[3, 1, 1, 4, 1, 1], 5, 3, 6), vec![3, 3]);
+        assert_eq!(chunk_sizes(&[2, 2, 1, 4, 2, 1], 5, 3, 5), vec![3, 1, 2]);
+        assert_eq!(chunk_sizes(&[3, 1, 1, 4, 1, 2], 5, 3, 6), vec![3, 1, 2]);
+        assert_eq!(split_point(&[1, 1, 1, 1]), 2);
+        assert_eq!(split_point(&[10, 1, 1, 1]), 1);
+        assert_eq!(split_point(&[1, 1, 1, 10]), 3);
+    }
+
+    #[test]
+    fn test_buffer_pool_write_back() {
+        let path = temp_path("pool-write-back");
+        let btree = BTree::<i32, i32>::with_pool_capacity(path, 4);
+        for i in 0..20000 {
+            btree.set(&i, &-i).unwrap();
+        }
+        let stats = btree.pool_stats();
+        assert!(stats.cached <= 4);
+        assert_eq!(stats.pinned, 0);
+        assert!(stats.evictions > 0 && stats.write_backs > 0);
+        drop(btree);
+
+        let btree = BTree::<i32, i32>::with_pool_capacity(path, 4);
+        for i in 0..20000 {
+            assert_eq!(btree.get(&i).unwrap(), Some(-i));
+        }
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_buffer_pool_lru() {
+        let path = temp_path("pool-lru");
+        let btree = BTree::<i32, i32>::new(path);
+        btree.bulk_load((0..5000).map(|i| (i, i)), 1.0).unwrap();
+        drop(btree);
+
+        let store = Arc::new(FileStore::new(OpenOptions::new().read(true).write(true).open(path).unwrap()));
+        let mut pool = BufferPool::<i32, i32>::new(store, 2, None);
+        pool.fetch(2).unwrap();
+        pool.fetch(3).unwrap();
+        pool.fetch(2).unwrap();
+        // 3 is the least recently used
+        pool.fetch(4).unwrap();
+        assert!(pool.frames.contains_key(&2) && pool.frames.contains_key(&4));
+        assert_eq!((pool.hits, pool.misses, pool.evictions), (1, 3, 1));
+
+        // pinned pages stay, the pool goes over capacity until they are released
+        let pinned = [pool.fetch(5).unwrap(), pool.fetch(6).unwrap(), pool.fetch(7).unwrap()];
+        assert_eq!(pool.stats().cached, 3);
+        assert_eq!(pool.stats().pinned, 3);
+        drop(pinned);
+        pool.fetch(2).unwrap();
+        assert_eq!(pool.stats().cached, 2);
+        assert!(pool.frames.contains_key(&7));
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_btree_is_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<BTree<i32, i32>>();
+    }
+
+    #[test]
+    fn test_concurrent_get_during_set() {
+        let path = temp_path("concurrent-get");
+        // a small pool keeps pages moving in and out under the readers
+        let btree = Arc::new(BTree::<i32, i32>::with_pool_capacity(path, 16));
+        btree.bulk_load((0..20000).map(|i| (i * 2, i)), 1.0).unwrap();
+        let mut pool = Pool::new(4);
+        for reader in 0..3 {
+            let btree = btree.clone();
+            pool.execute(
+                move || {
+                    for round in 0..3 {
+                        for i in ((reader + round) % 3..20000).step_by(3) {
+                            assert_eq!(btree.get(&(i * 2)).unwrap(), Some(i));
+                        }
+                    }
+                },
+                false,
+            );
+        }
+        // odd keys land between the loaded ones and split pages all over the tree
+        for i in 0..20000 {
+            btree.set(&(i * 2 + 1), &-i).unwrap();
+        }
+        drop(pool);
+
+        for i in 0..20000 {
+            assert_eq!(btree.get(&(i * 2)).unwrap(), Some(i));
+            assert_eq!(btree.get(&(i * 2 + 1)).unwrap(), Some(-i));
+        }
+        let stats = btree.pool_stats();
+        assert_eq!(stats.pinned, 0);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_cursor_during_set_and_remove() {
+        let path = temp_path("concurrent-cursor");
+        let btree = Arc::new(BTree::<i32, i32>::new(path));
+        for i in 0..2000 {
+            btree.set(&(i * 4), &i).unwrap();
+        }
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let readers: Vec<_> = (0..2)
+            .map(|reader| {
+                let btree = btree.clone();
+                let done = done.clone();
+                std::thread::spawn(move || {
+                    while !done.load(Ordering::Acquire) {
+                        let keys = if reader == 0 {
+                            btree.iter().unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>()
+                        } else {
+                            let mut keys = btree.iter().unwrap().rev().map(|r| r.unwrap().0).collect::<Vec<_>>();
+                            keys.reverse();
+                            keys
+                        };
+                        // keys the writer never touches are all there, in order, exactly once
+                        assert!(keys.windows(2).all(|w| w[0] < w[1]));
+                        let stable = keys.iter().filter(|k| *k % 4 == 0).count();
+                        assert_eq!(stable, 2000);
+                    }
+                })
+            })
+            .collect();
+        for round in 0..2 {
+            for i in 0..2000 {
+                btree.set(&(i * 4 + 1 + round), &i).unwrap();
+            }
+            for i in 0..2000 {
+                assert_eq!(btree.remove(&(i * 4 + 1 + round)).unwrap(), Some(i));
+            }
+        }
+        done.store(true, Ordering::Release);
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        assert_eq!(btree.iter().unwrap().count(), 2000);
+        drop(btree);
+        remove_files(path);
+    }
+
+    fn wal_len(path: &str) -> u64 {
+        std::fs::metadata(Wal::path_for(path)).unwrap().len()
+    }
+
+    #[test]
+    fn test_wal_replays_after_crash() {
+        let path = temp_path("wal-crash");
+        let btree = BTree::<i32, i32>::new(path);
+        for i in 0..3000 {
+            btree.set(&i, &i).unwrap();
+        }
+        btree.checkpoint().unwrap();
+        assert_eq!(wal_len(path), 0);
+        // merges and a root change that only the log knows about
+        for i in 0..3000 {
+            if i % 3 != 0 {
+                btree.remove(&i).unwrap();
+            }
+        }
+        btree.set(&-1, &-1).unwrap();
+        assert!(wal_len(path) > 0);
+        // a crash: no page is written back and the log is left as it is
+        std::mem::forget(btree);
+
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(wal_len(path), 0);
+        let expected = std::iter::once(-1).chain((0..3000).filter(|i| i % 3 == 0)).collect::<Vec<_>>();
+        let keys = btree.iter().unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+        assert_eq!(btree.get(&1).unwrap(), None);
+        assert_eq!(btree.get(&2997).unwrap(), Some(2997));
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_wal_discards_torn_record() {
+        let path = temp_path("wal-torn");
+        let btree = BTree::<i32, i32>::new(path);
+        for i in 0..1000 {
+            btree.set(&i, &i).unwrap();
+        }
+        btree.checkpoint().unwrap();
+        btree.set(&1000, &1000).unwrap();
+        btree.set(&1001, &1001).unwrap();
+        std::mem::forget(btree);
+
+        // the crash hit while the last record was being written
+        let wal = OpenOptions::new().write(true).open(Wal::path_for(path)).unwrap();
+        wal.set_len(wal_len(path) - 100).unwrap();
+        drop(wal);
+
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(btree.get(&999).unwrap(), Some(999));
+        assert_eq!(btree.get(&1000).unwrap(), Some(1000));
+        assert_eq!(btree.get(&1001).unwrap(), None);
+        assert_eq!(btree.iter().unwrap().count(), 1001);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_free_pages_are_reused() {
+        let path = temp_path("free-pages");
+        let btree = BTree::<i32, i32>::new(path);
+        btree.bulk_load((0..5000).map(|i| (i, i)), 1.0).unwrap();
+        let total_pages = btree.meta_page.lock().total_pages();
+        for i in 0..5000 {
+            btree.remove(&i).unwrap();
+        }
+        // the free list is logged like any other change
+        std::mem::forget(btree);
+
+        let btree = BTree::<i32, i32>::new(path);
+        {
+            let meta_page = btree.meta_page.lock();
+            // everything but META and the empty root leaf
+            assert_eq!(meta_page.free_count(), total_pages as usize - 2);
+        }
+        btree.bulk_load((0..5000).map(|i| (i, -i)), 1.0).unwrap();
+        btree.set(&5000, &-5000).unwrap();
+        assert_eq!(btree.meta_page.lock().total_pages(), total_pages);
+        let items = btree.iter().unwrap().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(items, (0..=5000).map(|i| (i, -i)).collect::<Vec<_>>());
+        let back = btree.iter().unwrap().rev().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(back, (0..=5000).rev().collect::<Vec<_>>());
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_free_list_overflow() {
+        let path = temp_path("free-list-overflow");
+        let btree = BTree::<i32, i32>::new(path);
+        let mut indexes = (0..3000)
+            .map(|_| btree.allocate_page(PageType::LEAF, false).unwrap().read().index)
+            .collect::<Vec<_>>();
+        for index in &indexes {
+            btree.free_page(&mut btree.fetch_mut(*index).unwrap());
+        }
+        btree.commit().unwrap();
+        assert_eq!(btree.verify().unwrap().free_pages, 3000);
+        let total_pages = btree.meta_page.lock().total_pages();
+        drop(btree);
+
+        let btree = BTree::<i32, i32>::new(path);
+        {
+            // META took what it could hold, the rest went to pages on the list
+            let meta_page = btree.meta_page.lock();
+            assert!(meta_page.free_count() < meta_page.free_list_capacity());
+            let list_page = btree.fetch(meta_page.next_free_list().unwrap()).unwrap();
+            assert_eq!(list_page.read().page_type, PageType::FREELIST);
+        }
+        let mut reused = (0..3000)
+            .map(|_| btree.allocate_page(PageType::LEAF, false).unwrap().read().index)
+            .collect::<Vec<_>>();
+        indexes.sort();
+        reused.sort();
+        assert_eq!(reused, indexes);
+        assert_eq!(btree.meta_page.lock().next_free_list(), None);
+        assert_eq!(btree.meta_page.lock().total_pages(), total_pages);
+        drop(btree);
+        remove_files(path);
+    }
+
+    // the error loading the file at path would fail with
+    fn load_error<K, V>(path: &str) -> PageError
+    where
+        K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+        V: Encodable + Decodable + BinSizer + Debug + Clone,
+    {
+        let store: StoreRef = Arc::new(FileStore::new(OpenOptions::new().read(true).write(true).open(path).unwrap()));
+        let err = BTree::<K, V>::init_load(&store).expect_err("file should not load");
+        err.downcast::<PageError>().unwrap()
+    }
+
+    #[test]
+    fn test_format_header() {
+        let path = temp_path("format");
+        drop(BTree::<i32, i32>::new(path));
+        BTree::<i32, i32>::init_load(&(Arc::new(FileStore::new(File::open(path).unwrap())) as StoreRef)).unwrap();
+        assert!(matches!(
+            load_error::<i64, i32>(path),
+            PageError::LayoutMismatch { field: "key size", found: 4, expected: 8 }
+        ));
+        assert!(matches!(
+            load_error::<i32, i64>(path),
+            PageError::LayoutMismatch { field: "value size", found: 4, expected: 8 }
+        ));
+
+        std::fs::write(path, "account,balance\n".repeat(1000)).unwrap();
+        assert!(matches!(load_error::<i32, i32>(path), PageError::BadMagic));
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_corrupted_page() {
+        let path = temp_path("corrupted");
+        let btree = BTree::<i32, i32>::new(path);
+        btree.bulk_load((0..5000).map(|i| (i, i)), 1.0).unwrap();
+        let leaf = btree.seek_leaf(Some(&2500), false).unwrap().read().index;
+        drop(btree);
+
+        // flip one bit in a balance
+        let mut bytes = std::fs::read(path).unwrap();
+        bytes[leaf as usize * PAGE_SIZE + 2000] ^= 0x10;
+        std::fs::write(path, &bytes).unwrap();
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(btree.get(&0).unwrap(), Some(0));
+        let err = btree.range(2500..).unwrap().next().unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref::<PageError>(), Some(PageError::Corrupted(i)) if *i == leaf));
+        let err = btree.get(&2500).unwrap_err();
+        assert!(matches!(err.downcast_ref::<PageError>(), Some(PageError::Corrupted(i)) if *i == leaf));
+        drop(btree);
+
+        // everything after the root leaf of an empty tree is cut off
+        let btree = BTree::<i32, i32>::new(path);
+        OpenOptions::new().write(true).open(path).unwrap().set_len(2 * PAGE_SIZE as u64).unwrap();
+        let err = btree.range(2500..).unwrap().next().unwrap().unwrap_err();
+        assert!(matches!(err.downcast_ref::<PageError>(), Some(PageError::Truncated(i)) if *i >= 2));
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_open_options() {
+        // any path will do, not only a &'static str
+        let path = std::path::PathBuf::from(temp_path("open"));
+        assert!(BTree::<i32, i32>::open(&path, Options::default().create(false)).is_err());
+        assert!(BTree::<i32, i32>::open(&path, Options::default().read_only(true)).is_err());
+
+        let btree = BTree::<i32, i32>::open(&path, Options::default().create_new(true).sync(SyncMode::Off)).unwrap();
+        assert_eq!(btree.path(), path);
+        for i in 0..100 {
+            btree.set(&i, &i).unwrap();
+        }
+        std::mem::forget(btree);
+        assert!(BTree::<i32, i32>::open(&path, Options::default().create_new(true)).is_err());
+        // the log has to be replayed by a writer first
+        let read_only = Options::default().read_only(true);
+        assert!(BTree::<i32, i32>::open(&path, read_only.clone()).is_err());
+        drop(BTree::<i32, i32>::open(&path, Options::default().create(false)).unwrap());
+
+        let btree = BTree::<i32, i32>::open(&path, read_only).unwrap();
+        assert!(btree.is_read_only());
+        assert_eq!(btree.get(&42).unwrap(), Some(42));
+        assert_eq!(btree.iter().unwrap().count(), 100);
+        assert!(btree.set(&100, &100).is_err());
+        assert!(btree.remove(&42).is_err());
+        assert!(btree.checkpoint().is_err());
+        assert_eq!(btree.get(&42).unwrap(), Some(42));
+        drop(btree);
+        assert_eq!(wal_len(path.to_str().unwrap()), 0);
+        remove_files(path.to_str().unwrap());
+    }
+
+    // IBAN-like account numbers of different lengths
+    fn iban(i: usize) -> String {
+        format!("DE{:02}{}", i % 97, "0123456789".repeat(1 + i % 3)) + &i.to_string()
+    }
+
+    // a value of i % 50 * 97 bytes, some of them spill into overflow pages
+    fn record(i: usize) -> String {
+        format!("{:08}", i).repeat(i % 50 * 97 / 8 + 1)[..(i % 50 * 97).max(8)].to_owned()
+    }
+
+    #[test]
+    fn test_variable_length_keys_and_values() {
+        let path = temp_path("variable-length");
+        let btree = BTree::<String, String>::new(path);
+        let n = 3000;
+        for i in (0..n).map(|i| i * 1237 % n) {
+            btree.set(&iban(i), &record(i)).unwrap();
+        }
+        for i in (0..n).step_by(7) {
+            assert_eq!(btree.get(&iban(i)).unwrap(), Some(record(i)));
+        }
+        let mut expected: Vec<usize> = (0..n).collect();
+        expected.sort_by_key(|i| iban(*i));
+        let keys = btree.iter().unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(keys, expected.iter().map(|i| iban(*i)).collect::<Vec<_>>());
+
+        // values change size in place, from inline to overflow and back
+        for i in (0..n).step_by(3) {
+            btree.set(&iban(i), &record(i + 25)).unwrap();
+        }
+        for i in 0..n {
+            if i % 2 == 0 {
+                let expected = if i % 3 == 0 { record(i + 25) } else { record(i) };
+                assert_eq!(btree.remove(&iban(i)).unwrap(), Some(expected));
+            }
+        }
+        drop(btree);
+
+        let btree = BTree::<String, String>::new(path);
+        for i in 0..n {
+            let expected = match i {
+                _ if i % 2 == 0 => None,
+                _ if i % 3 == 0 => Some(record(i + 25)),
+                _ => Some(record(i)),
+            };
+            assert_eq!(btree.get(&iban(i)).unwrap(), expected);
+        }
+        let back = btree.iter().unwrap().rev().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert_eq!(back.len(), n / 2);
+        assert!(back.windows(2).all(|w| w[0] > w[1]));
+        assert_healthy(&btree);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_overflow_pages_are_freed() {
+        let path = temp_path("overflow");
+        let btree = BTree::<i32, Vec<u8>>::new(path);
+        let large = (0..20000).map(|i| i as u8).collect::<Vec<_>>();
+        btree.set(&1, &vec![1; 10]).unwrap();
+        btree.set(&2, &large).unwrap();
+        // 20004 bytes over pages of a little over 4000
+        let total_pages = btree.meta_page.lock().total_pages();
+        assert_eq!(total_pages, 2 + 5);
+        assert_eq!(btree.get(&2).unwrap(), Some(large.clone()));
+        assert_eq!(btree.range(2..).unwrap().next().unwrap().unwrap(), (2, large.clone()));
+
+        // replacing the value frees its pages and the next large value reuses them
+        btree.set(&2, &vec![2; 10]).unwrap();
+        assert_eq!(btree.meta_page.lock().free_count(), 5);
+        btree.set(&3, &large).unwrap();
+        assert_eq!(btree.meta_page.lock().total_pages(), total_pages);
+        assert_eq!(btree.remove(&3).unwrap(), Some(large));
+        assert_eq!(btree.meta_page.lock().free_count(), 5);
+        assert_eq!(btree.get(&2).unwrap(), Some(vec![2; 10]));
+        assert_healthy(&btree);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_key_size_limit() {
+        let path = temp_path("key-size");
+        let btree = BTree::<String, i32>::new(path);
+        let long = "x".repeat(super::super::page::MAX_KEY_SIZE);
+        let err = btree.set(&long, &1).unwrap_err();
+        assert!(matches!(err.downcast_ref::<PageError>(), Some(PageError::KeyTooLarge { .. })));
+        // the length prefix counts too
+        let longest = "x".repeat(super::super::page::MAX_KEY_SIZE - 4);
+        btree.set(&longest, &1).unwrap();
+        assert_eq!(btree.get(&longest).unwrap(), Some(1));
+        assert_eq!(btree.iter().unwrap().count(), 1);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_bulk_load_variable_length() {
+        let path = temp_path("bulk-load-variable");
+        let btree = BTree::<String, String>::new(path);
+        let mut items = (0..5000).map(|i| (iban(i), record(i))).collect::<Vec<_>>();
+        items.sort();
+        assert_eq!(btree.bulk_load(items.clone(), 0.9).unwrap(), 5000);
+        for (key, value) in items.iter().step_by(11) {
+            assert_eq!(btree.get(key).unwrap().as_ref(), Some(value));
+        }
+        let loaded = btree.iter().unwrap().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(loaded, items);
+        btree.set(&iban(5000), &record(5000)).unwrap();
+        assert_eq!(btree.remove(&items[0].0).unwrap(), Some(items[0].1.clone()));
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.keys, 5000);
+        assert!(report.overflow_pages > 0);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[derive(Debug, Clone, PartialEq, BinSizer, Encodable, Decodable)]
+    struct Balance {
+        amount: i64,
+        version: u32,
+    }
+
+    #[test]
+    fn test_derived_value() {
+        let path = temp_path("derived-value");
+        let btree = BTree::<i32, Balance>::new(path);
+        for i in 0..2000 {
+            btree.set(&i, &Balance { amount: i as i64 * 100, version: 1 }).unwrap();
+        }
+        drop(btree);
+        let btree = BTree::<i32, Balance>::new(path);
+        assert_eq!(btree.get(&1999).unwrap(), Some(Balance { amount: 199900, version: 1 }));
+        assert_eq!(btree.iter().unwrap().count(), 2000);
+        drop(btree);
+        // the header records the size of the value
+        let err = load_error::<i32, i32>(path);
+        assert!(matches!(err, PageError::LayoutMismatch { .. }), "{:?}", err);
+        remove_files(path);
+    }
+
+    // problems verify finds in a tree of 3000 keys after f damages it
+    fn verify_damaged(name: &str, f: impl FnOnce(&BTree<i32, i32>)) -> Vec<Problem> {
+        let path = temp_path(name);
+        let btree = BTree::<i32, i32>::new(path);
+        btree.bulk_load((0..3000).map(|i| (i, i)), 1.0).unwrap();
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.keys, 3000);
+        assert_eq!(report.depth, 2);
+        assert_eq!(report.total_pages as usize, 1 + report.internal_pages + report.leaf_pages + report.free_pages);
+        f(&btree);
+        let problems = btree.verify().unwrap().problems;
+        drop(btree);
+        remove_files(path);
+        problems
+    }
+
+    fn has_problem(problems: &[Problem], page: u32, message: &str) -> bool {
+        problems.iter().any(|p| p.page == page && p.message.contains(message))
+    }
+
+    #[test]
+    fn test_verify_finds_problems() {
+        let mut first_leaf = 0;
+        let problems = verify_damaged("verify-order", |btree| {
+            let leaf = btree.seek_leaf(None, false).unwrap();
+            let mut leaf = leaf.write();
+            first_leaf = leaf.index;
+            let mut records: Vec<Vec<u8>> = (0..leaf.item_count()).map(|i| leaf.record_at(i).to_vec()).collect();
+            records.swap(3, 4);
+            leaf.set_records(&records).unwrap();
+        });
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(has_problem(&problems, first_leaf, "keys out of order at slot 4: 3 after 4"));
+
+        let problems = verify_damaged("verify-separator", |btree| {
+            let leaf = btree.seek_leaf(None, true).unwrap();
+            let mut leaf = leaf.write();
+            first_leaf = leaf.index;
+            let mut records: Vec<Vec<u8>> = (0..leaf.item_count()).map(|i| leaf.record_at(i).to_vec()).collect();
+            records[0] = Page::<i32, i32>::encode_key(&0).unwrap();
+            records[0].extend(0i32.to_be_bytes());
+            leaf.set_records(&records).unwrap();
+        });
+        assert!(has_problem(&problems, first_leaf, "key 0 is below"), "{:?}", problems);
+        assert!(has_problem(&problems, first_leaf, "first key 0 is not above 2"), "{:?}", problems);
+
+        let problems = verify_damaged("verify-chain", |btree| {
+            let leaf = btree.seek_leaf(None, false).unwrap();
+            let mut leaf = leaf.write();
+            first_leaf = leaf.index;
+            leaf.set_next_leaf(None);
+        });
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(has_problem(&problems, first_leaf, "next leaf is None"));
+
+        let mut total_pages = 0;
+        let problems = verify_damaged("verify-orphan", |btree| {
+            let mut meta_page = btree.meta_page.lock();
+            total_pages = meta_page.total_pages();
+            meta_page.set_total_page(total_pages + 1);
+        });
+        assert_eq!(problems.len(), 2, "{:?}", problems);
+        assert!(has_problem(&problems, 0, "META counts"));
+        assert!(has_problem(&problems, total_pages, "orphaned"));
+
+        let mut second_leaf = 0;
+        let problems = verify_damaged("verify-twice", |btree| {
+            let root = btree.root().unwrap();
+            let mut root = root.write();
+            first_leaf = root.ptr_at(0).unwrap();
+            second_leaf = root.ptr_at(1).unwrap();
+            root.set_ptr_at(1, first_leaf).unwrap();
+        });
+        assert!(has_problem(&problems, first_leaf, "reachable twice"), "{:?}", problems);
+        assert!(has_problem(&problems, second_leaf, "orphaned"), "{:?}", problems);
+
+        let problems = verify_damaged("verify-key-count", |btree| {
+            btree.meta_page.lock().set_key_count(2999);
+        });
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(has_problem(&problems, 0, "META counts 2999 keys"));
+
+        let problems = verify_damaged("verify-range", |btree| {
+            btree.meta_page.lock().push_free(100000).unwrap();
+        });
+        assert_eq!(problems.len(), 1, "{:?}", problems);
+        assert!(has_problem(&problems, 0, "links to page 100000"));
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = temp_path("compact");
+        let btree = BTree::<i32, String>::new(path);
+        // random inserts leave the leaves about half full
+        for i in 0..3000 {
+            let key = (i * 7919) % 3000;
+            btree.set(&key, &key.to_string()).unwrap();
+        }
+        for i in (0..3000).filter(|i| i % 3 != 0) {
+            btree.remove(&i).unwrap();
+        }
+        btree.set(&5000, &"z".repeat(6000)).unwrap();
+        let before = btree.iter().unwrap().map(|r| r.unwrap()).collect::<Vec<_>>();
+        drop(btree);
+
+        assert!(BTree::<i32, String>::compact(path, 1.5).is_err());
+        assert!(BTree::<i32, String>::compact(path, 0.3).is_err());
+        assert!(!Path::new(&format!("{}.compact", path)).exists());
+
+        let report = BTree::<i32, String>::compact(path, 1.0).unwrap();
+        assert_eq!(report.keys, before.len());
+        assert!(report.new_pages < report.old_pages, "{:?}", report);
+        assert!(report.new_bytes < report.old_bytes, "{:?}", report);
+        assert_eq!(report.new_bytes, report.new_pages as u64 * PAGE_SIZE as u64);
+        assert_eq!(std::fs::metadata(path).unwrap().len(), report.new_bytes);
+        assert!(!Path::new(&format!("{}.compact", path)).exists());
+        assert!(!Wal::path_for(format!("{}.compact", path)).exists());
+
+        let btree = BTree::<i32, String>::open(path, Options::default().read_only(true)).unwrap();
+        assert_eq!(btree.iter().unwrap().map(|r| r.unwrap()).collect::<Vec<_>>(), before);
+        let verify = btree.verify().unwrap();
+        assert!(verify.is_ok(), "{}", verify);
+        assert_eq!(verify.total_pages, report.new_pages);
+        assert!(verify.overflow_pages > 0);
+        drop(btree);
+        remove_files(path);
+
+        assert!(BTree::<i32, String>::compact(path, 1.0).is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let path = temp_path("stats");
+        let btree = BTree::<i32, i32>::new(path);
+        for i in 0..4000 {
+            btree.set(&((i * 7919) % 4000), &i).unwrap();
+        }
+        btree.set(&0, &-1).unwrap();
+        for i in (0..4000).step_by(4) {
+            assert!(btree.remove(&i).unwrap().is_some());
+        }
+        assert_eq!(btree.remove(&0).unwrap(), None);
+        // the count is logged with every operation and survives a crash
+        std::mem::forget(btree);
+
+        let btree = BTree::<i32, i32>::new(path);
+        let stats = btree.stats().unwrap();
+        assert_eq!(stats.keys, 3000);
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(stats.height, report.depth);
+        assert_eq!(stats.internal_pages, report.internal_pages);
+        assert_eq!(stats.leaf_pages, report.leaf_pages);
+        assert_eq!(stats.levels.len(), stats.height);
+        assert_eq!(stats.levels.last().unwrap().keys, 3000);
+        assert_eq!(stats.file_bytes, stats.total_pages as u64 * PAGE_SIZE as u64);
+        drop(btree);
+
+        let empty = temp_path("stats-empty");
+        let btree = BTree::<i32, i32>::new(empty);
+        let stats = btree.stats().unwrap();
+        assert_eq!((stats.height, stats.internal_pages, stats.leaf_pages, stats.keys), (1, 0, 1, 0));
+        drop(btree);
+        remove_files(empty);
+
+        let report = BTree::<i32, i32>::compact(path, 1.0).unwrap();
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(btree.stats().unwrap().keys, report.keys as u64);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_update() {
+        let path = temp_path("update");
+        let btree = BTree::<i32, String>::new(path);
+        assert_eq!(btree.update(&1, |old| old).unwrap(), None);
+        for i in 0..3000 {
+            let new = btree.update(&i, |old| {
+                assert_eq!(old, None);
+                Some(i.to_string())
+            });
+            assert_eq!(new.unwrap(), Some(i.to_string()));
+        }
+        for i in (0..3000).step_by(3) {
+            btree.update(&i, |old| old.map(|old| old + "!")).unwrap();
+        }
+        // grows into overflow pages and back, the overflow pages are freed
+        btree.update(&7, |_| Some("x".repeat(5000))).unwrap();
+        let total_pages = btree.meta_page.lock().total_pages();
+        btree.update(&7, |old| Some(old.unwrap()[..2].to_string())).unwrap();
+        assert_eq!(btree.meta_page.lock().free_count(), 2);
+        assert_eq!(btree.meta_page.lock().total_pages(), total_pages);
+        // removing most keys underflows leaves and merges them
+        for i in (0..3000).filter(|i| i % 10 != 0) {
+            assert!(btree.update(&i, |_| None).unwrap().is_none());
+        }
+        assert_healthy(&btree);
+        assert_eq!(btree.meta_page.lock().key_count(), 300);
+        for i in (0..3000).step_by(10) {
+            let expected = if i % 3 == 0 { format!("{}!", i) } else { i.to_string() };
+            assert_eq!(btree.get(&i).unwrap(), Some(expected));
+        }
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_concurrent_update() {
+        let path = temp_path("concurrent-update");
+        let btree = Arc::new(BTree::<i32, i64>::open(path, Options::default().sync(SyncMode::Off)).unwrap());
+        let mut pool = Pool::new(4);
+        for _ in 0..4 {
+            let btree = btree.clone();
+            pool.execute(
+                move || {
+                    for i in 0..500 {
+                        btree.update(&(i % 50), |old| Some(old.unwrap_or(0) + 1)).unwrap();
+                    }
+                },
+                false,
+            );
+        }
+        drop(pool);
+        for i in 0..50 {
+            assert_eq!(btree.get(&i).unwrap(), Some(40));
+        }
+        assert_healthy(&btree);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_get_many() {
+        let path = temp_path("get-many");
+        let btree = BTree::<i32, i32>::new(path);
+        btree.bulk_load((0..20000).map(|i| (i * 2, i)), 1.0).unwrap();
+        assert_eq!(btree.get_many(&[]).unwrap(), vec![]);
+        let keys: Vec<i32> = (0..5000).map(|i| (i * 7919) % 40001).chain([6, 6, -1]).collect();
+        let values = btree.get_many(&keys).unwrap();
+        for (key, value) in keys.iter().zip(&values) {
+            assert_eq!(*value, btree.get(key).unwrap());
+        }
+        assert_eq!(values[values.len() - 3..], [Some(3), Some(3), None]);
+
+        // every key: each leaf once, with the pages above it on the way down
+        let levels = btree.levels().unwrap();
+        let before = btree.pool_stats();
+        let keys: Vec<i32> = (0..40000).rev().collect();
+        assert_eq!(btree.get_many(&keys).unwrap().iter().flatten().count(), 20000);
+        let after = btree.pool_stats();
+        let fetches = after.hits + after.misses - before.hits - before.misses;
+        assert_eq!(fetches as usize, levels.last().unwrap().pages * levels.len());
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_get_many_lets_writers_in() {
+        let path = temp_path("get-many-writers");
+        let btree = Arc::new(BTree::<i32, i32>::new(path));
+        btree.bulk_load((0..20000).map(|i| (i * 2, i)), 1.0).unwrap();
+        let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+        let reader = {
+            let (btree, done) = (btree.clone(), done.clone());
+            std::thread::spawn(move || {
+                let keys: Vec<i32> = (0..500_000).map(|i| i % 40000).collect();
+                let found = btree.get_many(&keys).unwrap().iter().flatten().count();
+                done.store(true, Ordering::Release);
+                found
+            })
+        };
+        std::thread::sleep(std::time::Duration::from_millis(50));
+        // the root is not held for the whole batch, a write goes in while it runs
+        for i in 0..10 {
+            btree.set(&(i * 4000 + 1), &-1).unwrap();
+        }
+        assert!(!done.load(Ordering::Acquire), "the writes waited for get_many");
+        let found = reader.join().unwrap();
+        assert!((250_000..=250_500).contains(&found));
+        assert_healthy(&btree);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_set_many() {
+        let path = temp_path("set-many");
+        let btree = BTree::<i32, String>::new(path);
+        btree.set_many(&[]).unwrap();
+        let items: Vec<(i32, String)> = (0..6000).map(|i| ((i * 7919) % 6000, i.to_string())).collect();
+        btree.set_many(&items).unwrap();
+        assert_healthy(&btree);
+
+        // replaces, inserts, a key given twice and values moving in and out of overflow pages
+        let mut items: Vec<(i32, String)> = (0..6000).step_by(5).map(|i| (i, format!("{}!", i))).collect();
+        items.extend((6000..6100).map(|i| (i, i.to_string())));
+        items.push((10, "y".repeat(5000)));
+        items.push((10, "z".repeat(6000)));
+        items.push((20, "short".to_string()));
+        btree.set_many(&items).unwrap();
+        assert_eq!(btree.get(&10).unwrap(), Some("z".repeat(6000)));
+        assert_eq!(btree.get(&20).unwrap(), Some("short".to_string()));
+        assert_eq!(btree.get(&25).unwrap(), Some("25!".to_string()));
+        assert_eq!(btree.get(&6050).unwrap(), Some("6050".to_string()));
+        assert_eq!(btree.meta_page.lock().key_count(), 6100);
+        // the first long value of key 10 went back to the free list
+        assert_eq!(btree.meta_page.lock().free_count(), 2);
+        assert_healthy(&btree);
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_bloom_filter() {
+        let path = temp_path("bloom");
+        let bloom_path = BloomFilter::path_for(path);
+        let _ = std::fs::remove_file(&bloom_path);
+        let options = Options::default().sync(SyncMode::Off);
+        assert!(BTree::<i32, i32>::open(path, options.clone().bloom_filter(1.0)).is_err());
+        let btree = BTree::<i32, i32>::open(path, options.clone().bloom_filter(0.01)).unwrap();
+        // grows past the capacity it starts with and is rebuilt
+        for i in 0..3000 {
+            btree.set(&(i * 2), &i).unwrap();
+        }
+        btree.set_many(&[(6000, 1), (6002, 2)]).unwrap();
+        btree.update(&6004, |_| Some(3)).unwrap();
+        let fetches = |btree: &BTree<i32, i32>| {
+            let stats = btree.pool_stats();
+            stats.hits + stats.misses
+        };
+        let before = fetches(&btree);
+        assert!((0..3000).all(|i| btree.get(&(i * 2 + 1)).unwrap().is_none()));
+        // about 1% of the missing keys still go down the tree, two pages each
+        assert!(fetches(&btree) - before < 200, "{} pages read", fetches(&btree) - before);
+        assert_eq!(btree.get_many(&[1, 6002, 6004]).unwrap(), vec![None, Some(2), Some(3)]);
+        btree.remove(&0).unwrap();
+        assert_eq!(btree.get(&0).unwrap(), None);
+        drop(btree);
+
+        // kept next to the file and loaded without asking for it
+        let btree = BTree::<i32, i32>::open(path, Options::default().read_only(true)).unwrap();
+        assert_eq!(btree.bloom_fp_rate(), Some(0.01));
+        assert!((1..3000).all(|i| btree.get(&(i * 2)).unwrap() == Some(i)));
+        assert_eq!(btree.get_many(&[6000, 6002, 6004]).unwrap(), vec![Some(1), Some(2), Some(3)]);
+        drop(btree);
+
+        // a filter that was not closed cleanly may miss keys and is rebuilt
+        let btree = BTree::<i32, i32>::open(path, options.clone()).unwrap();
+        btree.set(&7001, &7001).unwrap();
+        std::mem::forget(btree);
+        assert!(!BloomFilter::load(&bloom_path).unwrap().unwrap().is_clean());
+        let btree = BTree::<i32, i32>::open(path, options.clone()).unwrap();
+        assert_eq!(btree.get(&7001).unwrap(), Some(7001));
+        assert!((1..3000).all(|i| btree.get(&(i * 2)).unwrap() == Some(i)));
+        drop(btree);
+
+        // another rate rebuilds it
+        let btree = BTree::<i32, i32>::open(path, options.bloom_filter(0.001)).unwrap();
+        assert_eq!(btree.bloom_fp_rate(), Some(0.001));
+        assert_eq!(btree.get(&7001).unwrap(), Some(7001));
+        drop(btree);
+        BTree::<i32, i32>::compact(path, 1.0).unwrap();
+        let btree = BTree::<i32, i32>::new(path);
+        assert_eq!(btree.bloom_fp_rate(), Some(0.001));
+        assert!((1..3000).all(|i| btree.get(&(i * 2)).unwrap() == Some(i)));
+        assert!(!BloomFilter::path_for(format!("{}.compact", path)).exists());
+        drop(btree);
+        remove_files(path);
+        std::fs::remove_file(bloom_path).unwrap();
+    }
+
+    #[test]
+    fn test_memory_store() {
+        let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+        let open = |options: Options| BTree::<i32, i32>::open_in("memory", data.clone(), wal.clone(), options);
+        assert!(open(Options::default().create(false)).is_err());
+        let btree = open(Options::default()).unwrap();
+        for i in 0..5000 {
+            btree.set(&i, &-i).unwrap();
+        }
+        for i in (0..5000).step_by(2) {
+            btree.remove(&i).unwrap();
+        }
+        assert_healthy(&btree);
+        std::mem::forget(btree);
+
+        assert!(open(Options::default().create_new(true)).is_err());
+        assert!(open(Options::default().read_only(true)).is_err());
+        assert!(open(Options::default().bloom_filter(0.01)).is_err());
+        drop(open(Options::default()).unwrap());
+        let btree = open(Options::default().read_only(true)).unwrap();
+        assert_eq!(btree.get(&4999).unwrap(), Some(-4999));
+        assert_eq!(btree.get(&4998).unwrap(), None);
+        assert_eq!(btree.stats().unwrap().keys, 2500);
+        assert_eq!(btree.stats().unwrap().file_bytes, data.bytes().len() as u64);
+    }
+
+    #[test]
+    fn test_mmap() {
+        let path = temp_path("mmap");
+        let options = Options::default().mmap(true).pool_capacity(16);
+        let btree = BTree::<i32, i32>::open(path, options.clone()).unwrap();
+        btree.bulk_load((0..20000).map(|i| (i, i)), 0.7).unwrap();
+        for i in 20000..30000 {
+            btree.set(&i, &i).unwrap();
+        }
+        for i in (0..30000).step_by(3) {
+            btree.remove(&i).unwrap();
+        }
+        assert_healthy(&btree);
+        std::mem::forget(btree);
+
+        // the log is replayed through the mapping
+        let btree = BTree::<i32, i32>::open(path, options.clone()).unwrap();
+        assert_eq!(btree.get(&29999).unwrap(), Some(29999));
+        assert_eq!(btree.get(&29997).unwrap(), None);
+        drop(btree);
+        for (options, mmap) in [(options.read_only(true), true), (Options::default().read_only(true), false)] {
+            let btree = BTree::<i32, i32>::open(path, options).unwrap();
+            assert_healthy(&btree);
+            assert_eq!(btree.stats().unwrap().keys, 20000);
+            assert!(btree.set(&0, &0).is_err());
+            // every page is lent by the mapping, none is read and copied
+            let stats = btree.pool_stats();
+            assert!(stats.misses > 0);
+            assert_eq!(stats.mapped, if mmap { stats.misses } else { 0 });
+        }
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_group_commit() {
+        use std::time::Duration;
+        let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+        let open = |ops: usize, interval: Duration| {
+            let options = Options::default().sync(SyncMode::Group { ops, interval });
+            BTree::<i32, i32>::open_in("group", data.clone(), wal.clone(), options)
+        };
+        assert!(open(0, Duration::from_millis(10)).is_err());
+        assert!(open(10, Duration::ZERO).is_err());
+
+        let btree = open(3, Duration::from_secs(3600)).unwrap();
+        btree.set(&1, &1).unwrap();
+        btree.remove(&1).unwrap();
+        assert_eq!(btree.wal().unsynced(), 2);
+        btree.set(&2, &2).unwrap();
+        assert_eq!(btree.wal().unsynced(), 0);
+        btree.set(&3, &3).unwrap();
+        btree.checkpoint().unwrap();
+        assert_eq!(btree.wal().unsynced(), 0);
+        drop(btree);
+
+        // nothing follows the last operation, the flusher syncs it
+        let btree = open(1000, Duration::from_millis(20)).unwrap();
+        btree.set(&4, &4).unwrap();
+        assert_eq!(btree.wal().unsynced(), 1);
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(btree.wal().unsynced(), 0);
+        assert_eq!(btree.iter().unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>(), vec![2, 3, 4]);
+    }
+
+    #[test]
+    fn test_log_synced_before_write_back() {
+        use std::time::Duration;
+        let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+        let options = Options::default().pool_capacity(8);
+        let btree = BTree::<i32, i32>::open_in("write-back", data.clone(), wal.clone(), options.clone()).unwrap();
+        btree.bulk_load((0..3000).map(|i| (i, 0)), 1.0).unwrap();
+        drop(btree);
+
+        // the log takes 200 records, then it can neither be written nor fsynced again
+        let trigger = FaultTrigger::new(Fault::Fail, 200);
+        let log = Arc::new(FaultyStore::new(wal.clone(), trigger.clone()));
+        let options = options.sync(SyncMode::Group { ops: 1000, interval: Duration::from_secs(3600) });
+        let btree = BTree::<i32, i32>::open_in("write-back", data.clone(), log, options.clone()).unwrap();
+        for i in 0..200 {
+            btree.set(&(i * 37 % 3000), &i).unwrap();
+        }
+        assert!(btree.wal().unsynced() > 0);
+        let written = data.bytes();
+        assert!(btree.set(&1, &1).is_err());
+        assert!(trigger.has_fired());
+
+        // evictions, checkpoints and dropping the tree would all write back pages whose records
+        // are not durable
+        assert!((0..3000).map(|i| btree.get(&i)).any(|value| value.is_err()));
+        assert!(btree.checkpoint().is_err());
+        assert!(btree.verify().is_err());
+        drop(btree);
+        assert!(data.bytes() == written, "a page was written back before its record was durable");
+
+        // what did reach the log is replayed
+        let btree = BTree::<i32, i32>::open_in("write-back", data, wal, options).unwrap();
+        assert_healthy(&btree);
+        assert_eq!(btree.get(&(199 * 37 % 3000)).unwrap(), Some(199));
+    }
+
+    #[test]
+    fn test_backup() {
+        let path = temp_path("backup");
+        let backup_path = temp_path("backup-copy");
+        let btree = BTree::<i32, i32>::open(path, Options::default().sync(SyncMode::Off)).unwrap();
+        btree.bulk_load((0..20000).map(|i| (i, i)), 0.8).unwrap();
+        let done = std::sync::atomic::AtomicBool::new(false);
+        let report = std::thread::scope(|scope| {
+            scope.spawn(|| {
+                let mut i = 20000;
+                while !done.load(Ordering::Relaxed) || i < 25000 {
+                    btree.set(&i, &i).unwrap();
+                    i += 1;
+                }
+            });
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            let report = btree.backup_to(backup_path).unwrap();
+            done.store(true, Ordering::Relaxed);
+            report
+        });
+        assert_healthy(&btree);
+
+        // the keys were set in order, a copy of one point in time holds a prefix of them
+        let copy = BTree::<i32, i32>::open(backup_path, Options::default().read_only(true)).unwrap();
+        let keys = copy.iter().unwrap().map(|r| r.unwrap().0).collect::<Vec<_>>();
+        assert!(keys.len() >= 20000 && keys.len() == report.keys);
+        assert!(keys.iter().enumerate().all(|(i, key)| *key == i as i32));
+        assert_eq!(copy.stats().unwrap().keys, keys.len() as u64);
+
+        // an existing file is left alone
+        assert!(btree.backup_to(backup_path).is_err());
+        assert_eq!(copy.iter().unwrap().count(), keys.len());
+        drop(copy);
+        drop(btree);
+        remove_files(path);
+        std::fs::remove_file(backup_path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshot() {
+        // every 50th value spills into overflow pages
+        let value = |i: i32, round: u8| vec![round.wrapping_add(i as u8); if i % 50 == 0 { 9000 } else { 8 }];
+        let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+        let options = Options::default().sync(SyncMode::Off).pool_capacity(32);
+        let btree = BTree::<i32, Vec<u8>>::open_in("snapshot", data, wal, options).unwrap();
+        for i in 0..3000 {
+            btree.set(&i, &value(i, 0)).unwrap();
+        }
+        let before: Vec<(i32, Vec<u8>)> = (0..3000).map(|i| (i, value(i, 0))).collect();
+        let root_index = btree.meta_page.lock().root_index();
+
+        let snapshot = btree.snapshot().unwrap();
+        assert_eq!(snapshot.len(), 3000);
+        let done = std::sync::atomic::AtomicBool::new(false);
+        std::thread::scope(|scope| {
+            scope.spawn(|| {
+                for i in (0..3000).rev() {
+                    btree.set(&i, &value(i, 1)).unwrap();
+                }
+                for i in (0..3000).step_by(3) {
+                    btree.remove(&i).unwrap();
+                }
+                for i in 3000..6000 {
+                    btree.set(&i, &value(i, 1)).unwrap();
+                }
+                btree.update(&1, |_| Some(value(1, 1))).unwrap();
+                btree.set_many(&[(2, value(2, 1)), (4, value(4, 1))]).unwrap();
+                done.store(true, Ordering::Release);
+            });
+            while !done.load(Ordering::Acquire) {
+                let items: Vec<(i32, Vec<u8>)> = snapshot.iter().unwrap().map(|r| r.unwrap()).collect();
+                assert!(items == before, "the snapshot changed");
+                assert_eq!(snapshot.get(&2999).unwrap(), Some(value(2999, 0)));
+            }
+        });
+        // the writes went to copies under a new root, the pages the snapshot reads are kept
+        assert_ne!(btree.meta_page.lock().root_index(), root_index);
+        let kept = snapshot.kept_pages();
+        assert!(kept > 0);
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.retired_pages, kept);
+        let items: Vec<(i32, Vec<u8>)> = snapshot.range(1000..=1100).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(items, before[1000..=1100]);
+        let items: Vec<(i32, Vec<u8>)> = snapshot.range((Bound::Excluded(2990), Bound::Unbounded)).unwrap().map(|r| r.unwrap()).collect();
+        assert_eq!(items, before[2991..]);
+        assert_eq!(snapshot.get(&0).unwrap(), Some(value(0, 0)));
+        assert_eq!(snapshot.get(&3000).unwrap(), None);
+        assert_eq!(btree.get(&0).unwrap(), None);
+        assert_eq!(btree.get(&1).unwrap(), Some(value(1, 1)));
+        assert_eq!(btree.iter().unwrap().count(), 5000);
+
+        // a later snapshot sees the later tree. Dropping the first frees the pages only it read
+        let later = btree.snapshot().unwrap();
+        assert_eq!(later.len(), 5000);
+        drop(snapshot);
+        assert_eq!(btree.snapshots.lock().retired.len(), 0);
+        btree.set(&1, &value(1, 2)).unwrap();
+        btree.remove(&5999).unwrap();
+        assert_eq!(btree.snapshots.lock().live.len(), 1);
+        assert!(later.kept_pages() > 0);
+        assert_eq!(later.get(&1).unwrap(), Some(value(1, 1)));
+        assert_eq!(later.iter().unwrap().count(), 5000);
+        drop(later);
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.retired_pages, 0);
+        assert_eq!(btree.meta_page.lock().retired_pages(), 0);
+        assert!(report.free_pages >= kept);
+        assert_eq!(btree.get(&1).unwrap(), Some(value(1, 2)));
+    }
+
+    #[test]
+    fn test_snapshot_pages_freed_after_crash() {
+        let path = temp_path("snapshot-crash");
+        let btree = BTree::<i32, i32>::new(path);
+        btree.bulk_load((0..20000).map(|i| (i, i)), 1.0).unwrap();
+        let snapshot = btree.snapshot().unwrap();
+        assert!(btree.bulk_load([(1, 1)], 1.0).is_err());
+        for i in (0..20000).step_by(7) {
+            btree.set(&i, &-i).unwrap();
+        }
+        for i in (1..20000).step_by(7) {
+            btree.remove(&i).unwrap();
+        }
+        assert!(snapshot.iter().unwrap().map(|r| r.unwrap()).eq((0..20000).map(|i| (i, i))));
+        assert_eq!(snapshot.get(&7).unwrap(), Some(7));
+        assert_eq!(btree.get(&7).unwrap(), Some(-7));
+        let kept = snapshot.kept_pages();
+        assert!(kept > 0);
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.retired_pages, kept);
+
+        // the process stops with the snapshot live, the next open finds the pages kept for it
+        std::mem::forget(snapshot);
+        std::mem::forget(btree);
+        let btree = BTree::<i32, i32>::new(path);
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{}", report);
+        assert_eq!(report.retired_pages, 0);
+        assert!(report.free_pages >= kept);
+        assert_eq!(report.keys, 20000 - (1..20000).step_by(7).count());
+        assert_eq!(btree.get(&7).unwrap(), Some(-7));
+        drop(btree);
+        remove_files(path);
+    }
+
+    // runs the same operations against a tree whose stores crash after a growing number of
+    // writes, and checks the reopened tree holds every operation that returned and nothing of
+    // the one that failed unless it was logged completely
+    #[test]
+    fn test_crash_at_every_write() {
+        let op = |btree: &BTree<i32, i32>, model: &mut BTreeMap<i32, i32>, i: i32| -> Result<()> {
+            if i % 100 == 99 {
+                return btree.checkpoint();
+            }
+            if i % 5 == 4 {
+                let key = i * 13 % 500;
+                btree.remove(&key)?;
+                model.remove(&key);
+            } else {
+                let key = i * 37 % 500;
+                btree.set(&key, &i)?;
+                model.insert(key, i);
+            }
+            Ok(())
+        };
+        let ops = 400;
+        for fault in [Fault::Fail, Fault::Tear] {
+            let mut crashed = true;
+            let mut after = 0;
+            while crashed {
+                let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+                // a small pool, so pages are written back between checkpoints too
+                let options = Options::default().pool_capacity(8);
+                let btree = BTree::<i32, i32>::open_in("crash", data.clone(), wal.clone(), options.clone()).unwrap();
+                btree.bulk_load((0..500).step_by(3).map(|i| (i, 0)), 1.0).unwrap();
+                drop(btree);
+
+                let trigger = FaultTrigger::new(fault, after);
+                let btree = BTree::<i32, i32>::open_in(
+                    "crash",
+                    Arc::new(FaultyStore::new(data.clone(), trigger.clone())),
+                    Arc::new(FaultyStore::new(wal.clone(), trigger.clone())),
+                    options.clone(),
+                )
+                .unwrap();
+                let mut model: BTreeMap<i32, i32> = (0..500).step_by(3).map(|i| (i, 0)).collect();
+                let mut before = model.clone();
+                let mut failed = false;
+                for i in 0..ops {
+                    before.clone_from(&model);
+                    if op(&btree, &mut model, i).is_err() {
+                        failed = true;
+                        break;
+                    }
+                }
+                crashed = trigger.has_fired();
+                assert_eq!(failed, crashed);
+                drop(btree);
+
+                let btree = BTree::<i32, i32>::open_in("crash", data, wal, options).unwrap();
+                assert_healthy(&btree);
+                let found: BTreeMap<i32, i32> = btree.iter().unwrap().map(|r| r.unwrap()).collect();
+                assert!(
+                    found == model || (failed && found == before),
+                    "{:?} after {} writes: the tree does not match",
+                    fault,
+                    after
+                );
+                after += 7;
+            }
+        }
+    }
+
+    #[test]
+    fn test_drop_keeps_log_when_write_back_fails() {
+        let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+        let btree = BTree::<i32, i32>::open_in("drop", data.clone(), wal.clone(), Options::default()).unwrap();
+        btree.bulk_load((0..2000).map(|i| (i, 0)), 1.0).unwrap();
+        drop(btree);
+
+        // a few pages reach the file before the write-back at drop fails
+        let trigger = FaultTrigger::new(Fault::Fail, 3);
+        let faulty = Arc::new(FaultyStore::new(data.clone(), trigger.clone()));
+        let btree = BTree::<i32, i32>::open_in("drop", faulty, wal.clone(), Options::default()).unwrap();
+        for i in 0..2000 {
+            btree.set(&i, &i).unwrap();
+        }
+        assert!(!trigger.has_fired());
+        drop(btree);
+        assert!(trigger.has_fired());
+        assert!(!wal.bytes().is_empty());
+
+        let btree = BTree::<i32, i32>::open_in("drop", data, wal, Options::default()).unwrap();
+        assert_healthy(&btree);
+        let found = btree.iter().unwrap().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(found, (0..2000).map(|i| (i, i)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_failed_checkpoint_keeps_operation() {
+        let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+        let btree = BTree::<i32, i32>::open_in("checkpoint", data.clone(), wal.clone(), Options::default()).unwrap();
+        btree.bulk_load((0..2000).map(|i| (i, 0)), 1.0).unwrap();
+        drop(btree);
+
+        // nothing is evicted, the data file is only written by the checkpoints
+        let trigger = FaultTrigger::new(Fault::Fail, 3);
+        let faulty = Arc::new(FaultyStore::new(data.clone(), trigger.clone()));
+        let options = Options::default().pool_capacity(1000);
+        let btree = BTree::<i32, i32>::open_in("checkpoint", faulty, wal.clone(), options).unwrap();
+        for round in 1..4 {
+            for i in 0..2000 {
+                btree.set(&i, &(i * round)).unwrap();
+            }
+        }
+        assert!(trigger.has_fired());
+        assert!(wal.bytes().len() as u64 >= CHECKPOINT_WAL_SIZE);
+        drop(btree);
+
+        let btree = BTree::<i32, i32>::open_in("checkpoint", data, wal, Options::default()).unwrap();
+        assert_healthy(&btree);
+        let found = btree.iter().unwrap().map(|r| r.unwrap()).collect::<Vec<_>>();
+        assert_eq!(found, (0..2000).map(|i| (i, i * 3)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_rollback_failed_operation() {
+        // only the data file fails, the log would still take a half-done operation
+        let value = "v".repeat(200);
+        let mut after = 0;
+        let mut failed_in_split = false;
+        loop {
+            let (data, wal) = (Arc::new(MemoryStore::new()), Arc::new(MemoryStore::new()));
+            let options = Options::default().pool_capacity(8);
+            drop(BTree::<i32, String>::open_in("rollback", data.clone(), wal.clone(), options.clone()).unwrap());
+
+            let trigger = FaultTrigger::new(Fault::Fail, after);
+            let faulty = Arc::new(FaultyStore::new(data.clone(), trigger.clone()));
+            let btree = BTree::<i32, String>::open_in("rollback", faulty, wal.clone(), options.clone()).unwrap();
+            let mut model = BTreeMap::new();
+            for i in 0..600 {
+                let key = i * 37 % 600;
+                let version = btree.version.load(Ordering::Acquire);
+                if btree.set(&key, &value).is_err() {
+                    failed_in_split |= btree.version.load(Ordering::Acquire) != version;
+                    break;
+                }
+                model.insert(key, value.clone());
+            }
+            drop(btree);
+            if !trigger.has_fired() {
+                break;
+            }
 
-        new_page.set_ptr_at(0, ptrs[up_i + 1])?;
-        for i in (up_i + 1)..keys.len() {
-            new_page.set_key_at(i - up_i - 1, &keys[i])?;
-            new_page.set_ptr_at(i - up_i, ptrs[i + 1])?;
+            // the failed operation left nothing in the log to replay
+            let btree = BTree::<i32, String>::open_in("rollback", data, wal, options).unwrap();
+            assert_healthy(&btree);
+            let found: BTreeMap<i32, String> = btree.iter().unwrap().map(|r| r.unwrap()).collect();
+            assert!(found == model, "the tree does not match after {} writes", after);
+            after += 7;
         }
-        Ok((keys[up_i].clone(), new_page.index))
+        assert!(failed_in_split);
     }
 }
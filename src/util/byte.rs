@@ -1,8 +1,16 @@
 use anyhow::{anyhow, Result};
 use core::mem;
 
+pub use byte_derive::{BinSizer, Decodable, Encodable};
+
 pub trait BinSizer {
+    /// Encoded size of every value of the type, or 0 if it varies from value to value.
     fn bin_size() -> usize;
+
+    /// Encoded size of this value.
+    fn encoded_size(&self) -> usize {
+        Self::bin_size()
+    }
 }
 
 pub trait Encodable {
@@ -35,15 +43,16 @@ macro_rules! num_impl {
         impl Encodable for $ty {
             fn encode(&self, buf: &mut [u8]) -> Result<usize> {
                 check_len(buf, $size)?;
-                unsafe { *(&mut buf[0] as *mut _ as *mut _) = self.to_be() };
+                buf[..$size].copy_from_slice(&self.to_be_bytes());
                 Ok($size)
             }
         }
         impl Decodable for $ty {
             fn decode(buf: &[u8]) -> Result<(Self, usize)> {
                 check_len(buf, $size)?;
-                let val: $ty = unsafe { *(&buf[0] as *const _ as *const _) };
-                Ok((val.to_be(), $size))
+                // records are not aligned, so read the bytes rather than through a pointer
+                let val = <$ty>::from_be_bytes(buf[..$size].try_into()?);
+                Ok((val, $size))
             }
         }
     };
@@ -88,6 +97,79 @@ macro_rules! float_impl {
 float_impl!(f32, u32);
 float_impl!(f64, u64);
 
+// variable-length types are prefixed with their length
+macro_rules! bytes_impl {
+    ($ty: ty, $from_bytes: expr) => {
+        impl BinSizer for $ty {
+            #[inline]
+            fn bin_size() -> usize {
+                0
+            }
+
+            fn encoded_size(&self) -> usize {
+                4 + self.len()
+            }
+        }
+        impl Encodable for $ty {
+            fn encode(&self, buf: &mut [u8]) -> Result<usize> {
+                check_len(buf, self.encoded_size())?;
+                (self.len() as u32).encode(buf)?;
+                buf[4..4 + self.len()].copy_from_slice(self.as_ref());
+                Ok(self.encoded_size())
+            }
+        }
+        impl Decodable for $ty {
+            fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+                let (len, _) = u32::decode(buf)?;
+                let len = len as usize;
+                check_len(buf, 4 + len)?;
+                Ok(($from_bytes(&buf[4..4 + len])?, 4 + len))
+            }
+        }
+    };
+}
+
+bytes_impl!(Vec<u8>, |bytes: &[u8]| -> Result<Vec<u8>> { Ok(bytes.to_vec()) });
+bytes_impl!(String, |bytes: &[u8]| -> Result<String> { Ok(std::str::from_utf8(bytes)?.to_owned()) });
+
+// dates are stored as their julian day
+impl BinSizer for time::Date {
+    #[inline]
+    fn bin_size() -> usize {
+        4
+    }
+}
+impl Encodable for time::Date {
+    fn encode(&self, buf: &mut [u8]) -> Result<usize> {
+        self.to_julian_day().encode(buf)
+    }
+}
+impl Decodable for time::Date {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let (day, size) = i32::decode(buf)?;
+        Ok((time::Date::from_julian_day(day)?, size))
+    }
+}
+
+// timestamps are stored as unix seconds in UTC, anything below a second is dropped
+impl BinSizer for time::OffsetDateTime {
+    #[inline]
+    fn bin_size() -> usize {
+        8
+    }
+}
+impl Encodable for time::OffsetDateTime {
+    fn encode(&self, buf: &mut [u8]) -> Result<usize> {
+        self.unix_timestamp().encode(buf)
+    }
+}
+impl Decodable for time::OffsetDateTime {
+    fn decode(buf: &[u8]) -> Result<(Self, usize)> {
+        let (seconds, size) = i64::decode(buf)?;
+        Ok((time::OffsetDateTime::from_unix_timestamp(seconds)?, size))
+    }
+}
+
 #[macro_export]
 macro_rules! define_fixed_len_str {
     ($name: ident, $capacity: expr) => {
@@ -151,3 +233,81 @@ macro_rules! define_fixed_len_str {
         }
     };
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, BinSizer, Encodable, Decodable)]
+    struct Record {
+        id: u32,
+        balance: i64,
+        rate: f32,
+    }
+
+    #[derive(Debug, PartialEq, BinSizer, Encodable, Decodable)]
+    struct Pair<T>(T, u8);
+
+    #[derive(Debug, PartialEq, BinSizer, Encodable, Decodable)]
+    enum Status {
+        Open,
+        Frozen { since: u32 },
+        Closed(u64, u8),
+    }
+
+    #[derive(Debug, PartialEq, BinSizer, Encodable, Decodable)]
+    struct Named {
+        id: u32,
+        name: String,
+        status: Status,
+    }
+
+    #[derive(Debug, PartialEq, BinSizer, Encodable, Decodable)]
+    enum Note {
+        Empty,
+        Text(String),
+    }
+
+    fn round_trip<T: BinSizer + Encodable + Decodable + PartialEq + std::fmt::Debug>(value: T) {
+        let mut buf = vec![0xff; value.encoded_size() + 3];
+        assert_eq!(value.encode(&mut buf).unwrap(), value.encoded_size());
+        assert_eq!(T::decode(&buf).unwrap(), (value, buf.len() - 3));
+    }
+
+    #[test]
+    fn test_derive_fixed_size() {
+        assert_eq!(Record::bin_size(), 16);
+        assert_eq!(Pair::<i32>::bin_size(), 5);
+        assert_eq!(Pair::<Record>::bin_size(), 17);
+        // tag and the longest variant
+        assert_eq!(Status::bin_size(), 10);
+        assert_eq!(Status::Open.encoded_size(), 10);
+        round_trip(Record { id: 7, balance: -1 << 40, rate: 0.25 });
+        round_trip(Pair(Record { id: 1, balance: 2, rate: 3.0 }, 4));
+        round_trip(Status::Open);
+        round_trip(Status::Frozen { since: 20220601 });
+        round_trip(Status::Closed(u64::MAX, 1));
+
+        let mut buf = [0xff; 10];
+        Status::Open.encode(&mut buf).unwrap();
+        assert_eq!(buf, [0; 10]);
+        assert!(Record { id: 1, balance: 2, rate: 3.0 }.encode(&mut [0; 15]).is_err());
+        assert!(Record::decode(&[0; 15]).is_err());
+    }
+
+    #[test]
+    fn test_derive_variable_size() {
+        assert_eq!(Named::bin_size(), 0);
+        assert_eq!(Note::bin_size(), 0);
+        assert_eq!(Pair::<String>::bin_size(), 0);
+        let named = Named { id: 1, name: "张三".to_owned(), status: Status::Frozen { since: 1 } };
+        assert_eq!(named.encoded_size(), 4 + 4 + 6 + 10);
+        round_trip(named);
+        round_trip(Note::Empty);
+        round_trip(Note::Text("memo".to_owned()));
+        round_trip(Pair("x".repeat(100), 9));
+        assert_eq!(Note::Empty.encoded_size(), 1);
+        assert!(Note::decode(&[2]).is_err());
+        assert!(Status::decode(&[3; 10]).is_err());
+    }
+}
@@ -0,0 +1,227 @@
+use super::bank::{parse_amount, AccountRecord};
+use super::btree::BTree;
+use anyhow::Result;
+use std::fmt::{Display, Formatter};
+use std::io::{BufRead, Lines};
+
+pub struct RejectedRow {
+    pub line: usize,
+    pub reason: String,
+}
+
+#[derive(Default)]
+pub struct ImportReport {
+    pub rows_read: usize,
+    pub accounts_added: usize,
+    pub merged: usize,
+    pub rejected: Vec<RejectedRow>,
+}
+
+impl Display for ImportReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "读取{}行，新增账户{}个，合并{}行，拒绝{}行",
+            self.rows_read,
+            self.accounts_added,
+            self.merged,
+            self.rejected.len()
+        )
+    }
+}
+
+// 逐行解析 account,balance，格式错误的行记入报告后跳过
+struct Rows<R> {
+    lines: Lines<R>,
+    line: usize,
+    report: ImportReport,
+    error: Option<std::io::Error>,
+}
+
+impl<R: BufRead> Rows<R> {
+    fn new(reader: R) -> Self {
+        Rows {
+            lines: reader.lines(),
+            line: 0,
+            report: ImportReport::default(),
+            error: None,
+        }
+    }
+
+    fn reject(&mut self, line: usize, reason: String) {
+        self.report.rejected.push(RejectedRow { line, reason });
+    }
+
+    fn finish(self) -> Result<ImportReport> {
+        match self.error {
+            Some(err) => Err(err.into()),
+            None => Ok(self.report),
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for Rows<R> {
+    // (行号, 账号, 以分为单位的余额)
+    type Item = (usize, i32, i64);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(err) => {
+                    self.error = Some(err);
+                    return None;
+                }
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            self.report.rows_read += 1;
+            match parse_row(&line) {
+                Ok((account, balance)) => return Some((self.line, account, balance)),
+                Err(reason) => self.reject(self.line, reason),
+            }
+        }
+    }
+}
+
+fn parse_row(line: &str) -> Result<(i32, i64), String> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+    if fields.len() != 2 {
+        return Err(format!("应为2列，实际为{}列", fields.len()));
+    }
+    let account = fields[0]
+        .parse::<i32>()
+        .map_err(|_| format!("账号无效：{}", fields[0]))?;
+    let balance = parse_amount(fields[1]).ok_or_else(|| format!("余额无效：{}", fields[1]))?;
+    Ok((account, balance))
+}
+
+/// 读取 account,balance 格式的 csv 写入索引，余额以元为单位，最多两位小数。
+/// 相同账号的余额累加（包括索引里已有的账号），新账号按现在开户。
+/// 格式错误的行不会中断导入，而是带行号记在报告里。
+pub fn import_csv<R: BufRead>(reader: R, btree: &BTree<i32, AccountRecord>) -> Result<ImportReport> {
+    let mut rows = Rows::new(reader);
+    while let Some((line, account, balance)) = rows.next() {
+        // 合并成功为 Some(true)，溢出时保留原余额为 Some(false)，新账户为 None
+        let mut merged = None;
+        btree.update(&account, |record| match record {
+            Some(mut record) => {
+                let sum = record.balance.checked_add(balance);
+                merged = Some(sum.is_some());
+                record.balance = sum.unwrap_or(record.balance);
+                Some(record)
+            }
+            None => Some(AccountRecord::open(balance)),
+        })?;
+        match merged {
+            Some(true) => rows.report.merged += 1,
+            Some(false) => rows.reject(line, format!("账号{}合并后余额溢出", account)),
+            None => rows.report.accounts_added += 1,
+        }
+    }
+    rows.finish()
+}
+
+/// 按账号升序排好的 csv 走 `BTree::bulk_load` 直接自底向上建索引，只能导入空索引。
+/// 相同账号必须相邻，否则按乱序报错。
+pub fn bulk_import_csv<R: BufRead>(
+    reader: R,
+    btree: &BTree<i32, AccountRecord>,
+    fill_factor: f64,
+) -> Result<ImportReport> {
+    let mut rows = Rows::new(reader);
+    let added = btree.bulk_load(
+        MergeAdjacent {
+            rows: &mut rows,
+            pending: None,
+        },
+        fill_factor,
+    )?;
+    rows.report.accounts_added = added;
+    rows.finish()
+}
+
+struct MergeAdjacent<'a, R> {
+    rows: &'a mut Rows<R>,
+    pending: Option<(i32, i64)>,
+}
+
+impl<'a, R: BufRead> Iterator for MergeAdjacent<'a, R> {
+    type Item = (i32, AccountRecord);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some((line, account, balance)) = self.rows.next() {
+            match self.pending {
+                Some((pending_account, pending_balance)) if pending_account == account => {
+                    match pending_balance.checked_add(balance) {
+                        Some(sum) => {
+                            self.pending = Some((account, sum));
+                            self.rows.report.merged += 1;
+                        }
+                        None => self.rows.reject(line, format!("账号{}合并后余额溢出", account)),
+                    }
+                }
+                _ => {
+                    if let Some((account, balance)) = self.pending.replace((account, balance)) {
+                        return Some((account, AccountRecord::open(balance)));
+                    }
+                }
+            }
+        }
+        self.pending.take().map(|(account, balance)| (account, AccountRecord::open(balance)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::test_util::{remove_files, temp_path};
+    use std::io::Cursor;
+
+    const CSV: &str = "1001,100\n1002, 50\n\n1001,-30.5\nabc,10\n1003\n1004,12x\n1002,92233720368547758.07\n";
+
+    fn balance(btree: &BTree<i32, AccountRecord>, account: i32) -> Option<i64> {
+        btree.get(&account).unwrap().map(|record| record.balance)
+    }
+
+    #[test]
+    fn test_import_csv() {
+        let path = temp_path("import");
+        let btree = BTree::<i32, AccountRecord>::new(path);
+        let report = import_csv(Cursor::new(CSV), &btree).unwrap();
+        assert_eq!(report.rows_read, 7);
+        assert_eq!(report.accounts_added, 2);
+        assert_eq!(report.merged, 1);
+        let lines: Vec<usize> = report.rejected.iter().map(|r| r.line).collect();
+        assert_eq!(lines, vec![5, 6, 7, 8]);
+        assert_eq!(balance(&btree, 1001), Some(6950));
+        assert_eq!(balance(&btree, 1002), Some(5000));
+
+        // a second import merges into what is already indexed
+        let report = import_csv(Cursor::new("1002,5\n1005,1\n"), &btree).unwrap();
+        assert_eq!((report.accounts_added, report.merged), (1, 1));
+        assert_eq!(balance(&btree, 1002), Some(5500));
+        drop(btree);
+        remove_files(path);
+    }
+
+    #[test]
+    fn test_bulk_import_csv() {
+        let path = temp_path("bulk-import");
+        let btree = BTree::<i32, AccountRecord>::new(path);
+        assert!(bulk_import_csv(Cursor::new("2,1\n1,1\n"), &btree, 1.0).is_err());
+
+        let csv = "1,10\n1,5\n2,7\nx,1\n3,1\n3,92233720368547758.07\n";
+        let report = bulk_import_csv(Cursor::new(csv), &btree, 1.0).unwrap();
+        assert_eq!(report.rows_read, 6);
+        assert_eq!(report.accounts_added, 3);
+        assert_eq!(report.merged, 1);
+        assert_eq!(report.rejected.len(), 2);
+        assert_eq!(balance(&btree, 1), Some(1500));
+        assert_eq!(balance(&btree, 3), Some(100));
+        drop(btree);
+        remove_files(path);
+    }
+}
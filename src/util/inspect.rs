@@ -0,0 +1,195 @@
+use super::btree::{LevelStats, TreeStats};
+use super::byte::{BinSizer, Decodable, Encodable};
+use super::page::{Page, PageType, Value};
+use serde_json::{json, Value as Json};
+use std::fmt::{Debug, Write};
+
+// 键和值按 Debug 输出成字符串，不要求它们能序列化
+fn debug<T: Debug>(value: T) -> Json {
+    Json::String(format!("{:?}", value))
+}
+
+/// 页面的 JSON 表示，字段和 `Page` 的 Debug 输出一致
+pub fn page_json<K, V>(page: &Page<K, V>) -> Json
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    let mut json = json!({
+        "index": page.index,
+        "type": format!("{:?}", page.page_type),
+    });
+    let fields = match page.page_type {
+        PageType::META => {
+            let [magic, version, page_size, key_size, value_size] = page.stored_format_header();
+            json!({
+                "format": {
+                    "magic": magic,
+                    "version": version,
+                    "page_size": page_size,
+                    "key_size": key_size,
+                    "value_size": value_size,
+                },
+                "root": page.root_index(),
+                "total_pages": page.total_pages(),
+                "keys": page.key_count(),
+                "retired": page.retired_pages(),
+                "free": page.free_list(),
+                "next_free_list": page.next_free_list(),
+            })
+        }
+        PageType::FREELIST => json!({
+            "free": page.free_list(),
+            "next_free_list": page.next_free_list(),
+        }),
+        PageType::OVERFLOW => json!({
+            "bytes": page.overflow_data().len(),
+            "next": page.next_overflow(),
+        }),
+        PageType::LEAF => {
+            let records: Vec<Json> = (0..page.item_count())
+                .map(|i| {
+                    let key = page.key_at(i).map_or(Json::Null, debug);
+                    match page.value_at(i) {
+                        Some(Value::Overflow { len, first_page }) => json!({
+                            "key": key,
+                            "overflow": { "bytes": len, "first_page": first_page },
+                        }),
+                        Some(Value::Inline(value)) => json!({ "key": key, "value": debug(value) }),
+                        None => json!({ "key": key, "value": null }),
+                    }
+                })
+                .collect();
+            json!({
+                "items": page.item_count(),
+                "free_bytes": page.free_space(),
+                "fill_factor": fill_factor(page),
+                "prev": page.prev_leaf(),
+                "next": page.next_leaf(),
+                "records": records,
+            })
+        }
+        PageType::INTERNAL => {
+            let records: Vec<Json> = (0..page.item_count())
+                .map(|i| json!({ "key": page.key_at(i).map_or(Json::Null, debug), "child": page.ptr_at(i + 1) }))
+                .collect();
+            json!({
+                "items": page.item_count(),
+                "free_bytes": page.free_space(),
+                "fill_factor": fill_factor(page),
+                "first_child": page.ptr_at(0),
+                "records": records,
+            })
+        }
+    };
+    if let (Json::Object(json), Json::Object(fields)) = (&mut json, fields) {
+        json.extend(fields);
+    }
+    json
+}
+
+fn fill_factor<K, V>(page: &Page<K, V>) -> f64
+where
+    K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
+    V: Encodable + Decodable + BinSizer + Debug + Clone,
+{
+    page.used_space() as f64 / Page::<K, V>::capacity() as f64
+}
+
+pub fn levels_json(levels: &[LevelStats]) -> Json {
+    let levels: Vec<Json> = levels
+        .iter()
+        .map(|level| {
+            json!({
+                "level": level.level,
+                "pages": level.pages,
+                "keys": level.keys,
+                "used_bytes": level.used_bytes,
+                "fill_factor": level.fill_factor(),
+            })
+        })
+        .collect();
+    json!({ "depth": levels.len(), "levels": levels })
+}
+
+pub fn stats_json(stats: &TreeStats) -> Json {
+    json!({
+        "height": stats.height,
+        "internal_pages": stats.internal_pages,
+        "leaf_pages": stats.leaf_pages,
+        "keys": stats.keys,
+        "total_pages": stats.total_pages,
+        "file_bytes": stats.file_bytes,
+        "levels": levels_json(&stats.levels)["levels"],
+    })
+}
+
+/// 每层一行：页数、键数和平均填充率，第 0 层是根
+pub fn levels_text(levels: &[LevelStats]) -> String {
+    let mut text = format!("{:<8}{:>10}{:>14}{:>10}", "level", "pages", "keys", "fill");
+    for level in levels {
+        write!(
+            text,
+            "\n{:<8}{:>10}{:>14}{:>9.1}%",
+            level.level,
+            level.pages,
+            level.keys,
+            level.fill_factor() * 100.0
+        )
+        .unwrap();
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::btree::BTree;
+    use crate::util::page::FORMAT_VERSION;
+    use crate::util::test_util::{remove_files, temp_path};
+
+    #[test]
+    fn test_inspect() {
+        let path = temp_path("inspect");
+        let btree = BTree::<i32, String>::new(path);
+        btree.bulk_load((0..2000).map(|i| (i, "x".repeat(i as usize % 7))), 1.0).unwrap();
+        btree.set(&5000, &"y".repeat(5000)).unwrap();
+
+        let meta = btree.with_page(0, page_json).unwrap();
+        assert_eq!(meta["type"], "META");
+        assert_eq!(meta["format"]["version"], FORMAT_VERSION);
+        assert_eq!(meta["keys"], 2001);
+        assert_eq!(meta["format"]["value_size"], 0);
+        let root = meta["root"].as_u64().unwrap() as u32;
+
+        let root = btree.with_page(root, page_json).unwrap();
+        assert_eq!(root["type"], "INTERNAL");
+        let first_leaf = root["first_child"].as_u64().unwrap() as u32;
+        let leaf = btree.with_page(first_leaf, page_json).unwrap();
+        assert_eq!(leaf["prev"], Json::Null);
+        assert_eq!(leaf["records"][3], json!({ "key": "3", "value": "\"xxx\"" }));
+
+        let levels = btree.levels().unwrap();
+        assert_eq!(levels.len(), 2);
+        assert_eq!(levels[1].keys, 2001);
+        assert_eq!(levels[1].pages, root["items"].as_u64().unwrap() as usize + 1);
+        assert!(levels[1].fill_factor() > 0.9);
+        let json = levels_json(&levels);
+        assert_eq!(json["depth"], 2);
+        assert_eq!(json["levels"][1]["pages"], levels[1].pages);
+        assert_eq!(levels_text(&levels).lines().count(), 3);
+        let stats = stats_json(&btree.stats().unwrap());
+        assert_eq!(stats["keys"], 2001);
+        assert_eq!(stats["levels"], json["levels"]);
+
+        let last_leaf = root["records"].as_array().unwrap().last().unwrap()["child"].as_u64().unwrap() as u32;
+        let last_leaf = btree.with_page(last_leaf, page_json).unwrap();
+        let record = last_leaf["records"].as_array().unwrap().last().unwrap().clone();
+        assert_eq!(record["overflow"]["bytes"], 5004);
+        let overflow = record["overflow"]["first_page"].as_u64().unwrap() as u32;
+        assert_eq!(btree.with_page(overflow, page_json).unwrap()["type"], "OVERFLOW");
+        assert!(btree.with_page(10000, page_json).is_err());
+        drop(btree);
+        remove_files(path);
+    }
+}
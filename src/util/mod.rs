@@ -2,5 +2,12 @@ pub mod bank;
 pub mod threadpool;
 pub mod priority_async_channel;
 pub mod btree;
+pub mod bloom;
 pub mod page;
-pub mod byte;
\ No newline at end of file
+pub mod store;
+pub mod byte;
+pub mod import;
+pub mod inspect;
+pub mod wal;
+#[cfg(test)]
+pub(crate) mod test_util;
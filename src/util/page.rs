@@ -1,45 +1,130 @@
-use std::fs::File;
 use anyhow::{Result, anyhow};
-use std::io::{Read, Seek, SeekFrom, Write};
-use std::borrow::{BorrowMut, Borrow};
 use super::byte::{Encodable, Decodable, BinSizer};
 use std::marker::PhantomData;
 use thiserror::Error;
 use std::fmt::{Debug, Formatter};
-use std::cell::RefCell;
-use std::rc::Rc;
+use super::wal::crc32;
+use super::store::{MappedPage, StoreRef};
+use std::ops::{Deref, DerefMut};
 
 pub const PAGE_SIZE: usize = 4096;
 pub const MAX_KEY_SIZE: usize = 128;
 pub const MAX_VALUE_SIZE: usize = 1024;
 const PTR_SIZE: usize = 4;
+// LEAF and INTERNAL pages: type, item count, next and prev leaf or the first child pointer,
+// start of the record heap and bytes lost to holes in it, then one slot per record
+const HEADER_SIZE: usize = 20;
+const HEAP_START_POS: usize = 16;
+const FRAGMENTED_POS: usize = 18;
+// offset and length of a record, records are stored from the end of the page down
+const SLOT_SIZE: usize = 4;
+// OVERFLOW pages: type, bytes of the value held, next overflow page, then the bytes
+const OVERFLOW_HEADER_SIZE: usize = 12;
+// every page ends with a CRC32 of the bytes before it
+const CHECKSUM_SIZE: usize = 4;
+const PAGE_BODY_SIZE: usize = PAGE_SIZE - CHECKSUM_SIZE;
+
+// "BTRE", the first field of the file format header in the META page
+pub const MAGIC: u32 = 0x4254_5245;
+pub const FORMAT_VERSION: u32 = 4;
 
 #[derive(Error, Debug)]
 pub enum PageError {
     #[error("page is full, need split")]
-    Full
+    Full,
+    #[error("key takes {size} bytes, at most {max} are allowed")]
+    KeyTooLarge { size: usize, max: usize },
+    #[error("page {0} is corrupted, its checksum does not match")]
+    Corrupted(u32),
+    #[error("page {0} is past the end of the file, the file is truncated")]
+    Truncated(u32),
+    #[error("not a btree file, the magic number does not match")]
+    BadMagic,
+    #[error("unsupported file format version {0}, expected {FORMAT_VERSION}")]
+    UnsupportedVersion(u32),
+    #[error("the file was created with {field} {found}, this BTree uses {expected}")]
+    LayoutMismatch { field: &'static str, found: u32, expected: u32 },
 }
 
 pub(crate) struct Page<K, V>
 {
     pub index: u32,
-    buf: [u8; PAGE_SIZE],
+    buf: PageBuf,
     pub page_type: PageType,
-    keys_pos: usize,
-    values_pos: usize,
-    ptrs_pos: usize,
-    max_item_count: usize,
     dirty: bool,
-    fd: Option<Rc<RefCell<File>>>,
+    store: Option<StoreRef>,
     _k: PhantomData<K>,
     _v: PhantomData<V>,
 }
 
+// the bytes of a page: its own, or lent by a store that maps its pages until the first change
+enum PageBuf {
+    Owned(Box<[u8; PAGE_SIZE]>),
+    Mapped(MappedPage),
+}
+
+impl PageBuf {
+    fn zeroed() -> Self {
+        PageBuf::Owned(Box::new([0; PAGE_SIZE]))
+    }
+}
+
+impl Deref for PageBuf {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &[u8; PAGE_SIZE] {
+        match self {
+            PageBuf::Owned(buf) => buf,
+            PageBuf::Mapped(page) => page,
+        }
+    }
+}
+
+impl DerefMut for PageBuf {
+    fn deref_mut(&mut self) -> &mut [u8; PAGE_SIZE] {
+        if let PageBuf::Mapped(page) = self {
+            *self = PageBuf::Owned(Box::new(**page));
+        }
+        match self {
+            PageBuf::Owned(buf) => buf,
+            PageBuf::Mapped(_) => unreachable!(),
+        }
+    }
+}
+
+// a clone is always a copy, the page it was taken from may be changed and written back
+impl Clone for PageBuf {
+    fn clone(&self) -> Self {
+        PageBuf::Owned(Box::new(**self))
+    }
+}
+
 #[derive(Debug, PartialOrd, PartialEq)]
 pub(crate) enum PageType {
     META,
     INTERNAL,
     LEAF,
+    // overflow of the free list kept in the META page
+    FREELIST,
+    // part of a value too large to store in its leaf
+    OVERFLOW,
+}
+
+/// A value as its leaf stores it.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Value<V> {
+    Inline(V),
+    // len bytes spilled into a chain of overflow pages
+    Overflow { len: usize, first_page: u32 },
+}
+
+/// Space a record of record_len bytes takes up in a page, its slot included.
+pub(crate) fn record_space(record_len: usize) -> usize {
+    record_len + SLOT_SIZE
+}
+
+pub(crate) fn internal_record_space(key_len: usize) -> usize {
+    record_space(key_len + PTR_SIZE)
 }
 
 #[derive(Debug, PartialOrd, PartialEq)]
@@ -53,14 +138,10 @@ impl<K, V> Default for Page<K, V> {
     fn default() -> Self {
         Page::<K, V> {
             index: 0,
-            buf: [0; PAGE_SIZE],
+            buf: PageBuf::zeroed(),
             page_type: PageType::LEAF,
-            keys_pos: 0,
-            values_pos: 0,
-            ptrs_pos: 0,
-            max_item_count: 0,
             dirty: false,
-            fd: None,
+            store: None,
             _k: PhantomData,
             _v: PhantomData,
         }
@@ -71,69 +152,170 @@ impl<K, V> Page<K, V> where
     K: Encodable + Decodable + BinSizer + PartialEq + PartialOrd + Debug + Clone,
     V: Encodable + Decodable + BinSizer + Debug + Clone
 {
-    pub fn new(fd: Rc<RefCell<File>>, index: u32, pt: PageType) -> Result<Self> {
+    pub fn new(store: StoreRef, index: u32, pt: PageType) -> Result<Self> {
         let mut page = Self::default();
-        page.page_type = pt;
         page.index = index;
-        page.fd = Some(fd);
-        match page.page_type{
-            PageType::META => {
-                page.buf[0] = 0x01;
-                page.set_root_index(0);
-                page.set_total_page(0);
-            }
-            PageType::INTERNAL => {
-                page.buf[0] = 0x02;
-                page.set_item_count(0).unwrap();
-            }
-            PageType::LEAF => {
-                page.buf[0] = 0;
-                page.set_item_count(0).unwrap();
-            }
-        }
-        page.init_layout();
+        page.store = Some(store);
+        page.reset(pt);
         Ok(page)
     }
 
-    fn init_layout(&mut self) {
+    // turns the page into an empty page of type pt, used for new pages and reused free ones
+    pub fn reset(&mut self, pt: PageType) {
+        self.buf = PageBuf::zeroed();
+        self.page_type = pt;
         match self.page_type{
             PageType::META => {
+                self.buf[0] = 0x01;
+                self.set_root_index(0);
+                self.set_total_page(0);
+                self.set_key_count(0);
+                self.set_retired_pages(0);
+                for (pos, field) in Self::format_header().iter().enumerate() {
+                    field.encode(&mut self.buf[12 + pos * 4..]).unwrap();
+                }
             }
             PageType::INTERNAL => {
-                self.max_item_count = (PAGE_SIZE - 8 - PTR_SIZE) / (K::bin_size() + PTR_SIZE);
-                self.keys_pos = 8;
-                self.ptrs_pos = self.keys_pos + self.max_item_count * K::bin_size()
+                self.buf[0] = 0x02;
+                self.set_item_count(0);
+                self.set_heap(PAGE_BODY_SIZE, 0);
             }
             PageType::LEAF => {
-                self.max_item_count = (PAGE_SIZE - 8) / (K::bin_size() + V::bin_size());
-                self.keys_pos = 8;
-                self.values_pos = self.keys_pos + self.max_item_count * K::bin_size();
+                self.buf[0] = 0;
+                self.set_item_count(0);
+                self.set_heap(PAGE_BODY_SIZE, 0);
+            }
+            PageType::FREELIST => {
+                self.buf[0] = 0x04;
             }
-        };
+            PageType::OVERFLOW => {
+                self.buf[0] = 0x08;
+            }
+        }
+        Self::check_layout();
+        self.mark_dirty();
+    }
+
+    fn check_layout() {
         // at least we should have two items in one page
-        assert!(self.page_type == PageType::META || self.max_item_count >= 2)
+        assert!(Self::max_key_size() <= MAX_KEY_SIZE, "fixed size keys must fit in MAX_KEY_SIZE");
+        assert!(Self::capacity() >= 2 * (Self::max_key_size() + Self::max_value_cell_size() + SLOT_SIZE));
     }
 
-    pub fn load(fd: Rc<RefCell<File>>, index: u32) -> Result<Self> {
-        let mut page = Self::default();
+    // magic, format version, page size, key and value bin sizes, in the order they are stored
+    // in the META page after the total page count
+    fn format_header() -> [u32; 5] {
+        [MAGIC, FORMAT_VERSION, PAGE_SIZE as u32, K::bin_size() as u32, V::bin_size() as u32]
+    }
 
-        {
-            let mut _fd = fd.as_ref().borrow_mut();
-            page.index = index;
-            _fd.seek(SeekFrom::Start((index as usize * PAGE_SIZE) as u64))?;
-            _fd.read_exact(page.buf.borrow_mut())?;
+    /// The file format header stored in the META page: magic, format version, page size and
+    /// the key and value bin sizes.
+    pub fn stored_format_header(&self) -> [u32; 5] {
+        let mut found = [0; 5];
+        for (i, field) in found.iter_mut().enumerate() {
+            *field = u32::decode(&self.buf[12 + i * 4..]).unwrap().0;
+        }
+        found
+    }
+
+    // checks the file format header in the META page against this build and K, V
+    fn check_format(&self) -> Result<()> {
+        let found = self.stored_format_header();
+        let expected = Self::format_header();
+        if self.page_type != PageType::META || found[0] != MAGIC {
+            return Err(PageError::BadMagic.into());
+        }
+        if found[1] != FORMAT_VERSION {
+            return Err(PageError::UnsupportedVersion(found[1]).into());
+        }
+        for (i, field) in ["page size", "key size", "value size"].into_iter().enumerate() {
+            if found[i + 2] != expected[i + 2] {
+                return Err(PageError::LayoutMismatch { field, found: found[i + 2], expected: expected[i + 2] }.into());
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load(store: StoreRef, index: u32) -> Result<Self> {
+        let page = Self::read(store, index)?;
+        page.verify_checksum()?;
+        Ok(page)
+    }
+
+    /// Loads the META page, checking the file format header before the checksum so that
+    /// opening some other file reports `BadMagic` rather than a corrupted page.
+    pub fn load_meta(store: StoreRef) -> Result<Self> {
+        let page = Self::read(store, 0)?;
+        page.check_format()?;
+        page.verify_checksum()?;
+        Ok(page)
+    }
+
+    fn read(store: StoreRef, index: u32) -> Result<Self> {
+        let mut page = Self::default();
+        page.index = index;
+        match store.map_page(index)? {
+            Some(mapped) => page.buf = PageBuf::Mapped(mapped),
+            None => store.read_page(index, &mut page.buf)?,
         }
 
         page.page_type = page.get_page_type();
-        page.fd = Some(fd);
-        page.init_layout();
+        page.store = Some(store);
+        Self::check_layout();
         Ok(page)
     }
 
+    fn verify_checksum(&self) -> Result<()> {
+        if u32::decode(&self.buf[PAGE_BODY_SIZE..]).unwrap().0 != crc32(&self.buf[..PAGE_BODY_SIZE]) {
+            return Err(PageError::Corrupted(self.index).into());
+        }
+        Ok(())
+    }
+
     fn mark_dirty(&mut self) {
         self.dirty = true
     }
 
+    pub fn is_dirty(&self) -> bool {
+        self.dirty
+    }
+
+    /// A copy of the page that is never written back, for reading the page as it is now.
+    pub fn copy(&self) -> Self {
+        Page::<K, V> {
+            index: self.index,
+            buf: self.buf.clone(),
+            page_type: self.get_page_type(),
+            dirty: false,
+            store: None,
+            _k: PhantomData,
+            _v: PhantomData,
+        }
+    }
+
+    /// Puts back a copy taken before the page was changed, along with whether the page was
+    /// waiting to be written back then.
+    pub fn restore(&mut self, copy: &Self, dirty: bool) {
+        self.buf = copy.buf.clone();
+        self.page_type = copy.get_page_type();
+        self.dirty = dirty;
+    }
+
+    /// Makes the page a copy of other, for a writer that must leave other as it is.
+    pub fn copy_from(&mut self, other: &Self) {
+        self.buf = other.buf.clone();
+        self.page_type = other.get_page_type();
+        self.mark_dirty();
+    }
+
+    /// The page as it is written to the file, checksum included.
+    pub fn image(&self) -> [u8; PAGE_SIZE] {
+        let mut image = *self.buf;
+        crc32(&image[..PAGE_BODY_SIZE]).encode(&mut image[PAGE_BODY_SIZE..]).unwrap();
+        image
+    }
+
+
     fn get_page_type(&self) -> PageType {
         let u = self.buf[0];
         if u & 0x01 == 1 {
@@ -141,6 +323,10 @@ impl<K, V> Page<K, V> where
         } else {
             if u & 0x02 > 0 {
                 PageType::INTERNAL
+            } else if u & 0x04 > 0 {
+                PageType::FREELIST
+            } else if u & 0x08 > 0 {
+                PageType::OVERFLOW
             } else {
                 PageType::LEAF
             }
@@ -181,107 +367,458 @@ impl<K, V> Page<K, V> where
         }
     }
 
+    /// Number of keys in the tree, kept in the META page after the file format header.
+    pub fn key_count(&self) -> u64 {
+        match self.page_type {
+            PageType::META => u64::decode(&self.buf[32..]).unwrap().0,
+            _ => panic!("not a meta page")
+        }
+    }
+
+    pub fn set_key_count(&mut self, key_count: u64) {
+        match self.page_type {
+            PageType::META => {
+                key_count.encode(&mut self.buf[32..]).unwrap();
+                self.mark_dirty();
+            }
+            _ => panic!("not a meta page")
+        }
+    }
+
+    /// Number of pages taken out of the tree while a snapshot could still read them, kept in
+    /// the META page after the key count. They are neither in the tree nor on the free list
+    /// until the snapshot is dropped.
+    pub fn retired_pages(&self) -> u32 {
+        match self.page_type {
+            PageType::META => u32::decode(&self.buf[40..]).unwrap().0,
+            _ => panic!("not a meta page")
+        }
+    }
+
+    pub fn set_retired_pages(&mut self, retired_pages: u32) {
+        match self.page_type {
+            PageType::META => {
+                retired_pages.encode(&mut self.buf[40..]).unwrap();
+                self.mark_dirty();
+            }
+            _ => panic!("not a meta page")
+        }
+    }
+
+    // released page indexes, the META page keeps them after its retired page count and overflows into a
+    // chain of FREELIST pages: count, next FREELIST page (0 if none), then the indexes
+    fn free_list_pos(&self) -> usize {
+        match self.page_type {
+            PageType::META => 44,
+            PageType::FREELIST => 4,
+            _ => panic!("not a meta or free list page")
+        }
+    }
+
+    pub fn free_list_capacity(&self) -> usize {
+        (PAGE_BODY_SIZE - self.free_list_pos() - 2 * PTR_SIZE) / PTR_SIZE
+    }
+
+    pub fn free_count(&self) -> usize {
+        u32::decode(&self.buf[self.free_list_pos()..]).unwrap().0 as usize
+    }
+
+    pub fn next_free_list(&self) -> Option<u32> {
+        match u32::decode(&self.buf[self.free_list_pos() + PTR_SIZE..]).unwrap().0 {
+            0 => None,
+            index => Some(index),
+        }
+    }
+
+    pub fn free_list(&self) -> Vec<u32> {
+        let pos = self.free_list_pos() + 2 * PTR_SIZE;
+        (0..self.free_count())
+            .map(|i| u32::decode(&self.buf[pos + i * PTR_SIZE..]).unwrap().0)
+            .collect()
+    }
+
+    pub fn set_free_list(&mut self, indexes: &[u32], next: Option<u32>) {
+        assert!(indexes.len() <= self.free_list_capacity());
+        let pos = self.free_list_pos();
+        (indexes.len() as u32).encode(&mut self.buf[pos..]).unwrap();
+        next.unwrap_or(0).encode(&mut self.buf[pos + PTR_SIZE..]).unwrap();
+        for (i, index) in indexes.iter().enumerate() {
+            index.encode(&mut self.buf[pos + (2 + i) * PTR_SIZE..]).unwrap();
+        }
+        self.mark_dirty();
+    }
+
+    pub fn push_free(&mut self, index: u32) -> Result<()> {
+        let count = self.free_count();
+        if count >= self.free_list_capacity() {
+            return Err(PageError::Full.into());
+        }
+        let pos = self.free_list_pos();
+        index.encode(&mut self.buf[pos + (2 + count) * PTR_SIZE..]).unwrap();
+        (count as u32 + 1).encode(&mut self.buf[pos..]).unwrap();
+        self.mark_dirty();
+        Ok(())
+    }
+
+    pub fn pop_free(&mut self) -> Option<u32> {
+        let count = self.free_count();
+        if count == 0 {
+            return None;
+        }
+        let pos = self.free_list_pos();
+        let index = u32::decode(&self.buf[pos + (1 + count) * PTR_SIZE..]).unwrap().0;
+        (count as u32 - 1).encode(&mut self.buf[pos..]).unwrap();
+        self.mark_dirty();
+        Some(index)
+    }
+
+    // leaf pages are chained in key order, index 0 (the meta page) means there is no sibling
+    pub fn next_leaf(&self) -> Option<u32> {
+        match self.page_type {
+            PageType::LEAF => match u32::decode(&self.buf[8..]).unwrap().0 {
+                0 => None,
+                index => Some(index),
+            },
+            _ => panic!("not a leaf page")
+        }
+    }
+
+    pub fn prev_leaf(&self) -> Option<u32> {
+        match self.page_type {
+            PageType::LEAF => match u32::decode(&self.buf[12..]).unwrap().0 {
+                0 => None,
+                index => Some(index),
+            },
+            _ => panic!("not a leaf page")
+        }
+    }
+
+    pub fn set_next_leaf(&mut self, index: Option<u32>) {
+        match self.page_type {
+            PageType::LEAF => {
+                index.unwrap_or(0).encode(&mut self.buf[8..]).unwrap();
+                self.mark_dirty();
+            }
+            _ => panic!("not a leaf page")
+        }
+    }
+
+    pub fn set_prev_leaf(&mut self, index: Option<u32>) {
+        match self.page_type {
+            PageType::LEAF => {
+                index.unwrap_or(0).encode(&mut self.buf[12..]).unwrap();
+                self.mark_dirty();
+            }
+            _ => panic!("not a leaf page")
+        }
+    }
+
     pub fn item_count(&self) -> usize {
         match self.page_type {
             PageType::INTERNAL | PageType::LEAF => u32::decode(&self.buf[4..]).unwrap().0 as usize,
-            _ => panic!("not a meta page")
+            _ => panic!("not a internal / leaf page")
+        }
+    }
+
+    fn set_item_count(&mut self, item_count: usize) {
+        (item_count as u32).encode(&mut self.buf[4..]).unwrap();
+        self.mark_dirty();
+    }
+
+    /// Bytes a LEAF or INTERNAL page has for its records and their slots.
+    pub fn capacity() -> usize {
+        PAGE_BODY_SIZE - HEADER_SIZE
+    }
+
+    // keys of a fixed size type are as large as they get, variable-length ones are capped
+    fn max_key_size() -> usize {
+        match K::bin_size() {
+            0 => MAX_KEY_SIZE,
+            size => size,
+        }
+    }
+
+    // values that can outgrow MAX_VALUE_SIZE are stored behind a tag saying whether they are
+    // inline or spilled into overflow pages, values of a small fixed size type are always inline
+    fn values_tagged() -> bool {
+        V::bin_size() == 0 || V::bin_size() > MAX_VALUE_SIZE
+    }
+
+    fn max_value_cell_size() -> usize {
+        if Self::values_tagged() {
+            1 + MAX_VALUE_SIZE
+        } else {
+            V::bin_size()
         }
     }
 
+    // the most space one more record can take up in this page, its slot included
+    fn max_record_size(&self) -> usize {
+        match self.page_type {
+            PageType::LEAF => Self::max_key_size() + Self::max_value_cell_size() + SLOT_SIZE,
+            PageType::INTERNAL => Self::max_key_size() + PTR_SIZE + SLOT_SIZE,
+            _ => panic!("not a internal / leaf page")
+        }
+    }
+
+    fn heap_start(&self) -> usize {
+        u16::decode(&self.buf[HEAP_START_POS..]).unwrap().0 as usize
+    }
+
+    fn fragmented(&self) -> usize {
+        u16::decode(&self.buf[FRAGMENTED_POS..]).unwrap().0 as usize
+    }
+
+    fn set_heap(&mut self, heap_start: usize, fragmented: usize) {
+        (heap_start as u16).encode(&mut self.buf[HEAP_START_POS..]).unwrap();
+        (fragmented as u16).encode(&mut self.buf[FRAGMENTED_POS..]).unwrap();
+        self.mark_dirty();
+    }
+
+    // offset and length of record i
+    fn slot(&self, i: usize) -> (usize, usize) {
+        let pos = HEADER_SIZE + i * SLOT_SIZE;
+        (
+            u16::decode(&self.buf[pos..]).unwrap().0 as usize,
+            u16::decode(&self.buf[pos + 2..]).unwrap().0 as usize,
+        )
+    }
+
+    fn set_slot(&mut self, i: usize, offset: usize, len: usize) {
+        let pos = HEADER_SIZE + i * SLOT_SIZE;
+        (offset as u16).encode(&mut self.buf[pos..]).unwrap();
+        (len as u16).encode(&mut self.buf[pos + 2..]).unwrap();
+    }
+
+    /// Bytes left for records and their slots, counting the holes removed records left behind.
+    pub fn free_space(&self) -> usize {
+        self.heap_start() - HEADER_SIZE - self.item_count() * SLOT_SIZE + self.fragmented()
+    }
+
+    pub fn used_space(&self) -> usize {
+        Self::capacity() - self.free_space()
+    }
+
+    // full means one more record may not fit, so an insert below could have to split the page
     pub fn is_full(&self) -> bool {
+        self.free_space() < self.max_record_size()
+    }
+
+    // a non-root page using less than half its capacity must borrow from or merge with a sibling
+    pub fn is_underflow(&self) -> bool {
         assert_ne!(self.page_type, PageType::META);
-        self.item_count() >= self.max_item_count
+        self.used_space() < Self::capacity() / 2
     }
 
-    pub fn set_item_count(&mut self, item_count: usize) -> Result<()>{
-        match self.page_type {
-            PageType::INTERNAL | PageType::LEAF=> {
-                if item_count > self.max_item_count {
-                    Err(PageError::Full.into())
-                } else {
-                    (item_count as u32).encode(&mut self.buf[4..]).unwrap();
-                    self.mark_dirty();
-                    Ok(())
-                }
-            },
-            _ => panic!("not a meta page")
+    // true if the page stays above the underflow threshold whichever record it loses
+    pub fn can_lose_record(&self) -> bool {
+        self.used_space() >= Self::capacity() / 2 + self.max_record_size()
+    }
+
+    /// The raw bytes of record i: the encoded key followed by the value cell in a leaf, or by
+    /// the pointer right of the key in an internal page.
+    pub fn record_at(&self, i: usize) -> &[u8] {
+        let (offset, len) = self.slot(i);
+        &self.buf[offset..offset + len]
+    }
+
+    pub fn insert_record(&mut self, i: usize, record: &[u8]) -> Result<()> {
+        let item_count = self.item_count();
+        assert!(i <= item_count);
+        if record.len() + SLOT_SIZE > self.free_space() {
+            return Err(PageError::Full.into());
+        }
+        if HEADER_SIZE + (item_count + 1) * SLOT_SIZE + record.len() > self.heap_start() {
+            self.compact();
+        }
+        let offset = self.heap_start() - record.len();
+        self.buf[offset..offset + record.len()].copy_from_slice(record);
+        self.set_heap(offset, self.fragmented());
+        let pos = HEADER_SIZE + i * SLOT_SIZE;
+        self.buf.copy_within(pos..HEADER_SIZE + item_count * SLOT_SIZE, pos + SLOT_SIZE);
+        self.set_slot(i, offset, record.len());
+        self.set_item_count(item_count + 1);
+        Ok(())
+    }
+
+    pub fn remove_record(&mut self, i: usize) -> Result<()> {
+        let item_count = self.item_count();
+        if i >= item_count {
+            return Err(anyhow!("over size"))
+        }
+        let (_, len) = self.slot(i);
+        let pos = HEADER_SIZE + i * SLOT_SIZE;
+        self.buf.copy_within(pos + SLOT_SIZE..HEADER_SIZE + item_count * SLOT_SIZE, pos);
+        self.set_item_count(item_count - 1);
+        if item_count == 1 {
+            self.set_heap(PAGE_BODY_SIZE, 0);
+        } else {
+            self.set_heap(self.heap_start(), self.fragmented() + len);
+        }
+        Ok(())
+    }
+
+    // leaves the page as it was if the new record does not fit
+    pub fn replace_record(&mut self, i: usize, record: &[u8]) -> Result<()> {
+        let (_, len) = self.slot(i);
+        if record.len() > self.free_space() + len {
+            return Err(PageError::Full.into());
+        }
+        self.remove_record(i)?;
+        self.insert_record(i, record)
+    }
+
+    /// Replaces every record in the page, leaving it as it was if they do not fit.
+    pub fn set_records<R: AsRef<[u8]>>(&mut self, records: &[R]) -> Result<()> {
+        let size: usize = records.iter().map(|r| r.as_ref().len() + SLOT_SIZE).sum();
+        if size > Self::capacity() {
+            return Err(PageError::Full.into());
+        }
+        self.set_item_count(0);
+        self.set_heap(PAGE_BODY_SIZE, 0);
+        for (i, record) in records.iter().enumerate() {
+            self.insert_record(i, record.as_ref())?;
+        }
+        Ok(())
+    }
+
+    // moves the records to the end of the page, closing the holes between them
+    fn compact(&mut self) {
+        let records: Vec<Vec<u8>> = (0..self.item_count()).map(|i| self.record_at(i).to_vec()).collect();
+        let mut offset = PAGE_BODY_SIZE;
+        for (i, record) in records.iter().enumerate() {
+            offset -= record.len();
+            self.buf[offset..offset + record.len()].copy_from_slice(record);
+            self.set_slot(i, offset, record.len());
+        }
+        self.set_heap(offset, 0);
+    }
+
+    /// Encodes a key for a record, failing if it is larger than a key may be.
+    pub fn encode_key(k: &K) -> Result<Vec<u8>> {
+        let size = k.encoded_size();
+        if size > Self::max_key_size() {
+            return Err(PageError::KeyTooLarge { size, max: Self::max_key_size() }.into());
+        }
+        let mut buf = vec![0; size];
+        k.encode(&mut buf)?;
+        Ok(buf)
+    }
+
+    pub fn encode_value(v: &V) -> Result<Vec<u8>> {
+        let mut buf = vec![0; v.encoded_size()];
+        v.encode(&mut buf)?;
+        Ok(buf)
+    }
+
+    /// The value cell of an encoded value stored in the leaf itself, or None if it is too large
+    /// and has to spill into overflow pages.
+    pub fn inline_value_cell(value: &[u8]) -> Option<Vec<u8>> {
+        if !Self::values_tagged() {
+            return Some(value.to_vec());
+        }
+        if value.len() > MAX_VALUE_SIZE {
+            return None;
+        }
+        let mut cell = Vec::with_capacity(1 + value.len());
+        cell.push(0);
+        cell.extend_from_slice(value);
+        Some(cell)
+    }
+
+    /// The value cell of a value of len bytes spilled into the overflow pages starting at
+    /// first_page.
+    pub fn overflow_value_cell(len: usize, first_page: u32) -> Vec<u8> {
+        let mut cell = vec![1];
+        cell.extend_from_slice(&(len as u32).to_be_bytes());
+        cell.extend_from_slice(&first_page.to_be_bytes());
+        cell
+    }
+
+    fn decode_value_cell(cell: &[u8]) -> Result<Value<V>> {
+        if !Self::values_tagged() {
+            return Ok(Value::Inline(V::decode(cell)?.0));
+        }
+        match cell[0] {
+            0 => Ok(Value::Inline(V::decode(&cell[1..])?.0)),
+            _ => Ok(Value::Overflow {
+                len: u32::decode(&cell[1..])?.0 as usize,
+                first_page: u32::decode(&cell[5..])?.0,
+            }),
         }
     }
 
+    pub fn internal_record(key: &[u8], ptr: u32) -> Vec<u8> {
+        let mut record = Vec::with_capacity(key.len() + PTR_SIZE);
+        record.extend_from_slice(key);
+        record.extend_from_slice(&ptr.to_be_bytes());
+        record
+    }
+
+    // the encoded key and the pointer of an internal record
+    pub fn internal_record_parts(record: &[u8]) -> (&[u8], u32) {
+        let (key, ptr) = record.split_at(record.len() - PTR_SIZE);
+        (key, u32::from_be_bytes(ptr.try_into().unwrap()))
+    }
+
     pub fn key_at(&self, i: usize) -> Option<K> {
         match self.page_type {
             PageType::INTERNAL | PageType::LEAF=> {
                 if i >= self.item_count() {
                     None
                 } else {
-                    K::decode(&self.buf[(self.keys_pos + i * K::bin_size())..]).map(|t| t.0).ok()
+                    K::decode(self.record_at(i)).map(|t| t.0).ok()
                 }
             }
             _ => panic!("not a internal / leaf page")
         }
     }
 
-    pub fn value_at(&self, i: usize) -> Option<V> {
+    pub fn value_at(&self, i: usize) -> Option<Value<V>> {
         match self.page_type {
             PageType::LEAF => {
                 if i >= self.item_count() {
                     None
                 } else {
-                    V::decode(&self.buf[(self.values_pos + i * V::bin_size())..]).map(|t| t.0).ok()
+                    let record = self.record_at(i);
+                    let (_, key_len) = K::decode(record).ok()?;
+                    Self::decode_value_cell(&record[key_len..]).ok()
                 }
             }
             _ => panic!("not a leaf page")
         }
     }
 
+    // ptrs[0] is kept in the header, ptrs[i + 1] at the end of record i
     pub fn ptr_at(&self, i: usize) -> Option<u32> {
         match self.page_type {
             PageType::INTERNAL=> {
                 if i >= self.item_count() + 1 {
                     None
+                } else if i == 0 {
+                    u32::decode(&self.buf[8..]).map(|t| t.0).ok()
                 } else {
-                    u32::decode(&self.buf[(self.ptrs_pos + i * PTR_SIZE)..]).map(|t| t.0).ok()
+                    let record = self.record_at(i - 1);
+                    u32::decode(&record[record.len() - PTR_SIZE..]).map(|t| t.0).ok()
                 }
             }
             _ => panic!("not a internal page")
         }
     }
 
-    pub fn set_key_at(&mut self, i: usize, key: &K) -> Result<()> {
-        match self.page_type {
-            PageType::INTERNAL | PageType::LEAF => {
-                if i >= self.item_count() {
-                    return Err(anyhow!("over size"))
-                }
-                key.encode(&mut self.buf[(self.keys_pos + i * K::bin_size())..])?;
-                self.mark_dirty();
-                Ok(())
-            }
-            _ => panic!("not a internal / leaf page")
-        }
-    }
-
-    pub fn set_value_at(&mut self, i: usize, value: &V) -> Result<()> {
-        match self.page_type {
-            PageType::LEAF => {
-                if i >= self.item_count() {
-                    return Err(anyhow!("over size"))
-                }
-                value.encode(&mut self.buf[(self.values_pos + i * V::bin_size())..])?;
-                self.mark_dirty();
-                Ok(())
-            }
-            _ => panic!("not a leaf page")
-        }
-    }
-
     pub fn set_ptr_at(&mut self, i: usize, ptr: u32) -> Result<()> {
         match self.page_type {
             PageType::INTERNAL => {
                 if i >= self.item_count() + 1 {
                     return Err(anyhow!("over size"))
                 }
-                ptr.encode(&mut self.buf[(self.ptrs_pos + i * PTR_SIZE)..])?;
+                let pos = if i == 0 {
+                    8
+                } else {
+                    let (offset, len) = self.slot(i - 1);
+                    offset + len - PTR_SIZE
+                };
+                ptr.encode(&mut self.buf[pos..])?;
                 self.mark_dirty();
                 Ok(())
             }
@@ -289,6 +826,14 @@ impl<K, V> Page<K, V> where
         }
     }
 
+    // replaces key i of an internal page with an encoded key, which fails with PageError::Full
+    // if a longer key does not fit
+    pub fn set_key_at(&mut self, i: usize, key: &[u8]) -> Result<()> {
+        assert_eq!(self.page_type, PageType::INTERNAL);
+        let ptr = self.ptr_at(i + 1).ok_or_else(|| anyhow!("over size"))?;
+        self.replace_record(i, &Self::internal_record(key, ptr))
+    }
+
     pub fn find(&self, k: &K) -> Option<(usize, Pos)> {
         let item_count = self.item_count();
         if item_count == 0 {
@@ -318,116 +863,82 @@ impl<K, V> Page<K, V> where
         None
     }
 
-    pub fn insert(&mut self, k: &K, v: &V) -> Result<()> {
+    // slot of the child of an internal page that k belongs under; an internal page left without
+    // keys, because a borrow could not fit its separator, still has its first child
+    pub fn child_slot(&self, k: &K) -> usize {
+        assert_eq!(self.page_type, PageType::INTERNAL);
+        match self.find(k) {
+            Some((i, Pos::Left)) => i,
+            Some((i, _)) => i + 1,
+            None => 0,
+        }
+    }
+
+    // inserts the leaf record for k, or replaces it if k is already there. The record must
+    // start with k encoded
+    pub fn insert(&mut self, k: &K, record: &[u8]) -> Result<()> {
         assert_eq!(self.page_type, PageType::LEAF);
-        let old_item_count = self.item_count();
         match self.find(k) {
-            None => {
-                // empty node
-                self.set_item_count(1)?;
-                self.set_key_at(0, k)?;
-                self.set_value_at(0, v)?;
-            },
-            Some((i, pos)) => {
-                match pos {
-                    Pos::Current => {
-                        self.set_key_at(i, k)?;
-                        self.set_value_at(i, v)?;
-                    }
-                    Pos::Left => {
-                        self.set_item_count(old_item_count + 1)?;
-                        unsafe {
-                            let buf_ptr = self.buf.as_mut_ptr();
-                            let key_ptr = buf_ptr.add(self.keys_pos);
-                            let value_ptr = buf_ptr.add(self.values_pos);
-                            std::ptr::copy(key_ptr.add(i * K::bin_size()), key_ptr.add((i + 1) * K::bin_size()), (old_item_count - i) * K::bin_size());
-                            std::ptr::copy(value_ptr.add(i * V::bin_size()), value_ptr.add((i + 1) * V::bin_size()), (old_item_count - i) * V::bin_size());
-                        }
-                        // for j in (i..old_item_count).rev() {
-                        //     self.set_key_at(j + 1, &self.key_at(j).unwrap())?;
-                        //     self.set_value_at(j + 1, &self.value_at(j).unwrap())?;
-                        // }
-                        self.set_key_at(i, k)?;
-                        self.set_value_at(i, v)?;
-                    }
-                    Pos::Right => {
-                        self.set_item_count(old_item_count + 1)?;
-                        unsafe {
-                            let buf_ptr = self.buf.as_mut_ptr();
-                            let key_ptr = buf_ptr.add(self.keys_pos);
-                            let value_ptr = buf_ptr.add(self.values_pos);
-                            std::ptr::copy(key_ptr.add((i + 1) * K::bin_size()), key_ptr.add((i + 2) * K::bin_size()), (old_item_count - i - 1) * K::bin_size());
-                            std::ptr::copy(value_ptr.add((i + 1) * V::bin_size()), value_ptr.add((i + 2) * V::bin_size()), (old_item_count - i - 1) * V::bin_size());
-                        }
-                        // for j in ((i + 1)..old_item_count).rev() {
-                        //     self.set_key_at(j + 1, &self.key_at(j).unwrap())?;
-                        //     self.set_value_at(j + 1, &self.value_at(j).unwrap())?;
-                        // }
-                        self.set_key_at(i + 1, k)?;
-                        self.set_value_at(i + 1, v)?;
-                    }
-                }
-            }
+            None => self.insert_record(0, record),
+            Some((i, Pos::Current)) => self.replace_record(i, record),
+            Some((i, Pos::Left)) => self.insert_record(i, record),
+            Some((i, Pos::Right)) => self.insert_record(i + 1, record),
         }
-        self.mark_dirty();
-        Ok(())
     }
 
     pub fn insert_ptr(&mut self, k: &K, ptr: u32) -> Result<()> {
         assert_eq!(self.page_type, PageType::INTERNAL);
-        let old_item_count = self.item_count();
+        let record = Self::internal_record(&Self::encode_key(k)?, ptr);
         match self.find(k) {
             None => {
                 // empty node
                 // must first set ptrs[0] !!!
                 assert!(self.ptr_at(0).unwrap() > 0);
-                self.set_item_count(1)?;
-                self.set_key_at(0, k)?;
-                self.set_ptr_at(1, ptr)?;
+                self.insert_record(0, &record)
             },
-            Some((i, pos)) => {
-                match pos {
-                    Pos::Current => {
-                        self.set_key_at(i, k)?;
-                        self.set_ptr_at(i + 1, ptr)?;
-                    }
-                    Pos::Left => {
-                        self.set_item_count(old_item_count + 1)?;
-                        unsafe {
-                            let buf_ptr = self.buf.as_mut_ptr();
-                            let key_ptr = buf_ptr.add(self.keys_pos);
-                            let ptr_ptr = buf_ptr.add(self.ptrs_pos);
-                            std::ptr::copy(key_ptr.add(i * K::bin_size()), key_ptr.add((i + 1) * K::bin_size()), (old_item_count - i) * K::bin_size());
-                            std::ptr::copy(ptr_ptr.add((i + 1) * PTR_SIZE), ptr_ptr.add((i + 2) * PTR_SIZE), (old_item_count - i) * PTR_SIZE);
-                        }
-                        // for j in (i..old_item_count).rev() {
-                        //     self.set_key_at(j + 1, &self.key_at(j).unwrap())?;
-                        //     self.set_ptr_at(j + 2, self.ptr_at(j + 1).unwrap())?;
-                        // }
-                        self.set_key_at(i, k)?;
-                        self.set_ptr_at(i + 1, ptr)?;
-                    }
-                    Pos::Right => {
-                        self.set_item_count(old_item_count + 1)?;
-                        unsafe {
-                            let buf_ptr = self.buf.as_mut_ptr();
-                            let key_ptr = buf_ptr.add(self.keys_pos);
-                            let ptr_ptr = buf_ptr.add(self.ptrs_pos);
-                            std::ptr::copy(key_ptr.add((i + 1) * K::bin_size()), key_ptr.add((i + 2) * K::bin_size()), (old_item_count - i -1) * K::bin_size());
-                            std::ptr::copy(ptr_ptr.add((i + 2) * PTR_SIZE), ptr_ptr.add((i + 3) * PTR_SIZE), (old_item_count - i - 1) * PTR_SIZE);
-                        }
-                        // for j in ((i + 1)..old_item_count).rev() {
-                        //     self.set_key_at(j + 1, &self.key_at(j).unwrap())?;
-                        //     self.set_ptr_at(j + 2, self.ptr_at(j + 1).unwrap())?;
-                        // }
-                        self.set_key_at(i + 1, k)?;
-                        self.set_ptr_at(i + 2, ptr)?;
-                    }
-                }
-            }
+            Some((i, Pos::Current)) => self.replace_record(i, &record),
+            Some((i, Pos::Left)) => self.insert_record(i, &record),
+            Some((i, Pos::Right)) => self.insert_record(i + 1, &record),
         }
+    }
+
+    pub fn remove_at(&mut self, i: usize) -> Result<()> {
+        assert_eq!(self.page_type, PageType::LEAF);
+        self.remove_record(i)
+    }
+
+    // removes key i together with the ptr on its right side
+    pub fn remove_ptr_at(&mut self, i: usize) -> Result<()> {
+        assert_eq!(self.page_type, PageType::INTERNAL);
+        self.remove_record(i)
+    }
+
+    /// Bytes of a value an overflow page holds.
+    pub fn overflow_capacity() -> usize {
+        PAGE_BODY_SIZE - OVERFLOW_HEADER_SIZE
+    }
+
+    pub fn overflow_data(&self) -> &[u8] {
+        assert_eq!(self.page_type, PageType::OVERFLOW);
+        let len = u32::decode(&self.buf[4..]).unwrap().0 as usize;
+        &self.buf[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + len]
+    }
+
+    pub fn next_overflow(&self) -> Option<u32> {
+        assert_eq!(self.page_type, PageType::OVERFLOW);
+        match u32::decode(&self.buf[8..]).unwrap().0 {
+            0 => None,
+            index => Some(index),
+        }
+    }
+
+    pub fn set_overflow(&mut self, data: &[u8], next: Option<u32>) {
+        assert_eq!(self.page_type, PageType::OVERFLOW);
+        assert!(data.len() <= Self::overflow_capacity());
+        (data.len() as u32).encode(&mut self.buf[4..]).unwrap();
+        next.unwrap_or(0).encode(&mut self.buf[8..]).unwrap();
+        self.buf[OVERFLOW_HEADER_SIZE..OVERFLOW_HEADER_SIZE + data.len()].copy_from_slice(data);
         self.mark_dirty();
-        Ok(())
     }
 }
 
@@ -438,16 +949,24 @@ impl<K,V> Debug for Page<K, V> where
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         match self.page_type {
             PageType::META => {
-                f.write_fmt(format_args!("{:?}; root index:{}; total pages: {}", self.page_type, self.root_index(), self.total_pages()))?;
+                let [_, version, page_size, key_size, value_size] = self.stored_format_header();
+                f.write_fmt(format_args!("{:?}; format version: {}; page size: {}; key size: {}; value size: {};\n", self.page_type, version, page_size, key_size, value_size))?;
+                f.write_fmt(format_args!("root index:{}; total pages: {}; keys: {}; retired: {}; free: {:?}; next free list: {:?}", self.root_index(), self.total_pages(), self.key_count(), self.retired_pages(), self.free_list(), self.next_free_list()))?;
+            }
+            PageType::FREELIST => {
+                f.write_fmt(format_args!("{:?}; free: {:?}; next free list: {:?}", self.page_type, self.free_list(), self.next_free_list()))?;
+            }
+            PageType::OVERFLOW => {
+                f.write_fmt(format_args!("{:?}; bytes: {}; next: {:?}", self.page_type, self.overflow_data().len(), self.next_overflow()))?;
             }
             PageType::LEAF => {
-                f.write_fmt(format_args!("{:?}; item count:{};\n", self.page_type, self.item_count()))?;
+                f.write_fmt(format_args!("{:?}; item count:{}; free: {}; prev: {:?}; next: {:?};\n", self.page_type, self.item_count(), self.free_space(), self.prev_leaf(), self.next_leaf()))?;
                 for i in 0..self.item_count() {
                     f.write_fmt(format_args!("#{} {:?}: {:?}\n", i, self.key_at(i).unwrap(), self.value_at(i).unwrap()))?;
                 }
             }
             PageType::INTERNAL => {
-                f.write_fmt(format_args!("{:?}; item count:{};\n", self.page_type, self.item_count()))?;
+                f.write_fmt(format_args!("{:?}; item count:{}; free: {};\n", self.page_type, self.item_count(), self.free_space()))?;
                 f.write_fmt(format_args!("#_ _: {}\n", self.ptr_at(0).unwrap()))?;
                 for i in 0..self.item_count() {
                     f.write_fmt(format_args!("#{} {:?}: {}\n", i, self.key_at(i).unwrap(), self.ptr_at(i + 1).unwrap()))?;
@@ -461,17 +980,31 @@ impl<K,V> Debug for Page<K, V> where
 impl<K, V> Page<K, V> {
     pub fn sync(&mut self) -> Result<()> {
         if self.dirty {
-            let mut fd = self.fd.as_ref().unwrap().as_ref().borrow_mut();
-            fd.seek(SeekFrom::Start((self.index as usize * PAGE_SIZE) as u64))?;
-            fd.write_all(self.buf.borrow())?;
+            let crc = crc32(&self.buf[..PAGE_BODY_SIZE]);
+            crc.encode(&mut self.buf[PAGE_BODY_SIZE..]).unwrap();
+            self.store.as_ref().unwrap().write_page(self.index, &self.buf)?;
             self.dirty = false;
         }
         Ok(())
     }
+
+    /// Forgets the changes not written back yet, for a page that is dropped without ever
+    /// reaching the file.
+    pub fn discard(&mut self) {
+        self.dirty = false;
+    }
+
+    /// Whether the page still points into a mapping of the file rather than holding a copy.
+    pub fn is_mapped(&self) -> bool {
+        matches!(self.buf, PageBuf::Mapped(_))
+    }
 }
 
+// pages are written back before they are dropped, this only catches one that was not
 impl<K, V> Drop for Page<K, V> {
     fn drop(&mut self) {
-        self.sync().unwrap();
+        if let Err(err) = self.sync() {
+            eprintln!("could not write back page {}: {:#}", self.index, err);
+        }
     }
 }
\ No newline at end of file
@@ -0,0 +1,441 @@
+use super::page::{PageError, PAGE_SIZE};
+use anyhow::Result;
+use memmap2::{Mmap, MmapOptions};
+use parking_lot::{Mutex, RwLock};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::ops::Deref;
+use std::os::unix::fs::FileExt;
+use std::sync::Arc;
+
+pub type StoreRef = Arc<dyn PageStore>;
+
+/// Where a `BTree` keeps its pages or its write-ahead log: a sequence of bytes that can be read
+/// and written at any offset. Page `index` is kept at `index * PAGE_SIZE`.
+pub trait PageStore: Send + Sync {
+    /// Fills buf from offset, failing with `UnexpectedEof` if the store ends first.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()>;
+
+    /// Writes data at offset, growing the store if it ends before.
+    fn write_at(&self, offset: u64, data: &[u8]) -> std::io::Result<()>;
+
+    fn len(&self) -> std::io::Result<u64>;
+
+    fn set_len(&self, len: u64) -> std::io::Result<()>;
+
+    /// Returns once everything written so far is durable.
+    fn sync(&self) -> std::io::Result<()>;
+
+    fn is_empty(&self) -> std::io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+
+    fn read_page(&self, index: u32, buf: &mut [u8; PAGE_SIZE]) -> Result<()> {
+        match self.read_at(index as u64 * PAGE_SIZE as u64, buf) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => Err(PageError::Truncated(index).into()),
+            result => Ok(result?),
+        }
+    }
+
+    fn write_page(&self, index: u32, buf: &[u8; PAGE_SIZE]) -> Result<()> {
+        Ok(self.write_at(index as u64 * PAGE_SIZE as u64, buf)?)
+    }
+
+    /// Lends page `index` in place instead of copying it out, None if the store cannot. The
+    /// page must not be written while it is lent.
+    fn map_page(&self, _index: u32) -> Result<Option<MappedPage>> {
+        Ok(None)
+    }
+}
+
+pub struct FileStore {
+    file: Mutex<File>,
+}
+
+impl FileStore {
+    pub fn new(file: File) -> Self {
+        FileStore { file: Mutex::new(file) }
+    }
+}
+
+impl PageStore for FileStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(buf)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(offset))?;
+        file.write_all(data)
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.file.lock().metadata()?.len())
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.file.lock().set_len(len)
+    }
+
+    // the file length is flushed along with the data when it is needed to read the data back
+    fn sync(&self) -> std::io::Result<()> {
+        self.file.lock().sync_data()
+    }
+}
+
+// the mapping grows in steps of this many bytes, past the end of the file
+const MAP_STEP: u64 = 16 << 20;
+
+/// Reads pages straight out of a shared memory mapping of the file rather than with a system
+/// call each: `map_page` lends a page in place, so loading one neither reads nor copies it,
+/// and readers do not wait for each other. The mapping reaches past the end of the file and is
+/// only remapped when a write goes past it, the pages lent from the old mapping keep it alive.
+///
+/// Writes go to the file with `pwrite`, which the mapping sees at once as both share the page
+/// cache. A lent page must never change under its borrower: the buffer pool holds a single
+/// `Page` for each index and it copies the bytes before its first change, so a page is only
+/// written back once nobody borrows it any more, and the file never shrinks below a lent page.
+pub struct MmapStore {
+    file: File,
+    writable: bool,
+    mapping: RwLock<Mapping>,
+}
+
+struct Mapping {
+    // None while the file is empty
+    map: Option<Arc<Mmap>>,
+    // the length of the file, the mapping past it must not be touched
+    len: u64,
+}
+
+impl MmapStore {
+    /// Maps file, which must be opened for writing unless `writable` is false.
+    pub fn new(file: File, writable: bool) -> Result<Self> {
+        let len = file.metadata()?.len();
+        let store = MmapStore { file, writable, mapping: RwLock::new(Mapping { map: None, len }) };
+        if len > 0 {
+            let map = store.map(len)?;
+            store.mapping.write().map = Some(map);
+        }
+        Ok(store)
+    }
+
+    // maps at least len bytes
+    fn map(&self, len: u64) -> std::io::Result<Arc<Mmap>> {
+        let len = len.div_ceil(MAP_STEP) * MAP_STEP;
+        // safety: the file is only written through this store, and no byte that is lent or past
+        // the end of the file is written or read, see `MmapStore`
+        unsafe { Ok(Arc::new(MmapOptions::new().len(len as usize).map(&self.file)?)) }
+    }
+}
+
+impl PageStore for MmapStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let mapping = self.mapping.read();
+        let end = offset + buf.len() as u64;
+        match &mapping.map {
+            Some(map) if end <= mapping.len => {
+                buf.copy_from_slice(&map[offset as usize..end as usize]);
+                Ok(())
+            }
+            _ => Err(Error::new(ErrorKind::UnexpectedEof, "read past the end of the file")),
+        }
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        if !self.writable {
+            return Err(Error::new(ErrorKind::PermissionDenied, "the file is mapped read-only"));
+        }
+        let mut mapping = self.mapping.write();
+        self.file.write_all_at(data, offset)?;
+        let end = offset + data.len() as u64;
+        if end > mapping.len {
+            mapping.len = end;
+        }
+        let mapped = mapping.map.as_ref().map_or(0, |map| map.len() as u64);
+        if end > mapped {
+            mapping.map = Some(self.map(end)?);
+        }
+        Ok(())
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.mapping.read().len)
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        let mut mapping = self.mapping.write();
+        self.file.set_len(len)?;
+        mapping.len = len;
+        Ok(())
+    }
+
+    // the writes went through the page cache like any other, there is nothing to msync
+    fn sync(&self) -> std::io::Result<()> {
+        self.file.sync_data()
+    }
+
+    fn map_page(&self, index: u32) -> Result<Option<MappedPage>> {
+        let mapping = self.mapping.read();
+        let offset = index as usize * PAGE_SIZE;
+        match &mapping.map {
+            Some(map) if (offset + PAGE_SIZE) as u64 <= mapping.len => Ok(Some(MappedPage { map: map.clone(), offset })),
+            _ => Err(PageError::Truncated(index).into()),
+        }
+    }
+}
+
+/// A page lent in place by a store that keeps its pages mapped, see `PageStore::map_page`.
+pub struct MappedPage {
+    map: Arc<Mmap>,
+    offset: usize,
+}
+
+impl Deref for MappedPage {
+    type Target = [u8; PAGE_SIZE];
+
+    fn deref(&self) -> &[u8; PAGE_SIZE] {
+        self.map[self.offset..self.offset + PAGE_SIZE].try_into().unwrap()
+    }
+}
+
+/// A store that lives in memory, for tests. Syncing does nothing and every write is kept, so a
+/// tree reopened on the same store sees what a tree that crashed on it had written.
+#[derive(Default)]
+pub struct MemoryStore {
+    data: Mutex<Vec<u8>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A copy of everything written so far.
+    pub fn bytes(&self) -> Vec<u8> {
+        self.data.lock().clone()
+    }
+}
+
+impl PageStore for MemoryStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        let data = self.data.lock();
+        let start = offset as usize;
+        match data.get(start..start + buf.len()) {
+            Some(bytes) => {
+                buf.copy_from_slice(bytes);
+                Ok(())
+            }
+            None => Err(Error::new(ErrorKind::UnexpectedEof, "read past the end of the store")),
+        }
+    }
+
+    fn write_at(&self, offset: u64, bytes: &[u8]) -> std::io::Result<()> {
+        let mut data = self.data.lock();
+        let start = offset as usize;
+        if data.len() < start + bytes.len() {
+            data.resize(start + bytes.len(), 0);
+        }
+        data[start..start + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        Ok(self.data.lock().len() as u64)
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        self.data.lock().resize(len as usize, 0);
+        Ok(())
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// How a `FaultyStore` write goes wrong.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The write fails without writing anything.
+    Fail,
+    /// Only the first half of the write makes it before it fails.
+    Tear,
+}
+
+/// Decides when the `FaultyStore`s sharing it go wrong: the first `after` writes across all of
+/// them go through, the next one fails as given by `fault`, and from then on every write,
+/// resize and sync fails as if the process had died. Reads keep working.
+pub struct FaultTrigger {
+    fault: Fault,
+    // writes still allowed, None once the fault has happened
+    writes_left: Mutex<Option<u64>>,
+}
+
+impl FaultTrigger {
+    pub fn new(fault: Fault, after: u64) -> Arc<Self> {
+        Arc::new(FaultTrigger { fault, writes_left: Mutex::new(Some(after)) })
+    }
+
+    pub fn has_fired(&self) -> bool {
+        self.writes_left.lock().is_none()
+    }
+
+    // whether the next write may go through, firing the fault if it may not
+    fn allow_write(&self) -> bool {
+        let mut writes_left = self.writes_left.lock();
+        match *writes_left {
+            Some(0) | None => {
+                *writes_left = None;
+                false
+            }
+            Some(left) => {
+                *writes_left = Some(left - 1);
+                true
+            }
+        }
+    }
+}
+
+fn injected() -> Error {
+    Error::other("injected fault")
+}
+
+/// Wraps another store and fails or tears its writes when its `FaultTrigger` fires, to test
+/// how a `BTree` copes with a crash in the middle of an operation.
+pub struct FaultyStore {
+    inner: StoreRef,
+    trigger: Arc<FaultTrigger>,
+}
+
+impl FaultyStore {
+    pub fn new(inner: StoreRef, trigger: Arc<FaultTrigger>) -> Self {
+        FaultyStore { inner, trigger }
+    }
+}
+
+impl PageStore for FaultyStore {
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> std::io::Result<()> {
+        self.inner.read_at(offset, buf)
+    }
+
+    fn write_at(&self, offset: u64, data: &[u8]) -> std::io::Result<()> {
+        if self.trigger.has_fired() {
+            return Err(injected());
+        }
+        if !self.trigger.allow_write() {
+            if self.trigger.fault == Fault::Tear {
+                self.inner.write_at(offset, &data[..data.len() / 2])?;
+            }
+            return Err(injected());
+        }
+        self.inner.write_at(offset, data)
+    }
+
+    fn len(&self) -> std::io::Result<u64> {
+        self.inner.len()
+    }
+
+    fn set_len(&self, len: u64) -> std::io::Result<()> {
+        if self.trigger.has_fired() {
+            return Err(injected());
+        }
+        self.inner.set_len(len)
+    }
+
+    fn sync(&self) -> std::io::Result<()> {
+        if self.trigger.has_fired() {
+            return Err(injected());
+        }
+        self.inner.sync()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory_store() {
+        let store = MemoryStore::new();
+        assert!(store.is_empty().unwrap());
+        store.write_page(1, &[7; PAGE_SIZE]).unwrap();
+        assert_eq!(store.len().unwrap(), 2 * PAGE_SIZE as u64);
+        let mut buf = [0; PAGE_SIZE];
+        store.read_page(0, &mut buf).unwrap();
+        assert_eq!(buf, [0; PAGE_SIZE]);
+        store.read_page(1, &mut buf).unwrap();
+        assert_eq!(buf, [7; PAGE_SIZE]);
+        let err = store.read_page(2, &mut buf).unwrap_err();
+        assert!(matches!(err.downcast_ref::<PageError>(), Some(PageError::Truncated(2))));
+        store.set_len(10).unwrap();
+        assert_eq!(store.bytes(), vec![0; 10]);
+    }
+
+    #[test]
+    fn test_mmap_store() {
+        let path = std::env::temp_dir().join(format!("banksys-mmap-{}.btree", std::process::id()));
+        let open = || {
+            let file = std::fs::OpenOptions::new().create(true).truncate(false).read(true).write(true).open(&path);
+            MmapStore::new(file.unwrap(), true).unwrap()
+        };
+        let store = open();
+        assert!(store.is_empty().unwrap());
+        let mut buf = [0; PAGE_SIZE];
+        assert!(store.read_page(0, &mut buf).is_err());
+        store.write_page(0, &[1; PAGE_SIZE]).unwrap();
+        let lent = store.map_page(0).unwrap().unwrap();
+        // far enough to remap, the page lent from the old mapping stays readable
+        let far = (MAP_STEP / PAGE_SIZE as u64 + 10) as u32;
+        store.write_page(far, &[2; PAGE_SIZE]).unwrap();
+        store.sync().unwrap();
+        assert_eq!(*lent, [1; PAGE_SIZE]);
+        assert_eq!(*store.map_page(far).unwrap().unwrap(), [2; PAGE_SIZE]);
+        assert!(store.map_page(far + 1).is_err());
+        drop(lent);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), (far as u64 + 1) * PAGE_SIZE as u64);
+        store.read_page(0, &mut buf).unwrap();
+        assert_eq!(buf, [1; PAGE_SIZE]);
+        drop(store);
+
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data[..PAGE_SIZE], [1; PAGE_SIZE]);
+        assert_eq!(data[far as usize * PAGE_SIZE..], [2; PAGE_SIZE]);
+        let store = MmapStore::new(File::open(&path).unwrap(), false).unwrap();
+        store.read_page(far, &mut buf).unwrap();
+        assert_eq!(buf, [2; PAGE_SIZE]);
+        assert!(store.read_page(far + 1, &mut buf).is_err());
+        assert!(store.write_page(1, &buf).is_err());
+        drop(store);
+
+        let store = open();
+        store.set_len(PAGE_SIZE as u64).unwrap();
+        assert!(store.read_page(1, &mut buf).is_err());
+        drop(store);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_faulty_store() {
+        let inner = Arc::new(MemoryStore::new());
+        let trigger = FaultTrigger::new(Fault::Tear, 2);
+        let a = FaultyStore::new(inner.clone(), trigger.clone());
+        let b = FaultyStore::new(Arc::new(MemoryStore::new()), trigger.clone());
+        a.write_at(0, &[1; 4]).unwrap();
+        b.write_at(0, &[1; 4]).unwrap();
+        a.sync().unwrap();
+        assert!(!trigger.has_fired());
+        // the third write across both stores is torn, and everything after fails
+        assert!(a.write_at(4, &[2; 4]).is_err());
+        assert!(trigger.has_fired());
+        assert!(b.write_at(4, &[2; 4]).is_err());
+        assert!(a.sync().is_err());
+        assert!(a.set_len(0).is_err());
+        assert_eq!(inner.bytes(), vec![1, 1, 1, 1, 2, 2]);
+        let mut buf = [0; 6];
+        a.read_at(0, &mut buf).unwrap();
+        assert_eq!(buf, [1, 1, 1, 1, 2, 2]);
+    }
+}
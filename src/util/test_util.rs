@@ -0,0 +1,16 @@
+use crate::util::wal::Wal;
+
+// a path in the temp directory for a test tree, with any leftover tree and log from an earlier
+// run removed. The path is leaked so trees opened on it can be moved into threads
+pub(crate) fn temp_path(name: &str) -> &'static str {
+    let path = std::env::temp_dir().join(format!("banksys-{}-{}.btree", name, std::process::id()));
+    let path: &'static str = Box::leak(path.to_str().unwrap().to_owned().into_boxed_str());
+    let _ = std::fs::remove_file(path);
+    let _ = std::fs::remove_file(Wal::path_for(path));
+    path
+}
+
+pub(crate) fn remove_files(path: &str) {
+    std::fs::remove_file(path).unwrap();
+    std::fs::remove_file(Wal::path_for(path)).unwrap();
+}
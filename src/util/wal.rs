@@ -0,0 +1,377 @@
+use super::page::PAGE_SIZE;
+use super::store::StoreRef;
+use anyhow::Result;
+use parking_lot::Mutex;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+const RECORD_MAGIC: u32 = 0x5741_4c52;
+// magic, page count
+const HEADER_SIZE: usize = 8;
+// page index followed by the page image
+const ENTRY_SIZE: usize = 4 + PAGE_SIZE;
+
+/// When a commit is considered durable. Pages and the META page only reach the data file
+/// once the log holding their after-images is fsynced, and a checkpoint fsyncs the data file
+/// before it empties the log, so the mode covers every write of an operation.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SyncMode {
+    /// The log is fsynced before an operation returns.
+    #[default]
+    Full,
+    /// The log is fsynced once `ops` operations are waiting for it, and at the latest
+    /// `interval` after the first of them. An operating system crash or power loss can lose
+    /// the operations of the last `interval`, but never tears one.
+    Group { ops: usize, interval: Duration },
+    /// The log is only fsynced when a page has to be written back. The tree survives the
+    /// process crashing, but an operating system crash or power loss can lose the last
+    /// operations.
+    Off,
+}
+
+/// Redo log kept next to the `.btree` file.
+///
+/// Every operation appends one record holding the after-image of each page it changed, the
+/// META page included, and the record is fsynced before any of those pages may be written
+/// to the data file. After a crash the complete records are replayed in order, which brings
+/// the data file to the last committed operation; a torn record at the end of the log is an
+/// operation that never committed and is discarded. A checkpoint fsyncs the data file once
+/// every page has been written back and truncates the log.
+pub(crate) struct Wal {
+    log: StoreRef,
+    data: StoreRef,
+    len: u64,
+    sync: SyncMode,
+    log_sync: Arc<LogSync>,
+}
+
+impl Wal {
+    pub fn path_for(btree_path: impl AsRef<Path>) -> PathBuf {
+        let mut path = btree_path.as_ref().as_os_str().to_owned();
+        path.push(".wal");
+        path.into()
+    }
+
+    /// Opens the log kept in `log` and replays whatever it holds into `data`. Returns the log
+    /// and the number of operations replayed.
+    pub fn open(log: StoreRef, data: StoreRef, sync: SyncMode) -> Result<(Self, usize)> {
+        let log_sync = Arc::new(LogSync { log: log.clone(), unsynced: Mutex::new(Unsynced::default()) });
+        let mut wal = Wal { log, data, len: 0, sync, log_sync };
+        let replayed = wal.replay()?;
+        Ok((wal, replayed))
+    }
+
+    fn replay(&mut self) -> Result<usize> {
+        let mut log = vec![0; self.log.len()? as usize];
+        if log.is_empty() {
+            return Ok(0);
+        }
+        self.log.read_at(0, &mut log)?;
+
+        let mut replayed = 0;
+        let mut pos = 0;
+        while let Some((pages, size)) = parse_record(&log[pos..]) {
+            for (index, image) in pages {
+                self.data.write_at(index as u64 * PAGE_SIZE as u64, image)?;
+            }
+            pos += size;
+            replayed += 1;
+        }
+        self.data.sync()?;
+        // a torn record at the end goes away with the rest
+        self.truncate()?;
+        Ok(replayed)
+    }
+
+    /// Appends one operation's page images and fsyncs the log as the `SyncMode` asks.
+    pub fn append(&mut self, pages: &[(u32, &[u8; PAGE_SIZE])]) -> Result<()> {
+        let mut record = Vec::with_capacity(HEADER_SIZE + pages.len() * ENTRY_SIZE + 4);
+        record.extend_from_slice(&RECORD_MAGIC.to_be_bytes());
+        record.extend_from_slice(&(pages.len() as u32).to_be_bytes());
+        for (index, image) in pages {
+            record.extend_from_slice(&index.to_be_bytes());
+            record.extend_from_slice(&image[..]);
+        }
+        let crc = crc32(&record);
+        record.extend_from_slice(&crc.to_be_bytes());
+
+        self.log.write_at(self.len, &record)?;
+        self.len += record.len() as u64;
+        let unsynced = self.log_sync.appended();
+        match self.sync {
+            SyncMode::Full => self.flush(),
+            SyncMode::Group { ops, .. } if unsynced >= ops => self.flush(),
+            SyncMode::Group { interval, .. } => self.log_sync.flush_older_than(interval),
+            SyncMode::Off => Ok(()),
+        }
+    }
+
+    /// Fsyncs the log if an operation appended to it is not durable yet.
+    pub fn flush(&mut self) -> Result<()> {
+        self.log_sync.flush()
+    }
+
+    /// Operations appended since the log was last fsynced.
+    pub fn unsynced(&self) -> usize {
+        self.log_sync.unsynced.lock().ops
+    }
+
+    /// Fsyncs the log for whoever writes pages to the data file, see `LogSync`.
+    pub fn log_sync(&self) -> Arc<LogSync> {
+        self.log_sync.clone()
+    }
+
+    /// Leaves the log as it is for the next open to replay, for a tree whose pages could not
+    /// all be written back.
+    pub fn abandon(&mut self) {
+        self.len = 0;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Must only be called once every page in the log has been written to the data file.
+    pub fn checkpoint(&mut self) -> Result<()> {
+        self.data.sync()?;
+        self.truncate()
+    }
+
+    fn truncate(&mut self) -> Result<()> {
+        self.log.set_len(0)?;
+        self.log.sync()?;
+        self.len = 0;
+        *self.log_sync.unsynced.lock() = Unsynced::default();
+        Ok(())
+    }
+}
+
+/// Fsyncs the log without taking the `Wal`'s lock. A page must not be written to the data
+/// file before the record that changed it is durable, and the buffer pool evicts pages while
+/// a commit may be holding the `Wal`, so every write-back goes through `flush` first.
+pub(crate) struct LogSync {
+    log: StoreRef,
+    unsynced: Mutex<Unsynced>,
+}
+
+// operations appended since the log was last fsynced, and when the first of them was
+#[derive(Default)]
+struct Unsynced {
+    ops: usize,
+    since: Option<Instant>,
+}
+
+impl LogSync {
+    // counts an operation just written to the log, returns how many are waiting
+    fn appended(&self) -> usize {
+        let mut unsynced = self.unsynced.lock();
+        unsynced.ops += 1;
+        unsynced.since.get_or_insert_with(Instant::now);
+        unsynced.ops
+    }
+
+    /// Fsyncs the log if an operation appended to it is not durable yet.
+    pub fn flush(&self) -> Result<()> {
+        let mut unsynced = self.unsynced.lock();
+        if unsynced.ops > 0 {
+            self.log.sync()?;
+            *unsynced = Unsynced::default();
+        }
+        Ok(())
+    }
+
+    fn flush_older_than(&self, age: Duration) -> Result<()> {
+        let waited = self.unsynced.lock().since.is_some_and(|since| since.elapsed() >= age);
+        if waited {
+            self.flush()
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Fsyncs a log opened with `SyncMode::Group` from a background thread, so that operations
+/// are durable `interval` after they commit even if no other operation follows them. The
+/// thread stops when the flusher is dropped.
+pub(crate) struct GroupFlusher {
+    stop: Option<Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl GroupFlusher {
+    pub fn spawn(log_sync: Arc<LogSync>, interval: Duration) -> Self {
+        let log_sync: Weak<LogSync> = Arc::downgrade(&log_sync);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::spawn(move || {
+            while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval / 2) {
+                let Some(log_sync) = log_sync.upgrade() else { break };
+                // the next commit retries and returns the error
+                if let Err(err) = log_sync.flush_older_than(interval) {
+                    eprintln!("could not sync the write-ahead log: {:#}", err);
+                }
+            }
+        });
+        GroupFlusher { stop: Some(stop), thread: Some(thread) }
+    }
+}
+
+impl Drop for GroupFlusher {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            thread.join().unwrap();
+        }
+    }
+}
+
+// the BTree drops its pages, writing them back, before the log. A checkpoint that fails leaves
+// the log in place to be replayed by the next open
+impl Drop for Wal {
+    fn drop(&mut self) {
+        if self.len > 0 {
+            if let Err(err) = self.checkpoint() {
+                eprintln!("could not checkpoint the write-ahead log: {:#}", err);
+            }
+        }
+    }
+}
+
+// page index and image
+type Entry<'a> = (u32, &'a [u8]);
+
+// returns the page images of the record at the start of buf and its size, or None if there is
+// no complete record there
+fn parse_record(buf: &[u8]) -> Option<(Vec<Entry<'_>>, usize)> {
+    if buf.len() < HEADER_SIZE || u32::from_be_bytes(buf[0..4].try_into().unwrap()) != RECORD_MAGIC {
+        return None;
+    }
+    let count = u32::from_be_bytes(buf[4..8].try_into().unwrap()) as usize;
+    let size = HEADER_SIZE + count.checked_mul(ENTRY_SIZE)? + 4;
+    if buf.len() < size {
+        return None;
+    }
+    let crc = u32::from_be_bytes(buf[size - 4..size].try_into().unwrap());
+    if crc32(&buf[..size - 4]) != crc {
+        return None;
+    }
+    let pages = buf[HEADER_SIZE..size - 4]
+        .chunks(ENTRY_SIZE)
+        .map(|entry| (u32::from_be_bytes(entry[0..4].try_into().unwrap()), &entry[4..]))
+        .collect();
+    Some((pages, size))
+}
+
+// CRC32_TABLES[0] is the usual byte-at-a-time table, table k advances a byte k more bytes so
+// eight bytes can be folded in at once
+const CRC32_TABLES: [[u32; 256]; 8] = crc32_tables();
+
+const fn crc32_tables() -> [[u32; 256]; 8] {
+    let mut tables = [[0; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xff) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
+/// CRC-32 (IEEE), the checksum used by zip and png.
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let t = &CRC32_TABLES;
+    let mut crc = 0xffff_ffff_u32;
+    let mut chunks = data.chunks_exact(8);
+    for chunk in &mut chunks {
+        let lo = crc ^ u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+        crc = t[7][(lo & 0xff) as usize]
+            ^ t[6][((lo >> 8) & 0xff) as usize]
+            ^ t[5][((lo >> 16) & 0xff) as usize]
+            ^ t[4][(lo >> 24) as usize]
+            ^ t[3][chunk[4] as usize]
+            ^ t[2][chunk[5] as usize]
+            ^ t[1][chunk[6] as usize]
+            ^ t[0][chunk[7] as usize];
+    }
+    for byte in chunks.remainder() {
+        crc = t[0][((crc ^ *byte as u32) & 0xff) as usize] ^ (crc >> 8);
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store::{Fault, FaultTrigger, FaultyStore, MemoryStore};
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+        assert_eq!(crc32(b"The quick brown fox jumps over the lazy dog"), 0x414f_a339);
+    }
+
+    #[test]
+    fn test_parse_record() {
+        let log = Arc::new(MemoryStore::new());
+        let data = Arc::new(MemoryStore::new());
+        let (mut wal, replayed) = Wal::open(log.clone(), data.clone(), SyncMode::Full).unwrap();
+        assert_eq!(replayed, 0);
+        let (a, b) = ([1; PAGE_SIZE], [2; PAGE_SIZE]);
+        wal.append(&[(3, &a), (0, &b)]).unwrap();
+        wal.append(&[(4, &b)]).unwrap();
+
+        let mut bytes = log.bytes();
+        let (pages, size) = parse_record(&bytes).unwrap();
+        assert_eq!(pages, vec![(3, &a[..]), (0, &b[..])]);
+        assert_eq!(parse_record(&bytes[size..]).unwrap().0, vec![(4, &b[..])]);
+        // torn or damaged records are not records
+        assert!(parse_record(&bytes[size..bytes.len() - 1]).is_none());
+        bytes[size + HEADER_SIZE + 10] ^= 1;
+        assert!(parse_record(&bytes[size..]).is_none());
+
+        // reopening replays both records into the data and empties the log
+        std::mem::forget(wal);
+        let (wal, replayed) = Wal::open(log.clone(), data.clone(), SyncMode::Full).unwrap();
+        assert_eq!(replayed, 2);
+        assert!(log.bytes().is_empty());
+        assert_eq!(data.bytes().len(), 5 * PAGE_SIZE);
+        assert_eq!(data.bytes()[..PAGE_SIZE], b[..]);
+        assert_eq!(data.bytes()[3 * PAGE_SIZE..4 * PAGE_SIZE], a[..]);
+        drop(wal);
+    }
+
+    #[test]
+    fn test_drop_keeps_log_on_error() {
+        let log = Arc::new(MemoryStore::new());
+        let data = Arc::new(MemoryStore::new());
+        let trigger = FaultTrigger::new(Fault::Fail, 1);
+        let faulty = Arc::new(FaultyStore::new(log.clone(), trigger.clone()));
+        let (mut wal, _) = Wal::open(faulty, data.clone(), SyncMode::Full).unwrap();
+        wal.append(&[(1, &[1; PAGE_SIZE])]).unwrap();
+        assert!(wal.append(&[(2, &[2; PAGE_SIZE])]).is_err());
+        // the checkpoint fails without a panic and the logged record survives for the next open
+        drop(wal);
+        let (_wal, replayed) = Wal::open(log.clone(), data.clone(), SyncMode::Full).unwrap();
+        assert_eq!(replayed, 1);
+        assert_eq!(data.bytes()[PAGE_SIZE..], [1; PAGE_SIZE]);
+    }
+}